@@ -1,6 +1,6 @@
 local M = {}
 
-M.VERSION_STR = "0.7.0"
+M.VERSION_STR = "0.8.0"
 
 M.downloads = {}
 M.downloading = false
@@ -29,6 +29,7 @@ M.connection = {
 
 local FILE_TRANSFER_CHUNK_SIZE = 16384;
 local CHUNK_SIZE = 65000  -- Safe size under 65536 limit
+local next_chunked_message_id = 0
 
 local message_handlers = {}
 
@@ -158,6 +159,12 @@ local function handle_chat(data)
   kissui.chat.add_message(data[1], nil, data[2])
 end
 
+-- Sent again when the server config is reloaded
+local function handle_server_info(server_info)
+  M.connection.server_info = server_info
+  M.connection.tickrate = server_info.tickrate
+end
+
 local function onExtensionLoaded()
   message_handlers.VehicleUpdate = vehiclemanager.update_vehicle
   message_handlers.VehicleSpawn = vehiclemanager.spawn_vehicle
@@ -173,6 +180,7 @@ local function onExtensionLoaded()
   message_handlers.CouplerAttached = vehiclemanager.attach_coupler
   message_handlers.CouplerDetached = vehiclemanager.detach_coupler
   message_handlers.ElectricsUndefinedUpdate = vehiclemanager.electrics_diff_update
+  message_handlers.ServerInfo = handle_server_info
 end
 
 local function send_data(raw_data, reliable)
@@ -192,6 +200,9 @@ local function send_data(raw_data, reliable)
   if data_size > CHUNK_SIZE then
     print("Large data detected: " .. data_size .. " bytes, sending in chunks")
     local num_chunks = math.ceil(data_size / CHUNK_SIZE)
+    -- The server keeps chunks of different messages apart by this ID
+    local message_id = next_chunked_message_id
+    next_chunked_message_id = (next_chunked_message_id + 1) % 4294967296
     
     for i = 0, num_chunks - 1 do
       local start_pos = i * CHUNK_SIZE + 1
@@ -200,6 +211,7 @@ local function send_data(raw_data, reliable)
       
       local chunk_data = jsonEncode({
         DataChunk = {
+          message_id = message_id,
           chunk_index = i,
           total_chunks = num_chunks,
           data = chunk
@@ -316,7 +328,7 @@ local function connect(addr, player_name)
       name = player_name,
       secret = generate_secret(server_info.server_identifier),
       steamid64 = steamid64,
-      client_version = {0, 7}
+      client_version = {0, 8}
     }
   }
   send_data(client_info, true)
@@ -255,6 +255,24 @@ local function spawn_vehicle(data)
   spawned:queueLuaCommand("extensions.hook('kissUpdateOwnership', false)")
 end
 
+-- Spawns a vehicle as our own, it gets sent to the server like any other local vehicle
+local function spawn_owned_vehicle(data)
+  local options = {
+    pos = vec3(data.position),
+    rot = quat(data.rotation),
+    config = serialize(jsonDecode(data.parts_config)),
+    paint  = table_to_paint(data.color),
+    paint2 = table_to_paint(data.palete_0),
+    paint3 = table_to_paint(data.palete_1),
+    autoEnterVehicle = false
+  }
+  options = sanitizeVehicleSpawnOptions(data.name, options)
+  local spawned = spawn.spawnVehicle(data.name, options.config, options.pos, options.rot, options)
+  if spawned and data.plate ~= nil then
+    extensions.core_vehicles.setPlateText(data.plate, spawned:getID())
+  end
+end
+
 local function onUpdate(dt)
   if not network.connection.connected then return end
   if (getMissionFilename():lower() ~= network.connection.server_info.map:lower()) and (getMissionPath():lower() ~= network.connection.server_info.map:lower()) and not M.loading_map then
@@ -377,9 +395,7 @@ local function reset_vehicle(data)
   end
 end
 
-local function update_vehicle_meta(data)
-  local id = M.id_map[data.vehicle_id or -1] or -1
-  if M.ownership[id] then return end
+local function apply_vehicle_meta(id, data)
   local vehicle = be:getObjectByID(id)
   if not vehicle then return end
   local plate = data.plate
@@ -409,6 +425,21 @@ local function update_vehicle_meta(data)
   vehicle:setField('partConfig', '', serialize(vd.config))
 end
 
+local function update_vehicle_meta(data)
+  local id = M.id_map[data.vehicle_id or -1] or -1
+  if M.ownership[id] then return end
+  apply_vehicle_meta(id, data)
+end
+
+-- Called by the server when it denied a paint or plate change of our own vehicle.
+-- vehicle_id is the in game ID here
+local function reset_own_vehicle_meta(data)
+  local id = data.vehicle_id
+  plates_buffer[id] = data.plate
+  colors_buffer[id] = data.colors_table
+  apply_vehicle_meta(id, data)
+end
+
 local function electrics_diff_update(data)
   local id = M.id_map[data[1] or -1]
   if id and not M.ownership[id] then
@@ -566,12 +597,14 @@ M.update_vehicle = update_vehicle
 M.send_vehicle_config = send_vehicle_config
 M.send_vehicle_config_inner = send_vehicle_config_inner
 M.spawn_vehicle = spawn_vehicle
+M.spawn_owned_vehicle = spawn_owned_vehicle
 M.update_vehicle_input = update_vehicle_input
 M.update_vehicle_gearbox = update_vehicle_gearbox
 M.rotate_nodes = rotate_nodes
 M.remove_vehicle = remove_vehicle
 M.reset_vehicle = reset_vehicle
 M.update_vehicle_meta = update_vehicle_meta
+M.reset_own_vehicle_meta = reset_own_vehicle_meta
 M.onVehicleDestroyed = onVehicleDestroyed
 M.onVehicleResetted = onVehicleResetted
 M.onVehicleSpawned = onVehicleSpawned
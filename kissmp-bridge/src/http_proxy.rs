@@ -70,14 +70,19 @@ pub async fn spawn_http_proxy(discord_tx: std::sync::mpsc::Sender<crate::Discord
                     };
                     let rt = tokio::runtime::Runtime::new().unwrap();
                     rt.block_on(async move {
-                        let server = kissmp_server::Server::from_config(config);
-                        server.run(false, destroyer_rx, Some(setup_result_tx)).await;
+                        match kissmp_server::Server::from_config(config) {
+                            Ok(server) => {
+                                server.run(false, destroyer_rx, Some(setup_result_tx)).await
+                            }
+                            // Dropping the setup result sender lets the loop below stop waiting
+                            Err(e) => error!("Failed to start the server: {:#}", e),
+                        }
                     });
                 });
                 // FIXME: Utilize setup response at some point. Like display dialog message on client with copy button instead of chat message
                 loop {
                     let result = setup_result_rx.try_recv();
-                    if result.is_ok() {
+                    if !matches!(result, Err(tokio::sync::oneshot::error::TryRecvError::Empty)) {
                         break;
                     }
                 }
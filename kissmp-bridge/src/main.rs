@@ -6,6 +6,7 @@ use futures::stream::FuturesUnordered;
 use futures::StreamExt;
 use quinn::IdleTimeout;
 use rustls::{Certificate, ServerName};
+use std::collections::HashMap;
 use std::convert::TryFrom;
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs};
 use std::sync::Arc;
@@ -17,6 +18,7 @@ extern crate log;
 
 const SERVER_IDLE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(120);
 const CONNECTED_BYTE: &[u8] = &[1];
+const VEHICLE_UPDATE_ACK_INTERVAL: std::time::Duration = std::time::Duration::from_millis(100);
 
 #[derive(Debug, Clone)]
 pub struct DiscordState {
@@ -338,6 +340,13 @@ async fn server_incoming(
     vc_playback_sender: std::sync::mpsc::Sender<voice_chat::VoiceChatPlaybackEvent>,
     server_connection: quinn::NewConnection,
 ) -> AHResult {
+    let connection = server_connection.connection.clone();
+    let mut received_states = shared::vehicle::StateHistory::default();
+    let mut pending_acks = HashMap::new();
+    let mut ack_interval = tokio::time::interval(VEHICLE_UPDATE_ACK_INTERVAL);
+    // An empty ack lets the server know that we can decode compact vehicle updates
+    send_vehicle_update_ack(&connection, vec![])?;
+
     let mut reliable_commands = server_connection.uni_streams
         .map(|stream| async { 
             let mut stream = stream?;
@@ -380,6 +389,17 @@ async fn server_incoming(
                                     client, pos, data,
                                 ));
                             }
+                            shared::ServerCommand::VehicleUpdateCompact(data) => {
+                                if let Some(update) = decode_vehicle_update(
+                                    &data,
+                                    &mut received_states,
+                                    &mut pending_acks,
+                                ) {
+                                    server_commands_sender
+                                        .send(shared::ServerCommand::VehicleUpdate(update))
+                                        .await?;
+                                }
+                            }
                             _ => server_commands_sender.send(command).await?,
                         }
                     }
@@ -390,6 +410,11 @@ async fn server_incoming(
                 }
                 None => break,
             },
+            _ = ack_interval.tick() => {
+                if !pending_acks.is_empty() {
+                    send_vehicle_update_ack(&connection, pending_acks.drain().collect())?;
+                }
+            },
             else => break,
         }
     }
@@ -397,6 +422,31 @@ async fn server_incoming(
     Ok(())
 }
 
+fn decode_vehicle_update(
+    data: &[u8],
+    received_states: &mut shared::vehicle::StateHistory,
+    pending_acks: &mut HashMap<u32, u64>,
+) -> Option<shared::vehicle::VehicleUpdate> {
+    let update = shared::vehicle::CompactVehicleUpdate::from_bytes(data).ok()?;
+    let baseline = update
+        .baseline
+        .and_then(|generation| received_states.get(update.vehicle_id, generation));
+    let state = update.decode(baseline)?;
+    let result = state.to_update(update.vehicle_id, update.generation);
+    received_states.push(update.vehicle_id, update.generation, state);
+    let ack = pending_acks
+        .entry(update.vehicle_id)
+        .or_insert(update.generation);
+    *ack = update.generation.max(*ack);
+    Some(result)
+}
+
+fn send_vehicle_update_ack(connection: &quinn::Connection, acks: Vec<(u32, u64)>) -> AHResult {
+    let data = bincode::serialize(&shared::ClientCommand::VehicleUpdateAck(acks))?;
+    connection.send_datagram(data.into())?;
+    Ok(())
+}
+
 async fn client_incoming(
     server_stream: quinn::Connection,
     vc_playback_sender: std::sync::mpsc::Sender<voice_chat::VoiceChatPlaybackEvent>,
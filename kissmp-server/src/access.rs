@@ -0,0 +1,439 @@
+use crate::world_state::hash_secret;
+use ipnetwork::IpNetwork;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use shared::ClientInfoPrivate;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+
+pub const ACCESS_LIST_FILE: &str = "access.json";
+
+/// A set of identifiers a player can be matched by.
+/// Every field is optional, an entry matches if any of the present `secret_hash`, `steamid64` or `ip` fields match.
+/// Anyone can pick any name, so `name` is only kept as a note for whoever reads the file
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct Identity {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// See `world_state::hash_secret`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub secret_hash: Option<String>,
+    /// A plain secret written into the file by hand. It's replaced by `secret_hash` when loading
+    #[serde(skip_serializing)]
+    secret: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub steamid64: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ip: Option<IpNetwork>,
+}
+
+impl Identity {
+    /// Leaves out the IP, so players sharing the address can still join.
+    /// It can be banned on its own with `parse`
+    pub fn from_client(client_info: &ClientInfoPrivate) -> Self {
+        Self {
+            name: Some(client_info.name.clone()),
+            secret_hash: Some(hash_secret(&client_info.secret)),
+            steamid64: client_info.steamid64.clone(),
+            ..Default::default()
+        }
+    }
+
+    /// Builds an identity from a single string identifier.
+    /// IP addresses and CIDR ranges are recognized first, then 17 digit SteamID64s.
+    /// Anything else is treated as a client secret.
+    pub fn parse(identifier: &str) -> Self {
+        if let Ok(ip) = identifier.parse::<IpNetwork>() {
+            return Self {
+                ip: Some(ip),
+                ..Default::default()
+            };
+        }
+        if identifier.len() == 17 && identifier.chars().all(|c| c.is_ascii_digit()) {
+            return Self {
+                steamid64: Some(identifier.to_string()),
+                ..Default::default()
+            };
+        }
+        Self {
+            secret_hash: Some(hash_secret(identifier)),
+            ..Default::default()
+        }
+    }
+
+    /// Returns true if a plain secret was replaced by its hash
+    fn hash_plain_secret(&mut self) -> bool {
+        match self.secret.take() {
+            Some(secret) => {
+                self.secret_hash = Some(hash_secret(&secret));
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn matches(&self, client_info: &ClientInfoPrivate, ip: IpAddr) -> bool {
+        if let Some(secret_hash) = &self.secret_hash {
+            if *secret_hash == hash_secret(&client_info.secret) {
+                return true;
+            }
+        }
+        if let (Some(steamid64), Some(client_steamid64)) = (&self.steamid64, &client_info.steamid64)
+        {
+            if steamid64 == client_steamid64 {
+                return true;
+            }
+        }
+        if let Some(network) = &self.ip {
+            if network.contains(ip) {
+                return true;
+            }
+        }
+        false
+    }
+
+    pub fn matches_identifier(&self, identifier: &str) -> bool {
+        self.secret_hash.as_deref() == Some(hash_secret(identifier).as_str())
+            || self.steamid64.as_deref() == Some(identifier)
+            || self.ip.map(|ip| ip.to_string()).as_deref() == Some(identifier)
+            || self
+                .ip
+                .zip(identifier.parse::<IpAddr>().ok())
+                .map(|(network, ip)| network.contains(ip))
+                .unwrap_or(false)
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct Ban {
+    #[serde(flatten)]
+    pub identity: Identity,
+    pub reason: String,
+    /// Unix timestamp in seconds. Permanent if not set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<u64>,
+}
+
+impl Ban {
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.map(|t| t <= now).unwrap_or(false)
+    }
+}
+
+#[derive(Deserialize, Serialize, Default, Debug)]
+#[serde(default)]
+pub struct AccessList {
+    #[serde(skip)]
+    path: PathBuf,
+    pub bans: Vec<Ban>,
+    /// Only players matching an entry are allowed to join, if set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub whitelist: Option<Vec<Identity>>,
+    pub admins: Vec<Identity>,
+}
+
+impl AccessList {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let mut list = if path.exists() {
+            let file = std::fs::File::open(path)?;
+            serde_json::from_reader::<_, AccessList>(std::io::BufReader::new(file))?
+        } else {
+            AccessList::default()
+        };
+        // The file is only created once there is something to save
+        list.path = path.to_path_buf();
+        let mut hashed = false;
+        for identity in list.identities_mut() {
+            hashed |= identity.hash_plain_secret();
+        }
+        if hashed {
+            info!(
+                "Replacing plain secrets in {} with their hashes",
+                path.display()
+            );
+            list.save()?;
+        }
+        Ok(list)
+    }
+
+    fn identities_mut(&mut self) -> impl Iterator<Item = &mut Identity> {
+        self.bans
+            .iter_mut()
+            .map(|ban| &mut ban.identity)
+            .chain(self.whitelist.iter_mut().flatten())
+            .chain(self.admins.iter_mut())
+    }
+
+    /// Writes to a temporary file first, so a crash while saving doesn't lose the previous list
+    pub fn save(&self) -> anyhow::Result<()> {
+        let data = serde_json::to_vec_pretty(self)?;
+        let temp_path = self.path.with_extension("json.tmp");
+        std::fs::write(&temp_path, data)?;
+        std::fs::rename(temp_path, &self.path)?;
+        Ok(())
+    }
+
+    /// Returns a reason if the client is not allowed to join the server
+    pub fn check(&mut self, client_info: &ClientInfoPrivate, ip: IpAddr) -> Option<String> {
+        self.remove_expired();
+        if let Some(ban) = self
+            .bans
+            .iter()
+            .find(|ban| ban.identity.matches(client_info, ip))
+        {
+            let mut reason = format!("You are banned from this server.\nReason: {}", ban.reason);
+            if let Some(expires_at) = ban.expires_at {
+                let minutes_left = expires_at.saturating_sub(unix_now()).div_ceil(60);
+                reason.push_str(&format!("\nExpires in {} minute(s)", minutes_left));
+            }
+            return Some(reason);
+        }
+        if let Some(whitelist) = &self.whitelist {
+            if !whitelist.iter().any(|x| x.matches(client_info, ip)) {
+                return Some(String::from("You are not whitelisted on this server."));
+            }
+        }
+        None
+    }
+
+    pub fn ban(&mut self, identity: Identity, reason: String, duration: Option<u64>) {
+        self.bans.push(Ban {
+            identity,
+            reason,
+            expires_at: duration.map(|d| unix_now() + d),
+        });
+        if let Err(e) = self.save() {
+            error!("Failed to save access list: {}", e);
+        }
+    }
+
+    /// Removes every ban that matches the identifier. Returns the amount of removed bans
+    pub fn unban(&mut self, identifier: &str) -> usize {
+        let len = self.bans.len();
+        self.bans
+            .retain(|ban| !ban.identity.matches_identifier(identifier));
+        let removed = len - self.bans.len();
+        if removed > 0 {
+            if let Err(e) = self.save() {
+                error!("Failed to save access list: {}", e);
+            }
+        }
+        removed
+    }
+
+    pub fn is_admin(&self, client_info: &ClientInfoPrivate, ip: IpAddr) -> bool {
+        self.admins.iter().any(|x| x.matches(client_info, ip))
+    }
+
+    fn remove_expired(&mut self) {
+        let now = unix_now();
+        let len = self.bans.len();
+        self.bans.retain(|ban| !ban.is_expired(now));
+        if len != self.bans.len() {
+            if let Err(e) = self.save() {
+                error!("Failed to save access list: {}", e);
+            }
+        }
+    }
+}
+
+fn unix_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+impl crate::Server {
+    /// Bans the identity and kicks every connected client it matches
+    pub fn ban(&mut self, identity: Identity, reason: String, duration: Option<u64>) {
+        info!("Banning {:?}. Reason: {}", identity, reason);
+        for connection in self.connections.values() {
+            let ip = connection.conn.remote_address().ip();
+            if identity.matches(&connection.client_info_private, ip) {
+                connection.conn.close(
+                    1u32.into(),
+                    format!("You have been banned.\nReason: {}", reason).as_bytes(),
+                );
+            }
+        }
+        self.access_list
+            .lock()
+            .unwrap()
+            .ban(identity, reason, duration);
+    }
+
+    pub fn ban_client(&mut self, client_id: u32, reason: String, duration: Option<u64>) {
+        if let Some(connection) = self.connections.get(&client_id) {
+            let identity = Identity::from_client(&connection.client_info_private);
+            self.ban(identity, reason, duration);
+        }
+    }
+
+    pub fn unban(&mut self, identifier: &str) {
+        let removed = self.access_list.lock().unwrap().unban(identifier);
+        info!("Removed {} ban(s) matching {}", removed, identifier);
+    }
+
+    pub fn is_admin(&self, client_id: u32) -> bool {
+        if let Some(connection) = self.connections.get(&client_id) {
+            self.access_list.lock().unwrap().is_admin(
+                &connection.client_info_private,
+                connection.conn.remote_address().ip(),
+            )
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STEAMID: &str = "76561198000000000";
+
+    fn client(secret: &str, steamid64: Option<&str>) -> ClientInfoPrivate {
+        ClientInfoPrivate {
+            name: String::from("Player"),
+            secret: secret.to_string(),
+            steamid64: steamid64.map(String::from),
+            client_version: shared::VERSION,
+        }
+    }
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("kissmp-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir.join(ACCESS_LIST_FILE)
+    }
+
+    #[test]
+    fn identifiers_are_parsed_by_kind() {
+        let identity = Identity::parse("10.0.0.1");
+        assert_eq!(identity.ip, Some("10.0.0.1/32".parse().unwrap()));
+        assert!(identity.secret_hash.is_none() && identity.steamid64.is_none());
+
+        let identity = Identity::parse("10.0.0.0/8");
+        assert_eq!(identity.ip, Some("10.0.0.0/8".parse().unwrap()));
+
+        let identity = Identity::parse("::1");
+        assert_eq!(identity.ip, Some("::1/128".parse().unwrap()));
+
+        let identity = Identity::parse(STEAMID);
+        assert_eq!(identity.steamid64.as_deref(), Some(STEAMID));
+        assert!(identity.secret_hash.is_none() && identity.ip.is_none());
+
+        // Anything else is a secret, including numbers that aren't 17 digits long
+        for secret in &[
+            "7656119800000000",
+            "765611980000000000",
+            "abc",
+            "10.0.0.1/99",
+        ] {
+            let identity = Identity::parse(secret);
+            assert_eq!(identity.secret_hash, Some(hash_secret(secret)));
+            assert!(identity.steamid64.is_none() && identity.ip.is_none());
+        }
+    }
+
+    #[test]
+    fn identities_match_any_of_their_fields() {
+        let player = client("secret", Some(STEAMID));
+        let address = ip("192.168.1.20");
+        assert!(Identity::parse("secret").matches(&player, address));
+        assert!(Identity::parse(STEAMID).matches(&player, address));
+        assert!(Identity::parse("192.168.1.20").matches(&player, address));
+        assert!(Identity::parse("192.168.1.0/24").matches(&player, address));
+        assert!(!Identity::parse("192.168.2.0/24").matches(&player, address));
+        assert!(!Identity::parse("other").matches(&player, address));
+        assert!(!Identity::parse(STEAMID).matches(&client("secret", None), address));
+        assert!(!Identity::default().matches(&player, address));
+    }
+
+    #[test]
+    fn client_identities_leave_out_the_ip() {
+        let player = client("secret", Some(STEAMID));
+        let identity = Identity::from_client(&player);
+        assert!(identity.ip.is_none());
+        assert!(identity.matches(&player, ip("10.0.0.1")));
+        assert!(!identity.matches(&client("other", None), ip("10.0.0.1")));
+        let saved = serde_json::to_string(&identity).unwrap();
+        assert!(!saved.contains("\"secret\""));
+        assert!(saved.contains(&hash_secret("secret")));
+    }
+
+    #[test]
+    fn identifiers_match_the_ban_they_were_parsed_from() {
+        for identifier in &["secret", STEAMID, "10.0.0.1", "10.0.0.0/8"] {
+            assert!(Identity::parse(identifier).matches_identifier(identifier));
+        }
+        let range = Identity::parse("10.0.0.0/8");
+        assert!(range.matches_identifier("10.1.2.3"));
+        assert!(!range.matches_identifier("11.0.0.1"));
+        assert!(!Identity::parse("secret").matches_identifier("other"));
+    }
+
+    #[test]
+    fn unbanning_an_ip_removes_ranges_containing_it() {
+        let mut list = AccessList::load(&temp_path("unban")).unwrap();
+        list.ban(Identity::parse("10.0.0.0/8"), String::new(), None);
+        list.ban(Identity::parse("192.168.0.0/16"), String::new(), None);
+        list.ban(Identity::parse("secret"), String::new(), None);
+        assert_eq!(list.unban("10.20.30.40"), 1);
+        assert_eq!(list.unban("10.20.30.40"), 0);
+        assert_eq!(list.unban("secret"), 1);
+        assert_eq!(list.bans.len(), 1);
+        let player = client("secret", None);
+        assert!(list.check(&player, ip("10.20.30.40")).is_none());
+        assert!(list.check(&player, ip("192.168.5.5")).is_some());
+    }
+
+    #[test]
+    fn bans_expire() {
+        let now = unix_now();
+        let ban = |expires_at| Ban {
+            identity: Identity::parse("secret"),
+            reason: String::new(),
+            expires_at,
+        };
+        assert!(!ban(None).is_expired(now));
+        assert!(!ban(Some(now + 1)).is_expired(now));
+        assert!(ban(Some(now)).is_expired(now));
+
+        let mut list = AccessList::load(&temp_path("expiry")).unwrap();
+        list.bans.push(ban(Some(now - 1)));
+        list.bans.push(ban(Some(now + 3600)));
+        let reason = list.check(&client("secret", None), ip("10.0.0.1")).unwrap();
+        assert!(reason.contains("Expires in 60 minute(s)"));
+        assert_eq!(list.bans.len(), 1);
+    }
+
+    #[test]
+    fn plain_secrets_are_replaced_by_hashes() {
+        let path = temp_path("plain-secrets");
+        std::fs::write(
+            &path,
+            r#"{"bans": [{"secret": "banned", "reason": "x"}], "admins": [{"name": "Me", "secret": "admin"}]}"#,
+        )
+        .unwrap();
+        let list = AccessList::load(&path).unwrap();
+        assert!(list.is_admin(&client("admin", None), ip("10.0.0.1")));
+        assert!(!list.is_admin(&client("banned", None), ip("10.0.0.1")));
+        let saved = std::fs::read_to_string(&path).unwrap();
+        assert!(!saved.contains("\"admin\"") && !saved.contains("\"banned\""));
+        assert!(saved.contains(&hash_secret("admin")));
+        assert!(!path.with_extension("json.tmp").exists());
+
+        let mut list = AccessList::load(&path).unwrap();
+        assert!(list
+            .check(&client("banned", None), ip("10.0.0.1"))
+            .is_some());
+    }
+}
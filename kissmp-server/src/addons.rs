@@ -0,0 +1,410 @@
+use crate::*;
+
+// Lua registry table with the environment of every loaded addon
+const ADDONS_REGISTRY_KEY: &str = "kissmp_addons";
+
+fn addons_table(lua_ctx: rlua::Context) -> rlua::Result<rlua::Table> {
+    if let Ok(table) = lua_ctx.named_registry_value::<_, rlua::Table>(ADDONS_REGISTRY_KEY) {
+        return Ok(table);
+    }
+    let table = lua_ctx.create_table()?;
+    lua_ctx.set_named_registry_value(ADDONS_REGISTRY_KEY, table.clone())?;
+    Ok(table)
+}
+
+// Returns a table that reads through to `source` without letting addons change it.
+// Nested tables are wrapped as well. Keys in `own` take priority over the ones in `source`
+const READ_ONLY_VIEW: &str = r#"
+local function view(source, own)
+    return setmetatable({}, {
+        __index = function(_, key)
+            local value = own[key]
+            if value == nil then
+                value = source[key]
+            end
+            if type(value) == "table" then
+                return view(value, {})
+            end
+            return value
+        end,
+        __newindex = function()
+            error("This table is read only", 2)
+        end,
+        __pairs = function()
+            return function(_, key)
+                local next_key, value = next(source, key)
+                if type(value) == "table" then
+                    value = view(value, {})
+                end
+                return next_key, value
+            end, nil, nil
+        end,
+        __len = function()
+            return #source
+        end,
+        __metatable = false,
+    })
+end
+return view
+"#;
+
+fn read_only_view<'lua>(
+    lua_ctx: rlua::Context<'lua>,
+    source: rlua::Table<'lua>,
+    own: rlua::Table<'lua>,
+) -> rlua::Result<rlua::Table<'lua>> {
+    let view: rlua::Function = lua_ctx.load(READ_ONLY_VIEW).eval()?;
+    view.call((source, own))
+}
+
+/// Module names are resolved relative to the addon folder, like `require("lib.utils")` -> `lib/utils.lua`
+fn module_path(addon_path: &std::path::Path, module: &str) -> Option<std::path::PathBuf> {
+    let valid = !module.is_empty()
+        && module
+            .split('.')
+            .all(|x| !x.is_empty() && x.chars().all(|c| c.is_alphanumeric() || c == '_' || c == '-'));
+    if !valid {
+        return None;
+    }
+    Some(addon_path.join(format!("{}.lua", module.replace('.', "/"))))
+}
+
+/// Every addon gets its own global table. Reads fall back to the shared globals,
+/// so the server API is available, but globals defined by the addon stay private.
+fn create_addon_env<'lua>(
+    lua_ctx: rlua::Context<'lua>,
+    name: &str,
+    addon_path: &std::path::Path,
+    storage: &crate::storage::Storage,
+) -> rlua::Result<rlua::Table<'lua>> {
+    let globals = lua_ctx.globals();
+    let env = lua_ctx.create_table()?;
+    let meta = lua_ctx.create_table()?;
+    meta.set("__index", globals.clone())?;
+    env.set_metatable(Some(meta));
+    env.set("_G", env.clone())?;
+    env.set("ADDON_NAME", name)?;
+
+    // hooks.register(hook, function) uses the addon name as the subname.
+    // With an explicit subname it's prefixed with the addon name, so addons can't replace each other's hooks.
+    // Everything else is read only, the handlers of other addons can't be changed through it either
+    let hooks = lua_ctx.create_table()?;
+    let addon = name.to_string();
+    hooks.set(
+        "register",
+        lua_ctx.create_function(
+            move |lua_ctx, (hook, subname, function): (String, rlua::Value, Option<rlua::Function>)| {
+                match (subname, function) {
+                    (rlua::Value::Function(function), None) => {
+                        lua::register_hook(lua_ctx, hook, addon.clone(), function)
+                    }
+                    (rlua::Value::String(subname), Some(function)) => lua::register_hook(
+                        lua_ctx,
+                        hook,
+                        format!("{}/{}", addon, subname.to_str()?),
+                        function,
+                    ),
+                    _ => Err(rlua::Error::RuntimeError(String::from(
+                        "Usage: hooks.register(hook, [subname], function)",
+                    ))),
+                }
+            },
+        )?,
+    )?;
+    env.set(
+        "hooks",
+        read_only_view(lua_ctx, globals.get::<_, rlua::Table>("hooks")?, hooks)?,
+    )?;
+
+    let commands = lua_ctx.create_table()?;
+    let addon = name.to_string();
+    commands.set(
+        "register",
+        lua_ctx.create_function(
+            move |lua_ctx, (name, help, callback): (String, String, rlua::Function)| {
+                lua::register_command(lua_ctx, name, help, callback, Some(addon.clone()))
+            },
+        )?,
+    )?;
+    env.set(
+        "commands",
+        read_only_view(lua_ctx, globals.get::<_, rlua::Table>("commands")?, commands)?,
+    )?;
+
+    crate::timers::add_timer_functions(lua_ctx, env.clone(), Some(name.to_string()))?;
+    crate::http::add_http_table(lua_ctx, env.clone(), Some(name.to_string()))?;
+    env.set(
+        "storage",
+        crate::storage::create_storage_table(lua_ctx, storage, name)?,
+    )?;
+
+    let package = lua_ctx.create_table()?;
+    package.set("loaded", lua_ctx.create_table()?)?;
+    env.set("package", package)?;
+
+    // Parts configs (.pc files) are JSON, they can be passed to build_vehicle as they are
+    let config_root = addon_path.to_path_buf();
+    env.set(
+        "read_vehicle_config",
+        lua_ctx.create_function(move |_, path: String| {
+            let relative = std::path::Path::new(&path);
+            if !relative
+                .components()
+                .all(|x| matches!(x, std::path::Component::Normal(_)))
+            {
+                return Err(rlua::Error::RuntimeError(format!(
+                    "{} has to be a path inside the addon folder",
+                    path
+                )));
+            }
+            let config = std::fs::read_to_string(config_root.join(relative)).map_err(|e| {
+                rlua::Error::RuntimeError(format!("Can't read {}: {}", path, e))
+            })?;
+            if let Err(e) = serde_json::from_str::<serde_json::Value>(&config) {
+                return Err(rlua::Error::RuntimeError(format!(
+                    "{} isn't a valid parts config: {}",
+                    path, e
+                )));
+            }
+            Ok(config)
+        })?,
+    )?;
+
+    // Only files inside the addon folder can be required
+    let addon = name.to_string();
+    let addon_path = addon_path.to_path_buf();
+    env.set(
+        "require",
+        lua_ctx.create_function(move |lua_ctx, module: String| {
+            let env: rlua::Table = addons_table(lua_ctx)?.get(addon.as_str())?;
+            let loaded: rlua::Table = env.get::<_, rlua::Table>("package")?.get("loaded")?;
+            let value: rlua::Value = loaded.get(module.as_str())?;
+            if !matches!(value, rlua::Value::Nil) {
+                return Ok(value);
+            }
+            let path = module_path(&addon_path, &module).ok_or_else(|| {
+                rlua::Error::RuntimeError(format!("Invalid module name: {}", module))
+            })?;
+            let source = std::fs::read_to_string(&path).map_err(|e| {
+                rlua::Error::RuntimeError(format!("Can't load module {}: {}", module, e))
+            })?;
+            let value: rlua::Value = lua_ctx
+                .load(&source)
+                .set_name(&format!("{}/{}", addon, module))?
+                .set_environment(env)?
+                .into_function()?
+                .call(module.clone())?;
+            let value = match value {
+                rlua::Value::Nil => rlua::Value::Boolean(true),
+                value => value,
+            };
+            loaded.set(module, value.clone())?;
+            Ok(value)
+        })?,
+    )?;
+    Ok(env)
+}
+
+impl Server {
+    /// Folders in the addons directory that have a main.lua
+    pub fn addon_names(&self) -> Vec<String> {
+        let mut names = vec![];
+        if let Ok(entries) = std::fs::read_dir(&self.paths.addons) {
+            for entry in entries {
+                let path = match entry {
+                    Ok(entry) => entry.path(),
+                    Err(e) => {
+                        warn!("Failed to read an entry of the addons folder: {}", e);
+                        continue;
+                    }
+                };
+                if !path.join("main.lua").is_file() {
+                    continue;
+                }
+                if let Some(name) = path.file_name().and_then(|x| x.to_str()) {
+                    names.push(name.to_string());
+                }
+            }
+        }
+        names.sort();
+        names
+    }
+
+    pub fn is_addon_loaded(&self, name: &str) -> bool {
+        self.lua.context(|lua_ctx| {
+            addons_table(lua_ctx)
+                .and_then(|addons| addons.contains_key(name))
+                .unwrap_or(false)
+        })
+    }
+
+    /// Name of the addon a file belongs to. The file doesn't have to exist anymore
+    pub fn addon_for_path(&self, path: &std::path::Path) -> Option<String> {
+        let addons_path = std::fs::canonicalize(&self.paths.addons).ok()?;
+        let path = match std::fs::canonicalize(path) {
+            Ok(path) => path,
+            Err(_) => std::fs::canonicalize(path.parent()?)
+                .ok()?
+                .join(path.file_name()?),
+        };
+        let name = path.strip_prefix(addons_path).ok()?.components().next()?;
+        Some(name.as_os_str().to_str()?.to_string())
+    }
+
+    pub fn load_lua_addons(&mut self) {
+        let path = self.paths.addons.clone();
+        if !path.exists() {
+            std::fs::create_dir_all(&path).unwrap();
+        }
+        for name in self.addon_names() {
+            if self.disabled_addons.contains(&name) {
+                info!("Addon {} is disabled", name);
+                continue;
+            }
+            self.load_addon(&name);
+        }
+    }
+
+    /// Runs the addon's main.lua in a new environment and calls its `OnLoad` hooks.
+    /// An already loaded addon is unloaded first
+    pub fn load_addon(&mut self, name: &str) -> bool {
+        use notify::Watcher;
+        let addon_path = self.paths.addons.join(name);
+        let main_path = addon_path.join("main.lua");
+        // The whole folder is watched, so changes to required files reload the addon too.
+        // It stays watched if loading fails, so fixing the error is picked up as well
+        let watch_path = std::fs::canonicalize(&addon_path).unwrap_or(addon_path.clone());
+        if let Err(e) = self
+            .lua_watcher
+            .watch(&watch_path, notify::RecursiveMode::Recursive)
+        {
+            warn!("Failed to watch {}: {}", addon_path.display(), e);
+        }
+        let source = match std::fs::read_to_string(&main_path) {
+            Ok(source) => source,
+            Err(e) => {
+                error!("Failed to read {}: {}", main_path.display(), e);
+                return false;
+            }
+        };
+        self.unload_addon(name);
+        self.hook_profiler.reset_instructions();
+        let result = self.lua.context(|lua_ctx| -> rlua::Result<()> {
+            let env = create_addon_env(lua_ctx, name, &addon_path, &self.storage)?;
+            addons_table(lua_ctx)?.set(name, env.clone())?;
+            lua_ctx
+                .load(&source)
+                .set_name(&format!("{}/main.lua", name))?
+                .set_environment(env)?
+                .exec()
+        });
+        if let Err(e) = result {
+            error!("Failed to load addon {}: {:?}", name, e);
+            self.unload_addon(name);
+            return false;
+        }
+        self.update_command_names();
+        self.run_addon_hook(name, "OnLoad");
+        true
+    }
+
+    /// Calls the handlers of a hook that were registered by one addon
+    fn run_addon_hook(&self, addon: &str, hook: &str) {
+        self.hook_profiler.reset_instructions();
+        self.lua.context(|lua_ctx| {
+            let handlers: rlua::Table = match lua_ctx
+                .globals()
+                .get::<_, rlua::Table>("hooks")
+                .and_then(|hooks| hooks.get(hook))
+            {
+                Ok(handlers) => handlers,
+                Err(_) => return,
+            };
+            let prefix = format!("{}/", addon);
+            for pair in handlers.pairs::<String, rlua::Function>() {
+                let (handler, function) = match pair {
+                    Ok(pair) => pair,
+                    Err(_) => continue,
+                };
+                if handler != addon && !handler.starts_with(&prefix) {
+                    continue;
+                }
+                if let Err(e) = function.call::<_, ()>(()) {
+                    error!("{}", e);
+                }
+            }
+        });
+    }
+
+    /// Calls the addon's `OnUnload` hooks and removes its hooks, commands and environment.
+    /// Returns false if it wasn't loaded
+    pub fn unload_addon(&mut self, name: &str) -> bool {
+        if !self.is_addon_loaded(name) {
+            return false;
+        }
+        self.run_addon_hook(name, "OnUnload");
+        self.cancel_addon_timers(name);
+        self.cancel_addon_requests(name);
+        let result = self.lua.context(|lua_ctx| -> rlua::Result<bool> {
+            let addons = addons_table(lua_ctx)?;
+            addons.set(name, rlua::Value::Nil)?;
+
+            let prefix = format!("{}/", name);
+            let hooks: rlua::Table = lua_ctx.globals().get("hooks")?;
+            for pair in hooks.pairs::<rlua::Value, rlua::Value>() {
+                let handlers = match pair? {
+                    (_, rlua::Value::Table(handlers)) => handlers,
+                    _ => continue,
+                };
+                let mut owned = vec![];
+                for pair in handlers.clone().pairs::<String, rlua::Value>() {
+                    let (handler, _) = pair?;
+                    if handler == name || handler.starts_with(&prefix) {
+                        owned.push(handler);
+                    }
+                }
+                for handler in owned {
+                    handlers.set(handler, rlua::Value::Nil)?;
+                }
+            }
+
+            let list: rlua::Table = lua_ctx
+                .globals()
+                .get::<_, rlua::Table>("commands")?
+                .get("list")?;
+            let mut owned = vec![];
+            for pair in list.clone().pairs::<String, rlua::Table>() {
+                let (command, entry) = pair?;
+                if entry.get::<_, Option<String>>("addon")?.as_deref() == Some(name) {
+                    owned.push(command);
+                }
+            }
+            for command in owned {
+                list.set(command, rlua::Value::Nil)?;
+            }
+            Ok(true)
+        });
+        self.update_command_names();
+        match result {
+            Ok(unloaded) => unloaded,
+            Err(e) => {
+                error!("Failed to unload addon {}: {}", name, e);
+                false
+            }
+        }
+    }
+
+    pub fn enable_addon(&mut self, name: &str) -> bool {
+        self.disabled_addons.remove(name);
+        self.load_addon(name)
+    }
+
+    pub fn disable_addon(&mut self, name: &str) {
+        use notify::Watcher;
+        self.disabled_addons.insert(name.to_string());
+        self.unload_addon(name);
+        let addon_path = self.paths.addons.join(name);
+        let watch_path = std::fs::canonicalize(&addon_path).unwrap_or(addon_path);
+        let _ = self.lua_watcher.unwatch(watch_path);
+    }
+}
@@ -0,0 +1,289 @@
+use crate::*;
+use serde::Deserialize;
+use serde_json::json;
+use std::io::Read;
+
+// Request bodies are small JSON objects, anything bigger is rejected
+const MAX_BODY_SIZE: u64 = 64 * 1024;
+
+#[derive(Debug, Clone)]
+pub struct AdminApiSettings {
+    pub port: u16,
+    pub token: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct KickRequest {
+    pub client_id: u32,
+    #[serde(default)]
+    pub reason: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct BanRequest {
+    pub client_id: Option<u32>,
+    /// Secret, SteamID64 or IP address. Used if `client_id` isn't set
+    pub identifier: Option<String>,
+    #[serde(default)]
+    pub reason: String,
+    /// In seconds. Permanent if not set
+    pub duration: Option<u64>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct UnbanRequest {
+    pub identifier: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ChatRequest {
+    pub message: String,
+    /// Sent to everyone if not set
+    pub client_id: Option<u32>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct RemoveVehicleRequest {
+    pub vehicle_id: u32,
+}
+
+#[derive(Debug)]
+pub enum AdminRequest {
+    Connections,
+    Vehicles,
+    Metrics,
+    Kick(KickRequest),
+    Ban(BanRequest),
+    Unban(UnbanRequest),
+    Chat(ChatRequest),
+    RemoveVehicle(RemoveVehicleRequest),
+    ReloadAddons,
+}
+
+#[derive(Debug)]
+pub struct AdminError {
+    pub status: u16,
+    pub message: String,
+}
+
+impl AdminError {
+    pub fn new(status: u16, message: &str) -> Self {
+        Self {
+            status,
+            message: message.to_string(),
+        }
+    }
+}
+
+pub type AdminResponse = Result<serde_json::Value, AdminError>;
+pub type AdminMessage = (AdminRequest, std::sync::mpsc::Sender<AdminResponse>);
+
+fn parse_body<T: serde::de::DeserializeOwned>(body: &str) -> Result<T, AdminError> {
+    serde_json::from_str(body).map_err(|e| AdminError::new(400, &e.to_string()))
+}
+
+fn parse_request(
+    method: &tiny_http::Method,
+    path: &str,
+    body: &str,
+) -> Result<AdminRequest, AdminError> {
+    use tiny_http::Method::*;
+    let path = path.split('?').next().unwrap_or("");
+    match (method, path) {
+        (Get, "/connections") => Ok(AdminRequest::Connections),
+        (Get, "/vehicles") => Ok(AdminRequest::Vehicles),
+        (Get, "/metrics") => Ok(AdminRequest::Metrics),
+        (Post, "/kick") => Ok(AdminRequest::Kick(parse_body(body)?)),
+        (Post, "/ban") => Ok(AdminRequest::Ban(parse_body(body)?)),
+        (Post, "/unban") => Ok(AdminRequest::Unban(parse_body(body)?)),
+        (Post, "/chat") => Ok(AdminRequest::Chat(parse_body(body)?)),
+        (Post, "/remove_vehicle") => Ok(AdminRequest::RemoveVehicle(parse_body(body)?)),
+        (Post, "/reload_addons") => Ok(AdminRequest::ReloadAddons),
+        _ => Err(AdminError::new(404, "Unknown endpoint")),
+    }
+}
+
+fn is_authorized(request: &tiny_http::Request, token: &str) -> bool {
+    let expected = format!("Bearer {}", token);
+    request
+        .headers()
+        .iter()
+        .find(|header| header.field.equiv("Authorization"))
+        // Constant time, so the token can't be guessed from how long the comparison takes
+        .map(|header| {
+            ring::constant_time::verify_slices_are_equal(
+                header.value.as_str().as_bytes(),
+                expected.as_bytes(),
+            )
+            .is_ok()
+        })
+        .unwrap_or(false)
+}
+
+fn handle_request(
+    mut request: tiny_http::Request,
+    token: &str,
+    requests: &mpsc::Sender<AdminMessage>,
+) {
+    let response = (|| {
+        if !is_authorized(&request, token) {
+            return Err(AdminError::new(401, "Invalid token"));
+        }
+        let mut body = String::new();
+        request
+            .as_reader()
+            .take(MAX_BODY_SIZE)
+            .read_to_string(&mut body)
+            .map_err(|_| AdminError::new(400, "Failed to read request body"))?;
+        let admin_request = parse_request(request.method(), request.url(), &body)?;
+        // The request is handled by the server loop, this thread only waits for the result
+        let (response_tx, response_rx) = std::sync::mpsc::channel();
+        requests
+            .blocking_send((admin_request, response_tx))
+            .map_err(|_| AdminError::new(503, "Server is shutting down"))?;
+        response_rx
+            .recv()
+            .map_err(|_| AdminError::new(503, "Server is shutting down"))?
+    })();
+    let (status, body) = match response {
+        Ok(value) => (200, value),
+        Err(e) => (e.status, json!({ "error": e.message })),
+    };
+    let content_type =
+        tiny_http::Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..]).unwrap();
+    let response = tiny_http::Response::from_string(body.to_string())
+        .with_status_code(status)
+        .with_header(content_type);
+    let _ = request.respond(response);
+}
+
+/// Starts the HTTP server on a separate thread. It only listens on localhost
+pub fn start(
+    settings: AdminApiSettings,
+    requests: mpsc::Sender<AdminMessage>,
+) -> anyhow::Result<()> {
+    let server = tiny_http::Server::http(("127.0.0.1", settings.port))
+        .map_err(|e| anyhow::Error::msg(e.to_string()))?;
+    std::thread::spawn(move || {
+        for request in server.incoming_requests() {
+            handle_request(request, &settings.token, &requests);
+        }
+    });
+    Ok(())
+}
+
+impl Server {
+    pub async fn on_admin_request(&mut self, request: AdminRequest) -> AdminResponse {
+        use AdminRequest::*;
+        match request {
+            Connections => {
+                let connections: Vec<serde_json::Value> = self
+                    .connections
+                    .iter()
+                    .map(|(id, connection)| {
+                        json!({
+                            "id": id,
+                            "name": connection.client_info_public.name,
+                            "ping": connection.client_info_public.ping,
+                            "current_vehicle": connection.client_info_public.current_vehicle,
+                            "ip": connection.conn.remote_address().ip().to_string(),
+                            "admin": self.is_admin(*id),
+                        })
+                    })
+                    .collect();
+                Ok(json!(connections))
+            }
+            Vehicles => {
+                let vehicles: Vec<serde_json::Value> = self
+                    .vehicles
+                    .iter()
+                    .map(|(id, vehicle)| {
+                        json!({
+                            "id": id,
+                            "name": vehicle.data.name,
+                            "owner": vehicle.data.owner,
+                            "position": vehicle.transform.as_ref().map(|t| t.position),
+                        })
+                    })
+                    .collect();
+                Ok(json!(vehicles))
+            }
+            Metrics => Ok(json!({
+                "uptime": self.start_time.elapsed().as_secs(),
+                "tick": self.tick,
+                "tickrate": self.tickrate,
+                "players": self.connections.len(),
+                "max_players": self.max_players,
+                "vehicles": self.vehicles.len(),
+            })),
+            Kick(request) => {
+                let connection = self
+                    .connections
+                    .get(&request.client_id)
+                    .ok_or_else(|| AdminError::new(404, "Unknown client"))?;
+                connection
+                    .conn
+                    .close(1u32.into(), &request.reason.into_bytes());
+                Ok(json!({}))
+            }
+            Ban(request) => {
+                match (request.client_id, request.identifier) {
+                    (Some(client_id), _) => {
+                        if !self.connections.contains_key(&client_id) {
+                            return Err(AdminError::new(404, "Unknown client"));
+                        }
+                        self.ban_client(client_id, request.reason, request.duration);
+                    }
+                    (None, Some(identifier)) => self.ban(
+                        access::Identity::parse(&identifier),
+                        request.reason,
+                        request.duration,
+                    ),
+                    (None, None) => {
+                        return Err(AdminError::new(
+                            400,
+                            "Either client_id or identifier is required",
+                        ))
+                    }
+                }
+                let _ = self.update_lua_connections();
+                Ok(json!({}))
+            }
+            Unban(request) => {
+                self.unban(&request.identifier);
+                Ok(json!({}))
+            }
+            Chat(request) => {
+                match request.client_id {
+                    Some(client_id) => {
+                        let connection = self
+                            .connections
+                            .get_mut(&client_id)
+                            .ok_or_else(|| AdminError::new(404, "Unknown client"))?;
+                        connection.send_chat_message(request.message).await;
+                    }
+                    None => {
+                        for client in self.connections.values_mut() {
+                            client.send_chat_message(request.message.clone()).await;
+                        }
+                        self.record_replay(ServerCommand::Chat(request.message, None));
+                    }
+                }
+                Ok(json!({}))
+            }
+            RemoveVehicle(request) => {
+                if !self.vehicles.contains_key(&request.vehicle_id) {
+                    return Err(AdminError::new(404, "Unknown vehicle"));
+                }
+                self.remove_vehicle(request.vehicle_id, None).await;
+                Ok(json!({}))
+            }
+            ReloadAddons => {
+                info!("Reloading addons");
+                self.load_lua_addons();
+                let _ = self.update_lua_connections();
+                Ok(json!({}))
+            }
+        }
+    }
+}
@@ -0,0 +1,107 @@
+use futures::{StreamExt, TryStreamExt};
+use kissmp_server::replay::ReplayReader;
+use kissmp_server::*;
+use log::{error, info, warn};
+use shared::ServerCommand;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+#[tokio::main]
+async fn main() {
+    shared::init_logging();
+
+    let mut args = std::env::args().skip(1);
+    let path = match args.next() {
+        Some(path) => std::path::PathBuf::from(path),
+        None => {
+            error!("Usage: kissmp-replay <replay file> [port]");
+            std::process::exit(1);
+        }
+    };
+    let port = args.next().and_then(|x| x.parse().ok()).unwrap_or(3698);
+    // Fail early if the file is broken
+    if let Err(e) = ReplayReader::open(&path) {
+        error!("Failed to open replay {}: {}", path.display(), e);
+        std::process::exit(1);
+    }
+
+    let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), port);
+    let (_endpoint, mut incoming) = quinn::Endpoint::server(server_config(), addr).unwrap();
+    info!("Serving replay {} on {}", path.display(), addr);
+    while let Some(connecting) = incoming.next().await {
+        let path = path.clone();
+        tokio::spawn(async move {
+            match connecting.await {
+                Ok(new_connection) => {
+                    let connection = new_connection.connection.clone();
+                    if let Err(e) = serve_replay(new_connection, &path).await {
+                        warn!("Replay connection closed: {}", e);
+                    }
+                    connection.close(0u32.into(), b"Replay has ended");
+                }
+                Err(e) => warn!("Failed to accept incoming connection: {}", e),
+            }
+        });
+    }
+}
+
+async fn serve_replay(
+    mut new_connection: quinn::NewConnection,
+    path: &std::path::Path,
+) -> anyhow::Result<()> {
+    let connection = new_connection.connection.clone();
+    let mut stream = new_connection
+        .uni_streams
+        .try_next()
+        .await?
+        .ok_or(anyhow::Error::msg("No client info stream received"))?;
+    let mut buf = [0; 4];
+    stream.read_exact(&mut buf).await?;
+    let mut buf = vec![0; u32::from_le_bytes(buf).min(16384) as usize];
+    stream.read_exact(&mut buf).await?;
+    if let shared::ClientCommand::ClientInfo(info) = bincode::deserialize(&buf)? {
+        info!("{} is watching the replay", info.name);
+    }
+    // Whatever the client sends is of no use to a replay
+    tokio::spawn(async move {
+        let mut streams = new_connection.uni_streams;
+        while let Some(Ok(_)) = streams.next().await {}
+    });
+
+    let mut reader = ReplayReader::open(path)?;
+    let header = reader.header.clone();
+    let server_info = ServerCommand::ServerInfo(shared::ServerInfo {
+        name: format!("{} (replay)", header.server_name),
+        player_count: 0,
+        // Never given to a player, so it won't collide with one from the replay
+        client_id: ids::REPLAY_CLIENT_ID,
+        map: header.map.clone(),
+        tickrate: header.tickrate,
+        max_vehicles_per_client: 0,
+        // Mods used by the recording have to be installed on the client already
+        mods: vec![],
+        server_identifier: String::from("replay"),
+    });
+    send(
+        &mut connection.open_uni().await?,
+        &Server::handle_outgoing_data(server_info),
+    )
+    .await?;
+
+    let tick_duration = std::time::Duration::from_secs(1) / header.tickrate.max(1) as u32;
+    let start = tokio::time::Instant::now();
+    let mut first_tick = None;
+    while let Some(frame) = reader.next_frame()? {
+        let first_tick = *first_tick.get_or_insert(frame.tick);
+        let offset = tick_duration * (frame.tick - first_tick) as u32;
+        tokio::time::sleep_until(start + offset).await;
+        let unreliable = matches!(frame.command, ServerCommand::VehicleUpdate(_));
+        let data = Server::handle_outgoing_data(frame.command);
+        if unreliable {
+            let _ = connection.send_datagram(data.into());
+        } else {
+            send(&mut connection.open_uni().await?, &data).await?;
+        }
+    }
+    info!("Replay has ended");
+    Ok(())
+}
@@ -0,0 +1,304 @@
+use crate::*;
+use std::time::Instant;
+
+// Chunked messages larger than this are rejected before anything is allocated for them
+pub const MAX_CHUNKS_PER_MESSAGE: u32 = 256;
+// Chunked messages a client can be sending at the same time
+pub const MAX_PENDING_CHUNKED_MESSAGES: usize = 4;
+// Incomplete messages are dropped when none of their chunks arrived for this long
+pub const CHUNKED_MESSAGE_TIMEOUT: Duration = Duration::from_secs(30);
+
+struct PendingMessage {
+    chunks: Vec<Option<String>>,
+    received: u32,
+    last_chunk: Instant,
+}
+
+/// Reassembles the chunked messages of a single client
+#[derive(Default)]
+pub struct ChunkBuffer {
+    // Message ID -> chunks received so far
+    messages: HashMap<u32, PendingMessage>,
+    // Bytes buffered over all messages
+    size: usize,
+}
+
+impl ChunkBuffer {
+    /// Adds a chunk and returns the whole message once all of its chunks arrived.
+    /// Errors are the reason to kick the client, the buffer is left unchanged by them
+    pub fn add(
+        &mut self,
+        message_id: u32,
+        chunk_index: u32,
+        total_chunks: u32,
+        data: String,
+        max_size: usize,
+        now: Instant,
+    ) -> Result<Option<String>, String> {
+        if total_chunks == 0 || total_chunks > MAX_CHUNKS_PER_MESSAGE || chunk_index >= total_chunks {
+            return Err(format!(
+                "Invalid data chunk {} of {}",
+                chunk_index, total_chunks
+            ));
+        }
+        match self.messages.get(&message_id) {
+            Some(message) => {
+                if message.chunks.len() != total_chunks as usize {
+                    return Err(format!(
+                        "Chunked message {} changed its size from {} to {} chunks",
+                        message_id,
+                        message.chunks.len(),
+                        total_chunks
+                    ));
+                }
+                if message.chunks[chunk_index as usize].is_some() {
+                    return Err(format!(
+                        "Data chunk {} of message {} was sent twice",
+                        chunk_index, message_id
+                    ));
+                }
+            }
+            None => {
+                if self.messages.len() >= MAX_PENDING_CHUNKED_MESSAGES {
+                    return Err(String::from("Too many chunked messages at once"));
+                }
+            }
+        }
+        if self.size + data.len() > max_size {
+            return Err(format!(
+                "Chunked messages are larger than the limit of {} bytes",
+                max_size
+            ));
+        }
+
+        self.size += data.len();
+        let message = self
+            .messages
+            .entry(message_id)
+            .or_insert_with(|| PendingMessage {
+                chunks: vec![None; total_chunks as usize],
+                received: 0,
+                last_chunk: now,
+            });
+        message.chunks[chunk_index as usize] = Some(data);
+        message.received += 1;
+        message.last_chunk = now;
+        if message.received < total_chunks {
+            return Ok(None);
+        }
+
+        let message = self.messages.remove(&message_id).unwrap();
+        let data: String = message.chunks.into_iter().map(|x| x.unwrap()).collect();
+        self.size -= data.len();
+        Ok(Some(data))
+    }
+
+    /// Drops messages that haven't received a chunk for `timeout`. Returns how many were dropped
+    pub fn evict_stale(&mut self, now: Instant, timeout: Duration) -> usize {
+        let stale: Vec<u32> = self
+            .messages
+            .iter()
+            .filter(|(_, message)| now.duration_since(message.last_chunk) > timeout)
+            .map(|(id, _)| *id)
+            .collect();
+        for id in &stale {
+            let message = self.messages.remove(id).unwrap();
+            self.size -= message
+                .chunks
+                .iter()
+                .flatten()
+                .map(|chunk| chunk.len())
+                .sum::<usize>();
+        }
+        stale.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+}
+
+impl Server {
+    pub async fn on_data_chunk(
+        &mut self,
+        client_id: u32,
+        message_id: u32,
+        chunk_index: u32,
+        total_chunks: u32,
+        data: String,
+    ) {
+        let max_size = self.max_chunked_message_size;
+        let result = self
+            .chunk_buffers
+            .entry(client_id)
+            .or_default()
+            .add(
+                message_id,
+                chunk_index,
+                total_chunks,
+                data,
+                max_size,
+                Instant::now(),
+            );
+        let full_json = match result {
+            Ok(Some(full_json)) => full_json,
+            Ok(None) => return,
+            Err(reason) => {
+                self.kick_client(client_id, reason);
+                return;
+            }
+        };
+        match serde_json::from_str::<shared::ClientCommand>(&full_json) {
+            Ok(shared::ClientCommand::DataChunk { .. }) => {
+                error!("Client {} sent a chunked message inside another one", client_id);
+            }
+            Ok(original_command) => {
+                // Box to avoid infinite type size
+                Box::pin(self.on_client_event(
+                    client_id,
+                    IncomingEvent::ClientCommand(original_command),
+                ))
+                .await;
+            }
+            Err(e) => {
+                error!("Failed to parse reassembled JSON: {}", e);
+            }
+        }
+    }
+
+    pub fn evict_stale_chunks(&mut self) {
+        let now = Instant::now();
+        for (client_id, buffer) in &mut self.chunk_buffers {
+            let evicted = buffer.evict_stale(now, CHUNKED_MESSAGE_TIMEOUT);
+            if evicted > 0 {
+                warn!(
+                    "Dropped {} incomplete chunked messages of client {}",
+                    evicted, client_id
+                );
+            }
+        }
+        self.chunk_buffers.retain(|_, buffer| !buffer.is_empty());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAX_SIZE: usize = 1024;
+
+    fn add(
+        buffer: &mut ChunkBuffer,
+        message_id: u32,
+        chunk_index: u32,
+        total_chunks: u32,
+        data: &str,
+    ) -> Result<Option<String>, String> {
+        buffer.add(
+            message_id,
+            chunk_index,
+            total_chunks,
+            String::from(data),
+            MAX_SIZE,
+            Instant::now(),
+        )
+    }
+
+    #[test]
+    fn reassembles_chunks_in_any_order() {
+        let mut buffer = ChunkBuffer::default();
+        assert_eq!(add(&mut buffer, 1, 2, 3, "ghi"), Ok(None));
+        assert_eq!(add(&mut buffer, 1, 0, 3, "abc"), Ok(None));
+        assert_eq!(add(&mut buffer, 1, 1, 3, "def"), Ok(Some(String::from("abcdefghi"))));
+        assert!(buffer.is_empty());
+        assert_eq!(buffer.size(), 0);
+    }
+
+    #[test]
+    fn single_chunk_and_empty_chunks() {
+        let mut buffer = ChunkBuffer::default();
+        assert_eq!(add(&mut buffer, 1, 0, 1, "abc"), Ok(Some(String::from("abc"))));
+        assert_eq!(add(&mut buffer, 2, 0, 2, ""), Ok(None));
+        assert_eq!(add(&mut buffer, 2, 1, 2, ""), Ok(Some(String::new())));
+    }
+
+    #[test]
+    fn messages_of_the_same_size_are_kept_apart() {
+        let mut buffer = ChunkBuffer::default();
+        assert_eq!(add(&mut buffer, 1, 0, 2, "a1"), Ok(None));
+        assert_eq!(add(&mut buffer, 2, 0, 2, "b1"), Ok(None));
+        assert_eq!(add(&mut buffer, 2, 1, 2, "b2"), Ok(Some(String::from("b1b2"))));
+        assert_eq!(add(&mut buffer, 1, 1, 2, "a2"), Ok(Some(String::from("a1a2"))));
+    }
+
+    #[test]
+    fn invalid_indices_are_rejected() {
+        let mut buffer = ChunkBuffer::default();
+        assert!(add(&mut buffer, 1, 0, 0, "a").is_err());
+        assert!(add(&mut buffer, 1, 2, 2, "a").is_err());
+        assert!(add(&mut buffer, 1, u32::MAX, 2, "a").is_err());
+        assert!(add(&mut buffer, 1, 0, MAX_CHUNKS_PER_MESSAGE + 1, "a").is_err());
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn inconsistent_chunks_are_rejected() {
+        let mut buffer = ChunkBuffer::default();
+        assert_eq!(add(&mut buffer, 1, 0, 3, "abc"), Ok(None));
+        // Same message ID with a different number of chunks
+        assert!(add(&mut buffer, 1, 1, 2, "def").is_err());
+        // Same chunk twice
+        assert!(add(&mut buffer, 1, 0, 3, "abc").is_err());
+        assert_eq!(buffer.size(), 3);
+        assert_eq!(add(&mut buffer, 1, 1, 3, "def"), Ok(None));
+        assert_eq!(add(&mut buffer, 1, 2, 3, "ghi"), Ok(Some(String::from("abcdefghi"))));
+    }
+
+    #[test]
+    fn total_size_is_limited() {
+        let mut buffer = ChunkBuffer::default();
+        let half = "x".repeat(MAX_SIZE / 2);
+        assert_eq!(add(&mut buffer, 1, 0, 3, &half), Ok(None));
+        assert_eq!(add(&mut buffer, 2, 0, 3, &half), Ok(None));
+        assert!(add(&mut buffer, 1, 1, 3, "x").is_err());
+        assert_eq!(buffer.size(), MAX_SIZE);
+    }
+
+    #[test]
+    fn pending_messages_are_limited() {
+        let mut buffer = ChunkBuffer::default();
+        for id in 0..MAX_PENDING_CHUNKED_MESSAGES as u32 {
+            assert_eq!(add(&mut buffer, id, 0, 2, "a"), Ok(None));
+        }
+        assert!(add(&mut buffer, 100, 0, 2, "a").is_err());
+        // Chunks of messages that were already started are still accepted
+        assert_eq!(add(&mut buffer, 0, 1, 2, "b"), Ok(Some(String::from("ab"))));
+        assert_eq!(add(&mut buffer, 100, 0, 2, "a"), Ok(None));
+    }
+
+    #[test]
+    fn stale_messages_are_evicted() {
+        let mut buffer = ChunkBuffer::default();
+        let start = Instant::now();
+        let timeout = Duration::from_secs(30);
+        buffer.add(1, 0, 2, String::from("old"), MAX_SIZE, start).unwrap();
+        buffer.add(2, 0, 2, String::from("new"), MAX_SIZE, start).unwrap();
+        buffer
+            .add(2, 1, 3, String::from("x"), MAX_SIZE, start + Duration::from_secs(20))
+            .unwrap_err();
+        buffer.add(3, 0, 2, String::from("abc"), MAX_SIZE, start + Duration::from_secs(20)).unwrap();
+
+        assert_eq!(buffer.evict_stale(start + Duration::from_secs(25), timeout), 0);
+        assert_eq!(buffer.evict_stale(start + Duration::from_secs(31), timeout), 2);
+        assert_eq!(buffer.size(), 3);
+        assert_eq!(
+            buffer.add(3, 1, 2, String::from("def"), MAX_SIZE, start + Duration::from_secs(31)),
+            Ok(Some(String::from("abcdef")))
+        );
+        assert!(buffer.is_empty());
+    }
+}
@@ -0,0 +1,199 @@
+use crate::lua::{run_cancellable_hook, to_array, LuaVehicleData};
+use crate::*;
+
+// Handlers can change the colors and the plate by returning a table with these fields
+fn apply_paint_changes(
+    changes: &rlua::Table,
+    color: &mut [f32; 8],
+    palete_0: &mut [f32; 8],
+    palete_1: &mut [f32; 8],
+    plate: &mut Option<String>,
+) -> rlua::Result<()> {
+    if let Some(value) = changes.get::<_, Option<Vec<f32>>>("color")? {
+        *color = to_array(value, "color")?;
+    }
+    if let Some(value) = changes.get::<_, Option<Vec<f32>>>("palete_0")? {
+        *palete_0 = to_array(value, "palete_0")?;
+    }
+    if let Some(value) = changes.get::<_, Option<Vec<f32>>>("palete_1")? {
+        *palete_1 = to_array(value, "palete_1")?;
+    }
+    if let Some(value) = changes.get::<_, Option<String>>("plate")? {
+        *plate = Some(value);
+    }
+    Ok(())
+}
+
+impl Server {
+    /// Runs `OnVehicleSpawnRequest`. Returns false if the vehicle was denied
+    pub fn vehicle_spawn_request_hook(&self, client_id: u32, data: &mut VehicleData) -> bool {
+        self.lua.context(|lua_ctx| {
+            let changes = match run_cancellable_hook(
+                lua_ctx,
+                "OnVehicleSpawnRequest",
+                (client_id, LuaVehicleData(data.clone())),
+            ) {
+                Some(changes) => changes,
+                None => return false,
+            };
+            for changes in changes {
+                let result = (|| -> rlua::Result<()> {
+                    if let Some(parts_config) = changes.get::<_, Option<String>>("parts_config")? {
+                        data.parts_config = parts_config;
+                    }
+                    apply_paint_changes(
+                        &changes,
+                        &mut data.color,
+                        &mut data.palete_0,
+                        &mut data.palete_1,
+                        &mut data.plate,
+                    )
+                })();
+                if let Err(e) = result {
+                    error!("OnVehicleSpawnRequest returned invalid changes: {}", e);
+                }
+            }
+            true
+        })
+    }
+
+    /// Runs `OnVehicleMetaUpdate`. Returns false if the update was denied
+    pub fn vehicle_meta_update_hook(
+        &self,
+        client_id: u32,
+        server_id: u32,
+        meta: &mut VehicleMeta,
+    ) -> bool {
+        self.lua.context(|lua_ctx| {
+            let table = lua_ctx.create_table().unwrap();
+            table.set("plate", meta.plate.clone()).unwrap();
+            table.set("color", meta.colors_table[0].to_vec()).unwrap();
+            table.set("palete_0", meta.colors_table[1].to_vec()).unwrap();
+            table.set("palete_1", meta.colors_table[2].to_vec()).unwrap();
+            let changes = match run_cancellable_hook(
+                lua_ctx,
+                "OnVehicleMetaUpdate",
+                (client_id, server_id, table),
+            ) {
+                Some(changes) => changes,
+                None => return false,
+            };
+            for changes in changes {
+                let [mut color, mut palete_0, mut palete_1] = meta.colors_table;
+                let result = apply_paint_changes(
+                    &changes,
+                    &mut color,
+                    &mut palete_0,
+                    &mut palete_1,
+                    &mut meta.plate,
+                );
+                match result {
+                    Ok(()) => meta.colors_table = [color, palete_0, palete_1],
+                    Err(e) => error!("OnVehicleMetaUpdate returned invalid changes: {}", e),
+                }
+            }
+            true
+        })
+    }
+
+    /// Puts the stored colors and plate back on the player's own vehicle after its update was denied
+    pub async fn reset_vehicle_meta(&mut self, client_id: u32, in_game_id: u32, server_id: u32) {
+        let meta = match self.vehicles.get(&server_id) {
+            Some(vehicle) => VehicleMeta {
+                vehicle_id: in_game_id,
+                plate: vehicle.data.plate.clone(),
+                colors_table: [
+                    vehicle.data.color,
+                    vehicle.data.palete_0,
+                    vehicle.data.palete_1,
+                ],
+            },
+            None => return,
+        };
+        let meta = serde_json::to_string(&meta).unwrap();
+        if let Some(connection) = self.connections.get_mut(&client_id) {
+            connection
+                .send_lua(format!(
+                    "vehiclemanager.reset_own_vehicle_meta(jsonDecode({}))",
+                    crate::world_state::lua_long_string(&meta)
+                ))
+                .await;
+        }
+    }
+
+    /// Runs `OnVehicleChanged`. Returns false if the player may not enter the vehicle
+    pub fn vehicle_changed_hook(&self, client_id: u32, server_id: u32) -> bool {
+        self.lua.context(|lua_ctx| {
+            run_cancellable_hook(lua_ctx, "OnVehicleChanged", (client_id, server_id)).is_some()
+        })
+    }
+
+    /// Runs `OnCouplerAttached` or `OnCouplerDetached`. Returns false if the event was denied
+    pub fn coupler_hook(
+        &self,
+        hook: &str,
+        client_id: u32,
+        vehicle_a: u32,
+        vehicle_b: u32,
+        node_a: u32,
+        node_b: u32,
+    ) -> bool {
+        self.lua.context(|lua_ctx| {
+            run_cancellable_hook(
+                lua_ctx,
+                hook,
+                (client_id, vehicle_a, vehicle_b, node_a, node_b),
+            )
+            .is_some()
+        })
+    }
+
+    /// Runs `OnElectricsUndefinedUpdate`. Returns false if the update was denied
+    pub fn electrics_undefined_update_hook(
+        &self,
+        client_id: u32,
+        server_id: u32,
+        update: &mut ElectricsUndefined,
+    ) -> bool {
+        self.lua.context(|lua_ctx| {
+            let changes = match run_cancellable_hook(
+                lua_ctx,
+                "OnElectricsUndefinedUpdate",
+                (client_id, server_id, update.diff.clone()),
+            ) {
+                Some(changes) => changes,
+                None => return false,
+            };
+            // The returned table replaces the whole diff
+            for changes in changes {
+                match changes.pairs::<String, f32>().collect::<rlua::Result<_>>() {
+                    Ok(diff) => update.diff = diff,
+                    Err(e) => error!("OnElectricsUndefinedUpdate returned an invalid diff: {}", e),
+                }
+            }
+            true
+        })
+    }
+
+    /// Runs `OnModsRequest`. Returns false if no files should be sent
+    pub fn mods_request_hook(&self, client_id: u32, files: &mut Vec<String>) -> bool {
+        self.lua.context(|lua_ctx| {
+            let changes = match run_cancellable_hook(
+                lua_ctx,
+                "OnModsRequest",
+                (client_id, files.clone()),
+            ) {
+                Some(changes) => changes,
+                None => return false,
+            };
+            // The returned table replaces the list of requested files
+            for changes in changes {
+                match changes.sequence_values::<String>().collect::<rlua::Result<_>>() {
+                    Ok(list) => *files = list,
+                    Err(e) => error!("OnModsRequest returned an invalid file list: {}", e),
+                }
+            }
+            true
+        })
+    }
+}
@@ -0,0 +1,604 @@
+use crate::*;
+use rustyline::completion::Completer;
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::validate::Validator;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CommandSource {
+    Console,
+    Client(u32),
+}
+
+pub struct BuiltinCommand {
+    pub name: &'static str,
+    pub usage: &'static str,
+    pub help: &'static str,
+}
+
+pub const BUILTIN_COMMANDS: &[BuiltinCommand] = &[
+    BuiltinCommand {
+        name: "help",
+        usage: "help [command]",
+        help: "Lists commands or shows help for one",
+    },
+    BuiltinCommand {
+        name: "status",
+        usage: "status",
+        help: "Shows players, vehicles and uptime",
+    },
+    BuiltinCommand {
+        name: "kick",
+        usage: "kick <client id> [reason]",
+        help: "Kicks a player",
+    },
+    BuiltinCommand {
+        name: "ban",
+        usage: "ban <client id or identifier> [duration in seconds] [reason]",
+        help: "Bans a player. Permanent if no duration is given",
+    },
+    BuiltinCommand {
+        name: "unban",
+        usage: "unban <identifier>",
+        help: "Removes bans matching a secret, SteamID64 or IP",
+    },
+    BuiltinCommand {
+        name: "say",
+        usage: "say <message>",
+        help: "Sends a chat message to everyone",
+    },
+    BuiltinCommand {
+        name: "vehicles",
+        usage: "vehicles",
+        help: "Lists spawned vehicles",
+    },
+    BuiltinCommand {
+        name: "reload",
+        usage: "reload",
+        help: "Loads all addons again",
+    },
+    BuiltinCommand {
+        name: "addons",
+        usage: "addons [load|unload|enable|disable <name>]",
+        help: "Lists addons or loads, unloads, enables or disables one",
+    },
+    BuiltinCommand {
+        name: "hooks",
+        usage: "hooks",
+        help: "Shows the slowest Lua hooks",
+    },
+    BuiltinCommand {
+        name: "tickrate",
+        usage: "tickrate [value]",
+        help: "Shows or changes the tickrate",
+    },
+    BuiltinCommand {
+        name: "stop",
+        usage: "stop",
+        help: "Stops the server",
+    },
+];
+
+/// Splits a command line into arguments. Double quotes group words, `\"` is a literal quote
+pub fn split_arguments(input: &str) -> Vec<String> {
+    let mut args = vec![];
+    let mut current = String::new();
+    let mut in_quotes = false;
+    let mut has_arg = false;
+    let mut chars = input.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if chars.peek() == Some(&'"') => {
+                current.push(chars.next().unwrap());
+                has_arg = true;
+            }
+            '"' => {
+                in_quotes = !in_quotes;
+                has_arg = true;
+            }
+            c if c.is_whitespace() && !in_quotes => {
+                if has_arg {
+                    args.push(std::mem::take(&mut current));
+                    has_arg = false;
+                }
+            }
+            c => {
+                current.push(c);
+                has_arg = true;
+            }
+        }
+    }
+    if has_arg {
+        args.push(current);
+    }
+    args
+}
+
+struct CommandHelper {
+    names: Arc<Mutex<Vec<String>>>,
+}
+
+impl Completer for CommandHelper {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &rustyline::Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        let prefix = &line[..pos];
+        // Only command names are completed
+        if prefix.contains(char::is_whitespace) {
+            return Ok((pos, vec![]));
+        }
+        let mut candidates: Vec<String> = self
+            .names
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|name| name.starts_with(prefix))
+            .cloned()
+            .collect();
+        candidates.sort();
+        Ok((0, candidates))
+    }
+}
+
+impl Hinter for CommandHelper {
+    type Hint = String;
+}
+impl Highlighter for CommandHelper {}
+impl Validator for CommandHelper {}
+impl rustyline::Helper for CommandHelper {}
+
+#[cfg(unix)]
+static SAVED_TERMINAL: Mutex<Option<libc::termios>> = Mutex::new(None);
+
+/// rustyline keeps the terminal in raw mode while it waits for input.
+/// The console thread is still waiting when the server exits, so the original mode is restored by hand.
+#[cfg(unix)]
+fn save_terminal() {
+    unsafe {
+        if libc::isatty(libc::STDIN_FILENO) == 1 {
+            let mut termios = std::mem::zeroed();
+            if libc::tcgetattr(libc::STDIN_FILENO, &mut termios) == 0 {
+                *SAVED_TERMINAL.lock().unwrap() = Some(termios);
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+pub fn restore_terminal() {
+    if let Some(termios) = SAVED_TERMINAL.lock().unwrap().as_ref() {
+        unsafe {
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, termios);
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn save_terminal() {}
+
+#[cfg(not(unix))]
+pub fn restore_terminal() {}
+
+/// Reads console input on a separate thread, with history and tab completion of command names
+pub fn spawn_console(names: Arc<Mutex<Vec<String>>>, lines: mpsc::Sender<String>) {
+    save_terminal();
+    std::thread::spawn(move || {
+        let mut editor = rustyline::Editor::<CommandHelper>::new();
+        editor.set_helper(Some(CommandHelper { names }));
+        loop {
+            match editor.readline("") {
+                Ok(line) => {
+                    editor.add_history_entry(line.as_str());
+                    if lines.blocking_send(line).is_err() {
+                        break;
+                    }
+                }
+                // The terminal is in raw mode while reading, so Ctrl-C doesn't raise SIGINT
+                Err(ReadlineError::Interrupted) => {
+                    let _ = lines.blocking_send(String::from("stop"));
+                    break;
+                }
+                Err(ReadlineError::Eof) => break,
+                Err(e) => {
+                    error!("Failed to read console input: {}", e);
+                    break;
+                }
+            }
+        }
+    });
+}
+
+impl Server {
+    pub fn is_command(&self, name: &str) -> bool {
+        BUILTIN_COMMANDS.iter().any(|command| command.name == name)
+            || self.lua_commands_help().iter().any(|(x, _)| x == name)
+    }
+
+    fn lua_commands_help(&self) -> Vec<(String, String)> {
+        let mut result = vec![];
+        self.lua.context(|lua_ctx| {
+            let list: rlua::Result<rlua::Table> = lua_ctx
+                .globals()
+                .get::<_, rlua::Table>("commands")
+                .and_then(|commands| commands.get("list"));
+            if let Ok(list) = list {
+                for (name, command) in list.pairs::<String, rlua::Table>().flatten() {
+                    result.push((name, command.get("help").unwrap_or_default()));
+                }
+            }
+        });
+        result.sort();
+        result
+    }
+
+    /// Refreshes the command names used for tab completion
+    pub fn update_command_names(&self) {
+        let mut names: Vec<String> = BUILTIN_COMMANDS
+            .iter()
+            .map(|command| command.name.to_string())
+            .collect();
+        for (name, _) in self.lua_commands_help() {
+            if !names.contains(&name) {
+                names.push(name);
+            }
+        }
+        *self.command_names.lock().unwrap() = names;
+    }
+
+    async fn reply(&mut self, source: CommandSource, message: String) {
+        match source {
+            CommandSource::Console => {
+                for line in message.lines() {
+                    info!("{}", line);
+                }
+            }
+            CommandSource::Client(client_id) => {
+                if let Some(connection) = self.connections.get_mut(&client_id) {
+                    connection.send_chat_message(message).await;
+                }
+            }
+        }
+    }
+
+    pub async fn run_command(&mut self, source: CommandSource, input: &str) {
+        let args = split_arguments(input);
+        let (name, args) = match args.split_first() {
+            Some((name, args)) => (name.clone(), args.to_vec()),
+            None => return,
+        };
+        if source != CommandSource::Console {
+            info!("{:?} ran command: {}", source, input);
+        }
+        let result = match self.run_builtin_command(&name, &args).await {
+            Some(result) => result,
+            None => self.run_lua_command(source, &name, args),
+        };
+        match result {
+            Ok(Some(message)) => self.reply(source, message).await,
+            Ok(None) => {}
+            Err(e) => self.reply(source, e).await,
+        }
+    }
+
+    fn run_lua_command(
+        &mut self,
+        source: CommandSource,
+        name: &str,
+        args: Vec<String>,
+    ) -> Result<Option<String>, String> {
+        let client_id = match source {
+            CommandSource::Client(client_id) => Some(client_id),
+            CommandSource::Console => None,
+        };
+        self.hook_profiler.reset_instructions();
+        self.lua.context(|lua_ctx| {
+            let callback: rlua::Result<rlua::Function> = lua_ctx
+                .globals()
+                .get::<_, rlua::Table>("commands")
+                .and_then(|commands| commands.get::<_, rlua::Table>("list"))
+                .and_then(|list| list.get::<_, rlua::Table>(name))
+                .and_then(|command| command.get("callback"));
+            match callback {
+                Ok(callback) => callback
+                    .call::<_, Option<String>>((client_id, args))
+                    .map_err(|e| {
+                        error!("{}", e);
+                        format!("Command {} has failed", name)
+                    }),
+                Err(_) => Err(format!(
+                    "Unknown command: {}. Type help for a list of commands",
+                    name
+                )),
+            }
+        })
+    }
+
+    /// Returns None if there's no built-in command with this name
+    async fn run_builtin_command(
+        &mut self,
+        name: &str,
+        args: &[String],
+    ) -> Option<Result<Option<String>, String>> {
+        let usage = BUILTIN_COMMANDS
+            .iter()
+            .find(|command| command.name == name)?
+            .usage;
+        let usage_error = || Err(format!("Usage: {}", usage));
+        let result = match name {
+            "help" => {
+                let mut commands: Vec<(String, String)> = BUILTIN_COMMANDS
+                    .iter()
+                    .map(|command| (command.usage.to_string(), command.help.to_string()))
+                    .collect();
+                commands.extend(self.lua_commands_help());
+                match args.first() {
+                    Some(name) => commands
+                        .into_iter()
+                        .find(|(usage, _)| usage.split(' ').next() == Some(name.as_str()))
+                        .map(|(usage, help)| Some(format!("{} - {}", usage, help)))
+                        .ok_or_else(|| format!("Unknown command: {}", name)),
+                    None => Ok(Some(
+                        commands
+                            .into_iter()
+                            .map(|(usage, help)| format!("{} - {}", usage, help))
+                            .collect::<Vec<String>>()
+                            .join("\n"),
+                    )),
+                }
+            }
+            "status" => {
+                let mut status = format!(
+                    "{}: {}/{} players, {} vehicles, tick {}, up for {}s",
+                    self.name,
+                    self.connections.len(),
+                    self.max_players,
+                    self.vehicles.len(),
+                    self.tick,
+                    self.start_time.elapsed().as_secs()
+                );
+                for (id, connection) in &self.connections {
+                    status.push_str(&format!(
+                        "\n[{}] {} - {}ms, {}",
+                        id,
+                        connection.client_info_public.name,
+                        connection.client_info_public.ping,
+                        connection.conn.remote_address().ip()
+                    ));
+                }
+                Ok(Some(status))
+            }
+            "kick" => match args.first().and_then(|x| x.parse::<u32>().ok()) {
+                Some(client_id) => match self.connections.get(&client_id) {
+                    Some(connection) => {
+                        let reason = if args.len() > 1 {
+                            args[1..].join(" ")
+                        } else {
+                            String::from("Kicked by an admin")
+                        };
+                        connection.conn.close(1u32.into(), reason.as_bytes());
+                        Ok(None)
+                    }
+                    None => Err(format!("There's no client with id {}", client_id)),
+                },
+                None => usage_error(),
+            },
+            "ban" => match args.first() {
+                Some(target) => {
+                    let duration = args.get(1).and_then(|x| x.parse::<u64>().ok());
+                    let reason_start = if duration.is_some() { 2 } else { 1 };
+                    let reason = if args.len() > reason_start {
+                        args[reason_start..].join(" ")
+                    } else {
+                        String::from("No reason given")
+                    };
+                    match target.parse::<u32>() {
+                        Ok(client_id) if self.connections.contains_key(&client_id) => {
+                            self.ban_client(client_id, reason, duration)
+                        }
+                        _ => self.ban(access::Identity::parse(target), reason, duration),
+                    }
+                    let _ = self.update_lua_connections();
+                    Ok(Some(format!("Banned {}", target)))
+                }
+                None => usage_error(),
+            },
+            "unban" => match args.first() {
+                Some(identifier) => {
+                    self.unban(identifier);
+                    Ok(None)
+                }
+                None => usage_error(),
+            },
+            "say" => {
+                if args.is_empty() {
+                    usage_error()
+                } else {
+                    let message = args.join(" ");
+                    for client in self.connections.values_mut() {
+                        client.send_chat_message(message.clone()).await;
+                    }
+                    self.record_replay(ServerCommand::Chat(message, None));
+                    Ok(None)
+                }
+            }
+            "vehicles" => {
+                let mut ids: Vec<&u32> = self.vehicles.keys().collect();
+                ids.sort();
+                let lines: Vec<String> = ids
+                    .into_iter()
+                    .map(|id| {
+                        let vehicle = &self.vehicles[id];
+                        let owner = vehicle
+                            .data
+                            .owner
+                            .and_then(|owner| self.connections.get(&owner))
+                            .map(|c| c.client_info_public.name.clone())
+                            .unwrap_or(String::from("nobody"));
+                        format!("[{}] {} - owned by {}", id, vehicle.data.name, owner)
+                    })
+                    .collect();
+                if lines.is_empty() {
+                    Ok(Some(String::from("There are no vehicles")))
+                } else {
+                    Ok(Some(lines.join("\n")))
+                }
+            }
+            "hooks" => {
+                let lines: Vec<String> = self
+                    .hook_profiler
+                    .slowest()
+                    .into_iter()
+                    .take(10)
+                    .map(|(hook, handler, stats)| {
+                        format!(
+                            "{} ({}): {} calls, avg {:.2}ms, max {:.2}ms, {} overruns",
+                            hook,
+                            handler,
+                            stats.calls,
+                            stats.total.as_secs_f64() * 1000.0 / stats.calls as f64,
+                            stats.max.as_secs_f64() * 1000.0,
+                            stats.overruns
+                        )
+                    })
+                    .collect();
+                if lines.is_empty() {
+                    Ok(Some(String::from("No hooks have been called yet")))
+                } else {
+                    Ok(Some(lines.join("\n")))
+                }
+            }
+            "addons" => match (args.first().map(String::as_str), args.get(1)) {
+                (None, _) => {
+                    let lines: Vec<String> = self
+                        .addon_names()
+                        .into_iter()
+                        .map(|name| {
+                            let state = if self.is_addon_loaded(&name) {
+                                "loaded"
+                            } else if self.disabled_addons.contains(&name) {
+                                "disabled"
+                            } else {
+                                "not loaded"
+                            };
+                            format!("{} - {}", name, state)
+                        })
+                        .collect();
+                    if lines.is_empty() {
+                        Ok(Some(String::from("There are no addons")))
+                    } else {
+                        Ok(Some(lines.join("\n")))
+                    }
+                }
+                (Some(_), Some(name)) if !self.addon_names().contains(name) => {
+                    Err(format!("Unknown addon: {}", name))
+                }
+                (Some("load"), Some(name)) => {
+                    if self.load_addon(name) {
+                        Ok(Some(format!("Addon {} loaded", name)))
+                    } else {
+                        Err(format!("Failed to load addon {}", name))
+                    }
+                }
+                (Some("unload"), Some(name)) => {
+                    if self.unload_addon(name) {
+                        Ok(Some(format!("Addon {} unloaded", name)))
+                    } else {
+                        Err(format!("Addon {} isn't loaded", name))
+                    }
+                }
+                (Some("enable"), Some(name)) => {
+                    if self.enable_addon(name) {
+                        Ok(Some(format!("Addon {} enabled", name)))
+                    } else {
+                        Err(format!("Failed to load addon {}", name))
+                    }
+                }
+                (Some("disable"), Some(name)) => {
+                    self.disable_addon(name);
+                    Ok(Some(format!(
+                        "Addon {} disabled until the server restarts",
+                        name
+                    )))
+                }
+                _ => usage_error(),
+            },
+            "reload" => {
+                self.load_lua_addons();
+                let _ = self.update_lua_connections();
+                Ok(Some(String::from("Addons reloaded")))
+            }
+            "tickrate" => match args.first() {
+                Some(value) => match value.parse::<u8>() {
+                    Ok(tickrate) if tickrate > 0 => {
+                        self.tickrate = tickrate;
+                        Ok(Some(format!("Tickrate set to {}", tickrate)))
+                    }
+                    _ => Err(String::from("Tickrate has to be between 1 and 255")),
+                },
+                None => Ok(Some(format!("Tickrate is {}", self.tickrate))),
+            },
+            "stop" => {
+                self.stop_requested = true;
+                Ok(None)
+            }
+            _ => return None,
+        };
+        Some(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn arguments_are_split_by_whitespace() {
+        assert_eq!(
+            split_arguments("kick 3 Spamming"),
+            vec!["kick", "3", "Spamming"]
+        );
+        assert_eq!(
+            split_arguments("  kick \t 3   Spamming  "),
+            vec!["kick", "3", "Spamming"]
+        );
+        assert!(split_arguments("").is_empty());
+        assert!(split_arguments("   ").is_empty());
+    }
+
+    #[test]
+    fn quotes_group_words() {
+        assert_eq!(
+            split_arguments("kick 3 \"Spamming the  chat\""),
+            vec!["kick", "3", "Spamming the  chat"]
+        );
+        assert_eq!(split_arguments("say a\"b c\"d"), vec!["say", "ab cd"]);
+        // An unclosed quote runs to the end
+        assert_eq!(
+            split_arguments("say \"hello world"),
+            vec!["say", "hello world"]
+        );
+    }
+
+    #[test]
+    fn empty_quotes_are_an_argument() {
+        assert_eq!(split_arguments("ban \"\" 60"), vec!["ban", "", "60"]);
+        assert_eq!(split_arguments("\"\""), vec![""]);
+    }
+
+    #[test]
+    fn escaped_quotes_are_literal() {
+        assert_eq!(split_arguments("say \\\"hi\\\""), vec!["say", "\"hi\""]);
+        assert_eq!(
+            split_arguments("say \"a \\\"quoted\\\" word\""),
+            vec!["say", "a \"quoted\" word"]
+        );
+        // Other backslashes are kept
+        assert_eq!(split_arguments("say a\\b \\"), vec!["say", "a\\b", "\\"]);
+    }
+}
@@ -1,6 +1,8 @@
+use crate::validation::SuspiciousUpdateAction;
+use crate::*;
 use serde::{Deserialize, Serialize};
 
-#[derive(Deserialize, Serialize)]
+#[derive(Deserialize, Serialize, Clone)]
 #[serde(default)]
 pub struct Config {
     pub server_name: String,
@@ -10,11 +12,44 @@ pub struct Config {
     pub tickrate: u8,
     pub port: u16,
     pub max_vehicles_per_client: u8,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub allowed_vehicles: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub denied_vehicles: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub banned_parts: Vec<String>,
+    pub max_vehicle_spawns_per_minute: u32,
+    pub max_vehicle_speed: f32,
+    pub suspicious_update_action: SuspiciousUpdateAction,
+    pub max_chunked_message_size: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub view_distance: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub full_rate_distance: Option<f32>,
     pub show_in_server_list: bool,
     pub upnp_enabled: bool,
+    pub record_replays: bool,
+    pub persist_world: bool,
     pub server_identifier: String,
+    pub lua_hook_budget_ms: u64,
+    pub lua_instruction_limit: u64,
+    pub lua_disable_slow_hooks_after: u32,
+    pub lua_http_timeout_ms: u64,
+    pub lua_http_max_response_size: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metrics_port: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub admin_api_port: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub admin_api_token: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub mods: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub disabled_addons: Vec<String>,
+    // Tables have to come last, TOML can't have plain values after them
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    pub vehicle_limits_per_role: HashMap<String, u8>,
+    pub rate_limits: crate::rate_limit::RateLimits,
 }
 
 impl Default for Config {
@@ -26,32 +61,192 @@ impl Default for Config {
             tickrate: 60,
             max_players: 8,
             max_vehicles_per_client: 3,
+            allowed_vehicles: vec![],
+            denied_vehicles: vec![],
+            banned_parts: vec![],
+            max_vehicle_spawns_per_minute: 0,
+            vehicle_limits_per_role: HashMap::new(),
+            max_vehicle_speed: 250.0,
+            suspicious_update_action: SuspiciousUpdateAction::Clamp,
+            rate_limits: crate::rate_limit::RateLimits::default(),
+            max_chunked_message_size: 4 * 1024 * 1024,
+            view_distance: None,
+            full_rate_distance: None,
             port: 3698,
             show_in_server_list: false,
             upnp_enabled: false,
+            record_replays: false,
+            persist_world: false,
             server_identifier: rand_string(),
+            lua_hook_budget_ms: 5,
+            lua_instruction_limit: 10_000_000,
+            lua_disable_slow_hooks_after: 0,
+            lua_http_timeout_ms: 10_000,
+            lua_http_max_response_size: 4 * 1024 * 1024,
+            metrics_port: None,
+            admin_api_port: None,
+            admin_api_token: None,
             mods: None,
+            disabled_addons: vec![],
+        }
+    }
+}
+
+const ENV_PREFIX: &str = "KISSMP_";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ConfigFormat {
+    Json,
+    Toml,
+    Yaml,
+}
+
+impl ConfigFormat {
+    /// Picks the format by file extension, JSON is the default
+    pub fn from_path(path: &std::path::Path) -> Self {
+        match path.extension().and_then(|x| x.to_str()) {
+            Some("toml") => ConfigFormat::Toml,
+            Some("yaml") | Some("yml") => ConfigFormat::Yaml,
+            _ => ConfigFormat::Json,
+        }
+    }
+}
+
+/// Fields set on the command line. They take priority over the file and the environment
+#[derive(Clone, Debug, Default)]
+pub struct ConfigOverrides {
+    pub port: Option<u16>,
+}
+
+impl ConfigOverrides {
+    fn apply(&self, config: &mut Config) {
+        if let Some(port) = self.port {
+            config.port = port;
+            info!("Config field port is set by --port");
         }
     }
 }
 
 impl Config {
-    pub fn load(path: &std::path::Path) -> Self {
+    /// Creates a default config if there's none at the path yet
+    pub fn load(path: &std::path::Path, overrides: &ConfigOverrides) -> anyhow::Result<Self> {
         if !path.exists() {
-            create_default_config();
+            create_default_config(path)?;
         }
-        let config_file = std::fs::File::open(path).unwrap();
-        let reader = std::io::BufReader::new(config_file);
-        serde_json::from_reader(reader).unwrap()
+        Self::from_file(path, overrides)
     }
+
+    /// Reads the config, applies `KISSMP_*` environment variable overrides and the command line ones,
+    /// then validates the result
+    pub fn from_file(path: &std::path::Path, overrides: &ConfigOverrides) -> anyhow::Result<Self> {
+        let text = std::fs::read_to_string(path)?;
+        let mut value: serde_json::Value = match ConfigFormat::from_path(path) {
+            ConfigFormat::Json => serde_json::from_str(&text)?,
+            ConfigFormat::Toml => toml::from_str(&text)?,
+            ConfigFormat::Yaml => serde_yaml::from_str(&text)?,
+        };
+        match &mut value {
+            serde_json::Value::Object(fields) => apply_env_overrides(fields, std::env::vars()),
+            _ => return Err(anyhow::Error::msg("Config has to be a map of fields")),
+        }
+        let (mut config, unknown_fields) = Self::from_value(value)?;
+        for field in unknown_fields {
+            warn!("Unknown config field {} is ignored", field);
+        }
+        overrides.apply(&mut config);
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Returns the config together with the fields it doesn't know
+    fn from_value(value: serde_json::Value) -> anyhow::Result<(Self, Vec<String>)> {
+        let mut unknown_fields = vec![];
+        let mut callback = |path: serde_ignored::Path| unknown_fields.push(path.to_string());
+        let deserializer = serde_ignored::Deserializer::new(value, &mut callback);
+        let config: Self = serde_path_to_error::deserialize(deserializer).map_err(|e| {
+            anyhow::Error::msg(format!("Invalid value for {}: {}", e.path(), e.inner()))
+        })?;
+        Ok((config, unknown_fields))
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let mut errors = vec![];
+        if self.tickrate == 0 {
+            errors.push("tickrate has to be greater than 0");
+        }
+        if self.max_players == 0 {
+            errors.push("max_players has to be greater than 0");
+        }
+        if !is_positive(self.max_vehicle_speed) {
+            errors.push("max_vehicle_speed has to be a positive number");
+        }
+        if matches!(self.view_distance, Some(x) if !is_positive(x)) {
+            errors.push("view_distance has to be a positive number");
+        }
+        if matches!(self.full_rate_distance, Some(x) if !is_positive(x)) {
+            errors.push("full_rate_distance has to be a positive number");
+        }
+        if self.admin_api_port.is_some()
+            && self.admin_api_token.as_deref().is_none_or(str::is_empty)
+        {
+            errors.push("admin_api_token is required when admin_api_port is set");
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(anyhow::Error::msg(errors.join(", ")))
+        }
+    }
+
+    pub fn to_string(&self, format: ConfigFormat) -> anyhow::Result<String> {
+        Ok(match format {
+            ConfigFormat::Json => serde_json::to_string_pretty(self)?,
+            ConfigFormat::Toml => toml::to_string_pretty(self)?,
+            ConfigFormat::Yaml => serde_yaml::to_string(self)?,
+        })
+    }
+}
+
+fn is_positive(x: f32) -> bool {
+    x.is_finite() && x > 0.0
 }
 
-pub fn create_default_config() {
-    use std::io::prelude::*;
-    let mut config_file = std::fs::File::create("./config.json").unwrap();
+/// `KISSMP_SERVER_NAME=abc` overrides `server_name`. Values are parsed as JSON, so
+/// `KISSMP_MODS=["a.zip"]` works too. Anything that isn't valid JSON is used as a string.
+fn apply_env_overrides(
+    fields: &mut serde_json::Map<String, serde_json::Value>,
+    vars: impl Iterator<Item = (String, String)>,
+) {
+    for (key, raw) in vars {
+        let field = match key.strip_prefix(ENV_PREFIX) {
+            Some(field) if !field.is_empty() => field.to_lowercase(),
+            _ => continue,
+        };
+        let mut value =
+            serde_json::from_str(&raw).unwrap_or(serde_json::Value::String(raw.clone()));
+        // A token or a name can look like a number. Use it as a string if that's what the field takes
+        if !value.is_string()
+            && !field_accepts(&field, value.clone())
+            && field_accepts(&field, serde_json::Value::String(raw.clone()))
+        {
+            value = serde_json::Value::String(raw);
+        }
+        fields.insert(field.clone(), value);
+        info!("Config field {} is set by {}", field, key);
+    }
+}
+
+/// Checks the value against the type of the field alone, the rest of the config doesn't matter
+fn field_accepts(field: &str, value: serde_json::Value) -> bool {
+    let mut fields = serde_json::Map::new();
+    fields.insert(field.to_string(), value);
+    serde_json::from_value::<Config>(serde_json::Value::Object(fields)).is_ok()
+}
+
+pub fn create_default_config(path: &std::path::Path) -> anyhow::Result<()> {
     let config = Config::default();
-    let config_str = serde_json::to_vec_pretty(&config).unwrap();
-    config_file.write_all(&config_str).unwrap();
+    std::fs::write(path, config.to_string(ConfigFormat::from_path(path))?)?;
+    Ok(())
 }
 
 fn rand_string() -> String {
@@ -59,3 +254,276 @@ fn rand_string() -> String {
         .map(|_| (0x20u8 + (rand::random::<f32>() * 96.0) as u8) as char)
         .collect()
 }
+
+impl Server {
+    /// Reloads the config whenever the file changes
+    pub fn watch_config(&mut self, path: &std::path::Path, overrides: ConfigOverrides) {
+        use notify::Watcher;
+        let path = match path.canonicalize() {
+            Ok(path) => path,
+            Err(e) => {
+                error!("Failed to watch config {}: {}", path.display(), e);
+                return;
+            }
+        };
+        // Editors often replace the file instead of writing to it, so the directory is watched
+        if let Some(parent) = path.parent() {
+            if let Err(e) = self
+                .lua_watcher
+                .watch(parent, notify::RecursiveMode::NonRecursive)
+            {
+                error!("Failed to watch config {}: {}", path.display(), e);
+                return;
+            }
+        }
+        self.config_path = Some(path);
+        self.config_overrides = overrides;
+    }
+
+    pub fn is_config_path(&self, path: &std::path::Path) -> bool {
+        self.config_path.as_deref() == Some(path)
+    }
+
+    pub async fn reload_config(&mut self) {
+        let path = match &self.config_path {
+            Some(path) => path.clone(),
+            None => return,
+        };
+        match Config::from_file(&path, &self.config_overrides) {
+            Ok(config) => self.apply_config(config).await,
+            Err(e) => error!("Failed to reload config, keeping the old one: {}", e),
+        }
+    }
+
+    /// Applies the fields that can change while the server is running.
+    /// Changes to the other fields are logged and ignored.
+    pub async fn apply_config(&mut self, mut config: Config) {
+        let old = &self.config;
+        let mut rejected = vec![];
+        if config.port != old.port {
+            rejected.push("port");
+        }
+        if config.map != old.map {
+            rejected.push("map");
+        }
+        if config.upnp_enabled != old.upnp_enabled {
+            rejected.push("upnp_enabled");
+        }
+        if config.server_identifier != old.server_identifier {
+            rejected.push("server_identifier");
+        }
+        if config.record_replays != old.record_replays {
+            rejected.push("record_replays");
+        }
+        if config.admin_api_port != old.admin_api_port
+            || config.admin_api_token != old.admin_api_token
+        {
+            rejected.push("admin_api_port/admin_api_token");
+        }
+        if config.metrics_port != old.metrics_port {
+            rejected.push("metrics_port");
+        }
+        if config.mods != old.mods {
+            if let Err(e) = list_mods(config.mods.clone(), &self.paths.mods) {
+                error!("Failed to list mods from the new config: {}", e);
+                config.mods = old.mods.clone();
+            }
+        }
+        for field in rejected {
+            warn!(
+                "{} can't be changed while the server is running. Restart the server to apply it",
+                field
+            );
+        }
+        let config = Config {
+            port: old.port,
+            map: old.map.clone(),
+            upnp_enabled: old.upnp_enabled,
+            server_identifier: old.server_identifier.clone(),
+            record_replays: old.record_replays,
+            admin_api_port: old.admin_api_port,
+            admin_api_token: old.admin_api_token.clone(),
+            metrics_port: old.metrics_port,
+            ..config
+        };
+        self.name = config.server_name.clone();
+        self.description = config.description.clone();
+        self.max_players = config.max_players;
+        self.max_vehicles_per_client = config.max_vehicles_per_client;
+        self.vehicle_policy = crate::vehicle_policy::VehiclePolicy::from_config(&config);
+        self.tickrate = config.tickrate;
+        self.max_vehicle_speed = config.max_vehicle_speed;
+        self.suspicious_update_action = config.suspicious_update_action;
+        *self.rate_limits.lock().unwrap() = config.rate_limits.clone();
+        self.max_chunked_message_size = config.max_chunked_message_size;
+        self.interest = crate::interest::InterestSettings {
+            view_distance: config.view_distance,
+            full_rate_distance: config.full_rate_distance,
+        };
+        self.show_in_list = config.show_in_server_list;
+        self.persist_world = config.persist_world;
+        self.mods = config.mods.clone();
+        self.hook_profiler
+            .set_limits(crate::hook_profiler::HookLimits::from_config(&config));
+        self.http
+            .set_limits(crate::http::HttpLimits::from_config(&config));
+
+        let old_disabled = std::mem::replace(&mut self.config, config).disabled_addons;
+        let disabled = self.config.disabled_addons.clone();
+        for name in &disabled {
+            if !old_disabled.contains(name) {
+                info!("Disabling addon {}", name);
+                self.disable_addon(name);
+            }
+        }
+        for name in &old_disabled {
+            if !disabled.contains(name) && self.addon_names().contains(name) {
+                info!("Enabling addon {}", name);
+                self.enable_addon(name);
+            }
+        }
+        info!("Config reloaded");
+
+        let ids: Vec<u32> = self.connections.keys().cloned().collect();
+        for id in ids {
+            let server_info = self.server_info(id);
+            if let Some(connection) = self.connections.get_mut(&id) {
+                let _ = connection
+                    .ordered
+                    .send(ServerCommand::ServerInfo(server_info))
+                    .await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn override_fields(vars: &[(&str, &str)]) -> Config {
+        let mut fields = serde_json::Map::new();
+        let vars = vars
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()));
+        apply_env_overrides(&mut fields, vars);
+        Config::from_value(serde_json::Value::Object(fields))
+            .unwrap()
+            .0
+    }
+
+    #[test]
+    fn zero_tickrate_is_invalid() {
+        let config = Config {
+            tickrate: 0,
+            ..Config::default()
+        };
+        let error = config.validate().unwrap_err().to_string();
+        assert!(error.contains("tickrate"));
+        assert!(Config::default().validate().is_ok());
+    }
+
+    #[test]
+    fn non_positive_distances_are_invalid() {
+        for value in &[0.0, -1.0, f32::NAN, f32::INFINITY] {
+            let config = Config {
+                max_vehicle_speed: *value,
+                view_distance: Some(*value),
+                ..Config::default()
+            };
+            let error = config.validate().unwrap_err().to_string();
+            assert!(error.contains("max_vehicle_speed"));
+            assert!(error.contains("view_distance"));
+        }
+    }
+
+    #[test]
+    fn numeric_names_stay_strings() {
+        let config = override_fields(&[("KISSMP_SERVER_NAME", "1234")]);
+        assert_eq!(config.server_name, "1234");
+        let config = override_fields(&[("KISSMP_ADMIN_API_TOKEN", "5678")]);
+        assert_eq!(config.admin_api_token.as_deref(), Some("5678"));
+    }
+
+    #[test]
+    fn env_overrides_are_parsed_as_json() {
+        let config = override_fields(&[
+            ("KISSMP_PORT", "3699"),
+            ("KISSMP_MODS", r#"["a.zip"]"#),
+            ("KISSMP_VIEW_DISTANCE", "500"),
+            ("PORT", "1"),
+        ]);
+        assert_eq!(config.port, 3699);
+        assert_eq!(config.mods, Some(vec![String::from("a.zip")]));
+        assert_eq!(config.view_distance, Some(500.0));
+    }
+
+    #[test]
+    fn invalid_env_override_reports_the_field() {
+        let mut fields = serde_json::Map::new();
+        let vars = vec![(String::from("KISSMP_PORT"), String::from("abc"))];
+        apply_env_overrides(&mut fields, vars.into_iter());
+        let error = Config::from_value(serde_json::Value::Object(fields))
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(error.starts_with("Invalid value for port"));
+    }
+
+    #[test]
+    fn format_is_picked_by_extension() {
+        use std::path::Path;
+        assert_eq!(
+            ConfigFormat::from_path(Path::new("a.toml")),
+            ConfigFormat::Toml
+        );
+        assert_eq!(
+            ConfigFormat::from_path(Path::new("a.yaml")),
+            ConfigFormat::Yaml
+        );
+        assert_eq!(
+            ConfigFormat::from_path(Path::new("a.yml")),
+            ConfigFormat::Yaml
+        );
+        assert_eq!(
+            ConfigFormat::from_path(Path::new("a.json")),
+            ConfigFormat::Json
+        );
+        assert_eq!(
+            ConfigFormat::from_path(Path::new("config")),
+            ConfigFormat::Json
+        );
+    }
+
+    #[test]
+    fn default_config_round_trips() {
+        let dir = std::env::temp_dir().join(format!("kissmp-config-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let default = Config::default();
+        for name in &["config.json", "config.toml", "config.yaml"] {
+            let path = dir.join(name);
+            std::fs::write(
+                &path,
+                default.to_string(ConfigFormat::from_path(&path)).unwrap(),
+            )
+            .unwrap();
+            let config = Config::from_file(&path, &ConfigOverrides::default()).unwrap();
+            assert_eq!(config.server_name, default.server_name);
+            assert_eq!(config.server_identifier, default.server_identifier);
+            assert_eq!(config.max_vehicle_speed, default.max_vehicle_speed);
+            assert_eq!(config.rate_limits, default.rate_limits);
+        }
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn command_line_overrides_win() {
+        let dir = std::env::temp_dir().join(format!("kissmp-overrides-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config.toml");
+        create_default_config(&path).unwrap();
+        let overrides = ConfigOverrides { port: Some(4000) };
+        assert_eq!(Config::from_file(&path, &overrides).unwrap().port, 4000);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -7,6 +7,9 @@ impl Server {
         match event {
             ClientConnected(connection) => {
                 let player_name = connection.client_info_public.name.clone();
+                self.record_replay(ServerCommand::PlayerInfoUpdate(
+                    connection.client_info_public.clone(),
+                ));
                 self.connections.insert(client_id, connection);
                 // Kinda ugly, but idk how to deal with lifetimes otherwise
                 let mut client_info_list = vec![];
@@ -49,6 +52,10 @@ impl Server {
                 });
             }
             ConnectionLost => {
+                crate::metrics::METRICS.remove_client(client_id);
+                self.vehicle_spawn_times.remove(&client_id);
+                self.client_id_allocator.release(client_id);
+                self.chunk_buffers.remove(&client_id);
                 let player_name = self
                     .connections
                     .get(&client_id)
@@ -62,11 +69,13 @@ impl Server {
                     .conn
                     .close(0u32.into(), b"");
                 self.connections.remove(&client_id);
+                self.record_replay(ServerCommand::PlayerDisconnected(client_id));
                 if let Some(client_vehicles) = self.vehicle_ids.clone().get(&client_id) {
                     for (_, id) in client_vehicles {
                         self.remove_vehicle(*id, Some(client_id)).await;
                     }
                 }
+                self.restore_returning_vehicles(client_id).await;
                 for (_, client) in &mut self.connections {
                     client
                         .send_chat_message(format!("Player {} has left the server", player_name))
@@ -86,6 +95,10 @@ impl Server {
                 });
                 info!("Client has disconnected from the server");
             }
+            VehicleSpawnDropped(in_game_id) => {
+                let reason = String::from("You are spawning vehicles too fast");
+                self.deny_vehicle(client_id, in_game_id, reason).await;
+            }
             ClientCommand(command) => {
                 match command {
                     Chat(initial_message) => {
@@ -101,6 +114,18 @@ impl Server {
                                 .name,
                             message
                         );
+                        // Commands from players without admin rights are handled like regular messages
+                        if let Some(input) = message.strip_prefix('/') {
+                            let name = crate::commands::split_arguments(input).into_iter().next();
+                            if let Some(name) = name {
+                                if self.is_admin(client_id) && self.is_command(&name) {
+                                    let input = input.to_string();
+                                    let source = crate::commands::CommandSource::Client(client_id);
+                                    self.run_command(source, &input).await;
+                                    return;
+                                }
+                            }
+                        }
                         self.lua.context(|lua_ctx| {
                             let results = crate::lua::run_hook::<(u32, String), Option<String>>(
                                 lua_ctx,
@@ -115,6 +140,7 @@ impl Server {
                             }
                         });
                         if message.len() > 0 {
+                            self.record_replay(ServerCommand::Chat(message.clone(), Some(client_id)));
                             for (_, client) in &mut self.connections {
                                 client
                                     .send_player_chat_message(message.clone(), client_id)
@@ -122,11 +148,15 @@ impl Server {
                             }
                         }
                     }
-                    VehicleUpdate(data) => {
+                    VehicleUpdate(mut data) => {
                         if let Some(server_id) =
                             self.get_server_id_from_game_id(client_id, data.vehicle_id)
                         {
+                            if !self.validate_vehicle_update(client_id, server_id, &mut data) {
+                                return;
+                            }
                             if let Some(vehicle) = self.vehicles.get_mut(&server_id) {
+                                vehicle.update_sent_at = Some(data.sent_at);
                                 vehicle.data.position = data.transform.position;
                                 vehicle.data.rotation = data.transform.rotation;
                                 vehicle.transform = Some(data.transform);
@@ -135,21 +165,31 @@ impl Server {
                             }
                         }
                     }
-                    VehicleData(data) => {
+                    VehicleData(mut data) => {
                         // Remove old vehicle with the same ID
-                        if let Some(server_id) =
+                        let kind = if let Some(server_id) =
                             self.get_server_id_from_game_id(client_id, data.in_game_id)
                         {
                             self.remove_vehicle(server_id, Some(client_id)).await;
+                            vehicle_policy::SpawnKind::Replacement
+                        } else if self.take_returning_vehicle(client_id, &data) {
+                            vehicle_policy::SpawnKind::Returning
+                        } else {
+                            vehicle_policy::SpawnKind::New
+                        };
+                        if let Err(reason) = self.check_vehicle_policy(client_id, &data, kind) {
+                            self.deny_vehicle(client_id, data.in_game_id, reason).await;
+                            return;
                         }
-                        if let Some(client_vehicles) = self.vehicle_ids.get(&client_id) {
-                            if (data.name != "unicycle")
-                                && (client_vehicles.len() as u8 >= self.max_vehicles_per_client)
-                            {
-                                return;
-                            }
+                        if !self.vehicle_spawn_request_hook(client_id, &mut data) {
+                            let reason = format!("{} was denied by the server", data.name);
+                            self.deny_vehicle(client_id, data.in_game_id, reason).await;
+                            return;
                         }
+                        self.record_vehicle_spawn(client_id, &data, kind);
                         self.spawn_vehicle(Some(client_id), data).await;
+                        // The client is in game now and can take its saved vehicles back
+                        self.return_restored_vehicles(client_id).await;
                     }
                     RemoveVehicle(id) => {
                         if let Some(server_id) = self.get_server_id_from_game_id(client_id, id) {
@@ -163,8 +203,11 @@ impl Server {
                             self.reset_vehicle(data, Some(client_id)).await;
                         }
                     }
-                    RequestMods(files) => {
-                        let paths = crate::list_mods(self.mods.clone());
+                    RequestMods(mut files) => {
+                        if !self.mods_request_hook(client_id, &mut files) {
+                            return;
+                        }
+                        let paths = crate::list_mods(self.mods.clone(), &self.paths.mods);
                         for path in paths.unwrap().1 {
                             if path.is_dir() {
                                 continue;
@@ -183,10 +226,14 @@ impl Server {
                                 .await;
                         }
                     }
-                    VehicleMetaUpdate(meta) => {
+                    VehicleMetaUpdate(mut meta) => {
                         if let Some(server_id) =
                             self.get_server_id_from_game_id(client_id, meta.vehicle_id)
                         {
+                            if !self.vehicle_meta_update_hook(client_id, server_id, &mut meta) {
+                                self.reset_vehicle_meta(client_id, meta.vehicle_id, server_id).await;
+                                return;
+                            }
                             if let Some(vehicle) = self.vehicles.get_mut(&server_id) {
                                 vehicle.data.color = meta.colors_table[0];
                                 vehicle.data.palete_0 = meta.colors_table[1];
@@ -203,10 +250,17 @@ impl Server {
                             }
                         }
                     }
-                    ElectricsUndefinedUpdate(vehicle_id, undefined_update) => {
+                    ElectricsUndefinedUpdate(vehicle_id, mut undefined_update) => {
                         if let Some(server_id) =
                             self.get_server_id_from_game_id(client_id, vehicle_id)
                         {
+                            if !self.electrics_undefined_update_hook(
+                                client_id,
+                                server_id,
+                                &mut undefined_update,
+                            ) {
+                                return;
+                            }
                             /* if let Some(vehicle) = self.vehicles.get_mut(&server_id) {
                                 for (key, value) in &undefined_update.diff {
                                     if let Some(electrics) = &mut vehicle.electrics {
@@ -228,6 +282,11 @@ impl Server {
                     Ping(ping) => {
                         let connection = self.connections.get_mut(&client_id).unwrap();
                         connection.client_info_public.ping = ping as u32;
+                        crate::metrics::METRICS.set_ping(
+                            client_id,
+                            &connection.client_info_public.name,
+                            ping as u32,
+                        );
                         let start = std::time::SystemTime::now();
                         let since_the_epoch = start.duration_since(std::time::UNIX_EPOCH).unwrap();
                         let data = bincode::serialize(&shared::ServerCommand::Pong(
@@ -238,10 +297,36 @@ impl Server {
                     }
                     VehicleChanged(id) => {
                         if let Some(server_id) = self.get_server_id_from_game_id(client_id, id) {
+                            if !self.vehicle_changed_hook(client_id, server_id) {
+                                return;
+                            }
                             self.set_current_vehicle(client_id, Some(server_id)).await;
                         }
                     }
+                    VehicleUpdateAck(acks) => {
+                        if let Some(connection) = self.connections.get_mut(&client_id) {
+                            connection.compact_updates = true;
+                            for (vehicle_id, generation) in acks {
+                                let acked = connection
+                                    .acked_generations
+                                    .entry(vehicle_id)
+                                    .or_insert(generation);
+                                *acked = generation.max(*acked);
+                            }
+                        }
+                    }
                     CouplerAttached(event) => {
+                        if !self.coupler_hook(
+                            "OnCouplerAttached",
+                            client_id,
+                            event.obj_a,
+                            event.obj_b,
+                            event.node_a_id,
+                            event.node_b_id,
+                        ) {
+                            return;
+                        }
+                        self.record_replay(ServerCommand::CouplerAttached(event.clone()));
                         for (_, client) in &mut self.connections {
                             let _ = client
                                 .ordered
@@ -250,6 +335,17 @@ impl Server {
                         }
                     }
                     CouplerDetached(event) => {
+                        if !self.coupler_hook(
+                            "OnCouplerDetached",
+                            client_id,
+                            event.obj_a,
+                            event.obj_b,
+                            event.node_a_id,
+                            event.node_b_id,
+                        ) {
+                            return;
+                        }
+                        self.record_replay(ServerCommand::CouplerDetached(event.clone()));
                         for (_, client) in &mut self.connections {
                             let _ = client
                                 .ordered
@@ -282,35 +378,9 @@ impl Server {
                             let _ = client.conn.send_datagram(data.clone().into());
                         }
                     }
-                    DataChunk { chunk_index, total_chunks, data } => {
-                        // info!("Received chunk {}/{} from client {}", chunk_index + 1, total_chunks, client_id);
-                        let chunks = self.chunk_buffers
-                            .entry(client_id)
-                            .or_insert_with(HashMap::new)
-                            .entry(total_chunks)
-                            .or_insert_with(|| vec![String::new(); total_chunks as usize]);
-                        
-                        chunks[chunk_index as usize] = data;
-
-                        if chunks.iter().all(|c| !c.is_empty()) {
-                            // info!("All {} chunks received, reassembling data", total_chunks);
-                            let full_json = chunks.join("");
-                            // Parse and recursively handle reassembled command
-                            match serde_json::from_str::<shared::ClientCommand>(&full_json) {
-                                Ok(original_command) => {
-                                    // Box to avoid infinite type size
-                                    Box::pin(self.on_client_event(
-                                        client_id, 
-                                        IncomingEvent::ClientCommand(original_command)
-                                    )).await;
-                                }
-                                Err(e) => {
-                                    error!("Failed to parse reassembled JSON: {}", e);
-                                }
-                            }
-                            // Clear chunks for this client
-                            self.chunk_buffers.get_mut(&client_id).unwrap().remove(&total_chunks);
-                        }
+                    DataChunk { message_id, chunk_index, total_chunks, data } => {
+                        self.on_data_chunk(client_id, message_id, chunk_index, total_chunks, data)
+                            .await;
                     }
                     _ => {}
                 }
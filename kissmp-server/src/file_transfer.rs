@@ -32,6 +32,12 @@ pub async fn transfer_file(
         )
         .await?;
         stream.finish();
+        metrics::METRICS.record_message(
+            metrics::Direction::Sent,
+            metrics::Transport::Stream,
+            "FilePart",
+        );
+        metrics::METRICS.record_file_transfer(n);
         chunk_n += 1;
     }
     Ok(())
@@ -0,0 +1,173 @@
+use crate::*;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+// Instructions between two checks of the instruction limit
+const INSTRUCTION_CHECK_INTERVAL: u32 = 1000;
+const REGISTRY_KEY: &str = "kissmp_hook_profiler";
+// Slow tick warnings are printed at most this often
+pub const SLOW_TICK_WARNING_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Debug)]
+pub struct HookLimits {
+    /// Time a single hook handler may take before a warning is printed
+    pub budget: Duration,
+    /// Maximum amount of Lua instructions per call. 0 disables the limit
+    pub instruction_limit: u64,
+    /// Handlers are unregistered after exceeding their budget this many times. 0 never unregisters them
+    pub disable_after: u32,
+}
+
+impl HookLimits {
+    pub fn from_config(config: &config::Config) -> Self {
+        Self {
+            budget: Duration::from_millis(config.lua_hook_budget_ms),
+            instruction_limit: config.lua_instruction_limit,
+            disable_after: config.lua_disable_slow_hooks_after,
+        }
+    }
+}
+
+#[derive(Default, Clone, Debug)]
+pub struct HandlerStats {
+    pub calls: u64,
+    pub total: Duration,
+    pub max: Duration,
+    /// Calls that exceeded the budget or the instruction limit
+    pub overruns: u32,
+}
+
+struct ProfilerState {
+    limits: HookLimits,
+    // (hook, handler) -> stats
+    stats: HashMap<(String, String), HandlerStats>,
+}
+
+/// Measures hook handlers and stops runaway Lua code.
+/// It's stored in the Lua registry, so `run_hook` can reach it without access to the server.
+#[derive(Clone)]
+pub struct HookProfiler {
+    state: Arc<Mutex<ProfilerState>>,
+    instructions: Arc<AtomicU64>,
+    instruction_limit: Arc<AtomicU64>,
+}
+
+impl rlua::UserData for HookProfiler {}
+
+impl HookProfiler {
+    pub fn new(limits: HookLimits) -> Self {
+        Self {
+            instruction_limit: Arc::new(AtomicU64::new(limits.instruction_limit)),
+            state: Arc::new(Mutex::new(ProfilerState {
+                limits,
+                stats: HashMap::new(),
+            })),
+            instructions: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    pub fn install(&self, lua: &rlua::Lua) {
+        let instructions = self.instructions.clone();
+        let instruction_limit = self.instruction_limit.clone();
+        let triggers = rlua::HookTriggers {
+            every_nth_instruction: Some(INSTRUCTION_CHECK_INTERVAL),
+            ..Default::default()
+        };
+        lua.set_hook(triggers, move |_, _| {
+            let count = instructions.fetch_add(INSTRUCTION_CHECK_INTERVAL as u64, Ordering::Relaxed)
+                + INSTRUCTION_CHECK_INTERVAL as u64;
+            let limit = instruction_limit.load(Ordering::Relaxed);
+            if limit != 0 && count > limit {
+                return Err(rlua::Error::RuntimeError(format!(
+                    "Instruction limit of {} exceeded",
+                    limit
+                )));
+            }
+            Ok(())
+        });
+        lua.context(|lua_ctx| {
+            lua_ctx
+                .set_named_registry_value(REGISTRY_KEY, self.clone())
+                .unwrap();
+        });
+    }
+
+    pub fn from_lua(lua_ctx: rlua::Context) -> Option<Self> {
+        let profiler: rlua::AnyUserData = lua_ctx.named_registry_value(REGISTRY_KEY).ok()?;
+        let profiler = profiler.borrow::<HookProfiler>().ok()?.clone();
+        Some(profiler)
+    }
+
+    pub fn set_limits(&self, limits: HookLimits) {
+        self.instruction_limit
+            .store(limits.instruction_limit, Ordering::Relaxed);
+        self.state.lock().unwrap().limits = limits;
+    }
+
+    pub fn budget(&self) -> Duration {
+        self.state.lock().unwrap().limits.budget
+    }
+
+    /// Has to be called before running any Lua code, so the instruction limit applies to each call separately
+    pub fn reset_instructions(&self) {
+        self.instructions.store(0, Ordering::Relaxed);
+    }
+
+    fn instruction_limit_hit(&self) -> bool {
+        let limit = self.instruction_limit.load(Ordering::Relaxed);
+        limit != 0 && self.instructions.load(Ordering::Relaxed) > limit
+    }
+
+    /// Records a finished handler call. Returns true if the handler should be disabled
+    pub fn record(&self, hook: &str, handler: &str, duration: Duration) -> bool {
+        let hit_limit = self.instruction_limit_hit();
+        let mut state = self.state.lock().unwrap();
+        let limits = state.limits.clone();
+        let stats = state
+            .stats
+            .entry((hook.to_string(), handler.to_string()))
+            .or_default();
+        stats.calls += 1;
+        stats.total += duration;
+        stats.max = stats.max.max(duration);
+        if !hit_limit && duration <= limits.budget {
+            return false;
+        }
+        stats.overruns += 1;
+        if hit_limit {
+            warn!(
+                "Hook {} ({}) was stopped after exceeding the instruction limit",
+                hook, handler
+            );
+        } else {
+            warn!(
+                "Hook {} ({}) took {:.2}ms, over the budget of {}ms",
+                hook,
+                handler,
+                duration.as_secs_f64() * 1000.0,
+                limits.budget.as_millis()
+            );
+        }
+        if limits.disable_after != 0 && stats.overruns >= limits.disable_after {
+            error!(
+                "Hook {} ({}) has been disabled after {} overruns",
+                hook, handler, stats.overruns
+            );
+            stats.overruns = 0;
+            return true;
+        }
+        false
+    }
+
+    /// Handlers sorted from the slowest to the fastest
+    pub fn slowest(&self) -> Vec<(String, String, HandlerStats)> {
+        let state = self.state.lock().unwrap();
+        let mut result: Vec<(String, String, HandlerStats)> = state
+            .stats
+            .iter()
+            .map(|((hook, handler), stats)| (hook.clone(), handler.clone(), stats.clone()))
+            .collect();
+        result.sort_by_key(|x| std::cmp::Reverse(x.2.max));
+        result
+    }
+}
@@ -0,0 +1,439 @@
+use crate::*;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+const HTTP_REGISTRY_KEY: &str = "kissmp_http";
+// Lua table: request ID -> { callback, addon }
+const CALLBACKS_REGISTRY_KEY: &str = "kissmp_http_callbacks";
+
+// Without a callback, `http.request` waits for the response inside of an `async` function
+const REQUEST_HELPER: &str = r#"
+local request = ...
+return function(options, callback)
+    if callback then
+        return request(options, callback)
+    end
+    local co, is_main = coroutine.running()
+    if not co or is_main then
+        error("http.request needs a callback outside of async functions", 2)
+    end
+    request(options, function(response, err)
+        local ok, resume_err = coroutine.resume(co, response, err)
+        if not ok then
+            error(resume_err, 0)
+        end
+    end)
+    return coroutine.yield()
+end
+"#;
+
+#[derive(Clone, Debug)]
+pub struct HttpLimits {
+    pub timeout: Duration,
+    /// Responses with a larger body fail with an error
+    pub max_response_size: usize,
+}
+
+impl HttpLimits {
+    pub fn from_config(config: &config::Config) -> Self {
+        Self {
+            timeout: Duration::from_millis(config.lua_http_timeout_ms),
+            max_response_size: config.lua_http_max_response_size,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct HttpRequest {
+    pub method: String,
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Option<Vec<u8>>,
+}
+
+#[derive(Clone, Debug)]
+pub struct HttpResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+pub async fn send_request(
+    client: &reqwest::Client,
+    request: HttpRequest,
+    limits: &HttpLimits,
+) -> anyhow::Result<HttpResponse> {
+    let method = reqwest::Method::from_bytes(request.method.to_uppercase().as_bytes())
+        .map_err(|_| anyhow::anyhow!("Invalid method {}", request.method))?;
+    let mut builder = client.request(method, &request.url);
+    for (name, value) in request.headers {
+        builder = builder.header(name, value);
+    }
+    if let Some(body) = request.body {
+        builder = builder.body(body);
+    }
+    let max_size = limits.max_response_size;
+    let response = async move {
+        let mut response = builder.send().await?;
+        if response.content_length().unwrap_or(0) > max_size as u64 {
+            return Err(anyhow::anyhow!(
+                "Response is larger than the limit of {} bytes",
+                max_size
+            ));
+        }
+        let status = response.status().as_u16();
+        let headers = response
+            .headers()
+            .iter()
+            .map(|(name, value)| {
+                (
+                    name.to_string(),
+                    String::from_utf8_lossy(value.as_bytes()).to_string(),
+                )
+            })
+            .collect();
+        // Content-Length can be missing or wrong, so the body is counted while it's read
+        let mut body = vec![];
+        while let Some(chunk) = response.chunk().await? {
+            if body.len() + chunk.len() > max_size {
+                return Err(anyhow::anyhow!(
+                    "Response is larger than the limit of {} bytes",
+                    max_size
+                ));
+            }
+            body.extend_from_slice(&chunk);
+        }
+        Ok(HttpResponse {
+            status,
+            headers,
+            body,
+        })
+    };
+    tokio::time::timeout(limits.timeout, response)
+        .await
+        .map_err(|_| anyhow::anyhow!("Request timed out after {}ms", limits.timeout.as_millis()))?
+}
+
+pub type HttpResult = (u64, Result<HttpResponse, String>);
+
+/// Requests run on the tokio runtime and their responses are sent back to the server loop,
+/// so callbacks are called between ticks. Callbacks are kept in the Lua registry.
+#[derive(Clone)]
+pub struct Http {
+    client: reqwest::Client,
+    tx: mpsc::UnboundedSender<HttpResult>,
+    limits: Arc<Mutex<HttpLimits>>,
+    next_id: Arc<AtomicU64>,
+}
+
+impl rlua::UserData for Http {}
+
+impl Http {
+    pub fn new(
+        client: reqwest::Client,
+        limits: HttpLimits,
+    ) -> (Self, mpsc::UnboundedReceiver<HttpResult>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        (
+            Self {
+                client,
+                tx,
+                limits: Arc::new(Mutex::new(limits)),
+                next_id: Arc::new(AtomicU64::new(1)),
+            },
+            rx,
+        )
+    }
+
+    pub fn install(&self, lua: &rlua::Lua) {
+        lua.context(|lua_ctx| {
+            lua_ctx
+                .set_named_registry_value(HTTP_REGISTRY_KEY, self.clone())
+                .unwrap();
+            lua_ctx
+                .set_named_registry_value(CALLBACKS_REGISTRY_KEY, lua_ctx.create_table().unwrap())
+                .unwrap();
+            add_http_table(lua_ctx, lua_ctx.globals(), None).unwrap();
+        });
+    }
+
+    fn from_lua(lua_ctx: rlua::Context) -> rlua::Result<Self> {
+        let http: rlua::AnyUserData = lua_ctx.named_registry_value(HTTP_REGISTRY_KEY)?;
+        let http = http.borrow::<Http>()?.clone();
+        Ok(http)
+    }
+
+    pub fn set_limits(&self, limits: HttpLimits) {
+        *self.limits.lock().unwrap() = limits;
+    }
+
+    fn start(&self, request: HttpRequest) -> rlua::Result<u64> {
+        let runtime = tokio::runtime::Handle::try_current().map_err(|_| {
+            rlua::Error::RuntimeError(String::from(
+                "HTTP requests can't be made outside of the server",
+            ))
+        })?;
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let client = self.client.clone();
+        let limits = self.limits.lock().unwrap().clone();
+        let tx = self.tx.clone();
+        runtime.spawn(async move {
+            let result = send_request(&client, request, &limits)
+                .await
+                .map_err(|e| e.to_string());
+            let _ = tx.send((id, result));
+        });
+        Ok(id)
+    }
+}
+
+fn request_from_lua(options: rlua::Table) -> rlua::Result<HttpRequest> {
+    let url: String = options.get("url")?;
+    let method: Option<String> = options.get("method")?;
+    let mut headers = vec![];
+    if let Some(table) = options.get::<_, Option<rlua::Table>>("headers")? {
+        for pair in table.pairs::<String, String>() {
+            headers.push(pair?);
+        }
+    }
+    let body = match options.get::<_, rlua::Value>("body")? {
+        rlua::Value::Nil => None,
+        rlua::Value::String(body) => Some(body.as_bytes().to_vec()),
+        // Tables are sent as JSON
+        rlua::Value::Table(table) => {
+            if !headers
+                .iter()
+                .any(|(name, _)| name.eq_ignore_ascii_case("content-type"))
+            {
+                headers.push((
+                    String::from("Content-Type"),
+                    String::from("application/json"),
+                ));
+            }
+            let json = lua::lua_to_json(rlua::Value::Table(table))?;
+            Some(serde_json::to_vec(&json).unwrap())
+        }
+        _ => {
+            return Err(rlua::Error::RuntimeError(String::from(
+                "Request body has to be a string or a table",
+            )))
+        }
+    };
+    Ok(HttpRequest {
+        method: method.unwrap_or(String::from("GET")),
+        url,
+        headers,
+        body,
+    })
+}
+
+/// Adds the `http` table to a table.
+/// Callbacks of an addon's requests are dropped when it's unloaded
+pub fn add_http_table<'lua>(
+    lua_ctx: rlua::Context<'lua>,
+    table: rlua::Table<'lua>,
+    addon: Option<String>,
+) -> rlua::Result<()> {
+    let request = lua_ctx.create_function(
+        move |lua_ctx, (options, callback): (rlua::Table, rlua::Function)| {
+            let id = Http::from_lua(lua_ctx)?.start(request_from_lua(options)?)?;
+            let pending = lua_ctx.create_table()?;
+            pending.set("callback", callback)?;
+            pending.set("addon", addon.clone())?;
+            let callbacks: rlua::Table = lua_ctx.named_registry_value(CALLBACKS_REGISTRY_KEY)?;
+            callbacks.set(id, pending)?;
+            Ok(id)
+        },
+    )?;
+    let request: rlua::Function = lua_ctx
+        .load(REQUEST_HELPER)
+        .set_name("http")?
+        .into_function()?
+        .call(request)?;
+    let http = lua_ctx.create_table()?;
+    http.set("request", request)?;
+    table.set("http", http)?;
+    Ok(())
+}
+
+fn response_to_lua<'lua>(
+    lua_ctx: rlua::Context<'lua>,
+    response: HttpResponse,
+) -> rlua::Result<rlua::Table<'lua>> {
+    let table = lua_ctx.create_table()?;
+    table.set("status", response.status)?;
+    let headers = lua_ctx.create_table()?;
+    for (name, value) in response.headers {
+        headers.set(name, value)?;
+    }
+    table.set("headers", headers)?;
+    table.set("body", lua_ctx.create_string(&response.body)?)?;
+    Ok(table)
+}
+
+impl Server {
+    pub fn run_http_callback(&mut self, (id, result): HttpResult) {
+        self.hook_profiler.reset_instructions();
+        let start = std::time::Instant::now();
+        let found = self.lua.context(|lua_ctx| {
+            let callbacks: rlua::Table = match lua_ctx.named_registry_value(CALLBACKS_REGISTRY_KEY) {
+                Ok(callbacks) => callbacks,
+                Err(_) => return false,
+            };
+            // The addon could have been unloaded while the request was running
+            let pending: rlua::Table = match callbacks.get::<_, Option<rlua::Table>>(id) {
+                Ok(Some(pending)) => pending,
+                _ => return false,
+            };
+            callbacks.set(id, rlua::Value::Nil).unwrap();
+            let callback: rlua::Function = pending.get("callback").unwrap();
+            let addon: Option<String> = pending.get("addon").unwrap();
+            let call_result = match result {
+                Ok(response) => match response_to_lua(lua_ctx, response) {
+                    Ok(response) => callback.call::<_, ()>((response, rlua::Value::Nil)),
+                    Err(e) => Err(e),
+                },
+                Err(e) => callback.call::<_, ()>((rlua::Value::Nil, e)),
+            };
+            if let Err(e) = call_result {
+                error!("HTTP callback {} has failed: {}", id, e);
+            }
+            let addon = addon.unwrap_or(String::from("server"));
+            self.hook_profiler.record("HttpResponse", &addon, start.elapsed());
+            true
+        });
+        if found {
+            crate::metrics::METRICS.record_hook("HttpResponse", start.elapsed());
+        }
+    }
+
+    /// Drops the callbacks of the addon's pending requests
+    pub fn cancel_addon_requests(&mut self, addon: &str) {
+        self.lua.context(|lua_ctx| {
+            let callbacks: rlua::Table = match lua_ctx.named_registry_value(CALLBACKS_REGISTRY_KEY) {
+                Ok(callbacks) => callbacks,
+                Err(_) => return,
+            };
+            let mut owned = vec![];
+            for (id, pending) in callbacks.clone().pairs::<u64, rlua::Table>().flatten() {
+                if pending.get::<_, Option<String>>("addon").ok().flatten().as_deref() == Some(addon) {
+                    owned.push(id);
+                }
+            }
+            for id in owned {
+                let _ = callbacks.set(id, rlua::Value::Nil);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Serves every request on a local port with the given handler
+    fn mock_server(
+        handler: impl Fn(tiny_http::Request) + Send + 'static,
+    ) -> (String, std::thread::JoinHandle<()>) {
+        let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", server.server_addr());
+        let handle = std::thread::spawn(move || {
+            for request in server.incoming_requests() {
+                handler(request);
+            }
+        });
+        (url, handle)
+    }
+
+    fn limits() -> HttpLimits {
+        HttpLimits {
+            timeout: Duration::from_secs(5),
+            max_response_size: 1024,
+        }
+    }
+
+    fn request(method: &str, url: String) -> HttpRequest {
+        HttpRequest {
+            method: method.to_string(),
+            url,
+            headers: vec![],
+            body: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn sends_method_headers_and_body() {
+        let (url, _server) = mock_server(|mut request| {
+            let mut body = String::new();
+            request.as_reader().read_to_string(&mut body).unwrap();
+            let token = request
+                .headers()
+                .iter()
+                .find(|header| header.field.equiv("X-Token"))
+                .map(|header| header.value.to_string())
+                .unwrap_or_default();
+            let reply = format!("{} {} {}", request.method(), token, body);
+            let response = tiny_http::Response::from_string(reply)
+                .with_status_code(201)
+                .with_header("X-Reply: yes".parse::<tiny_http::Header>().unwrap());
+            request.respond(response).unwrap();
+        });
+        let mut post = request("post", url);
+        post.headers.push((String::from("X-Token"), String::from("secret")));
+        post.body = Some(b"hello".to_vec());
+        let response = send_request(&reqwest::Client::new(), post, &limits())
+            .await
+            .unwrap();
+        assert_eq!(response.status, 201);
+        assert_eq!(response.body, b"POST secret hello");
+        assert!(response
+            .headers
+            .contains(&(String::from("x-reply"), String::from("yes"))));
+    }
+
+    #[tokio::test]
+    async fn rejects_large_responses() {
+        let (url, _server) = mock_server(|request| {
+            request
+                .respond(tiny_http::Response::from_data(vec![0u8; 4096]))
+                .unwrap();
+        });
+        let result = send_request(&reqwest::Client::new(), request("GET", url), &limits()).await;
+        assert!(result.unwrap_err().to_string().contains("larger than the limit"));
+    }
+
+    #[tokio::test]
+    async fn rejects_large_responses_without_content_length() {
+        let (url, _server) = mock_server(|request| {
+            let data = std::io::Cursor::new(vec![0u8; 4096]);
+            let response = tiny_http::Response::new(200.into(), vec![], data, None, None);
+            request.respond(response).unwrap();
+        });
+        let result = send_request(&reqwest::Client::new(), request("GET", url), &limits()).await;
+        assert!(result.unwrap_err().to_string().contains("larger than the limit"));
+    }
+
+    #[tokio::test]
+    async fn times_out() {
+        let (url, _server) = mock_server(|request| {
+            std::thread::sleep(Duration::from_secs(2));
+            let _ = request.respond(tiny_http::Response::empty(200));
+        });
+        let limits = HttpLimits {
+            timeout: Duration::from_millis(200),
+            ..limits()
+        };
+        let result = send_request(&reqwest::Client::new(), request("GET", url), &limits).await;
+        assert!(result.unwrap_err().to_string().contains("timed out"));
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_methods() {
+        let result = send_request(
+            &reqwest::Client::new(),
+            request("NOT A METHOD", String::from("http://127.0.0.1:1")),
+            &limits(),
+        )
+        .await;
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,227 @@
+use crate::*;
+use std::collections::HashSet;
+use std::time::Instant;
+
+const REGISTRY_KEY: &str = "kissmp_vehicle_ids";
+// Released IDs aren't handed out again for this long, so late messages about
+// an old client or vehicle can't be mistaken for a new one
+pub const ID_REUSE_DELAY: Duration = Duration::from_secs(60);
+// Vehicle IDs used to be random u16s, clients are used to them being small
+pub const MAX_VEHICLE_ID: u32 = u16::MAX as u32;
+// Players never get this ID, the replay viewer uses it for itself
+pub const REPLAY_CLIENT_ID: u32 = u32::MAX;
+pub const MAX_CLIENT_ID: u32 = REPLAY_CLIENT_ID - 1;
+
+struct AllocatorState {
+    min: u32,
+    max: u32,
+    next: u32,
+    live: HashSet<u32>,
+    // ID -> when it was released
+    released: HashMap<u32, Instant>,
+    reuse_delay: Duration,
+}
+
+impl AllocatorState {
+    fn is_cooling_down(&self, id: u32, now: Instant) -> bool {
+        self.released
+            .get(&id)
+            .map(|at| now.duration_since(*at) < self.reuse_delay)
+            .unwrap_or(false)
+    }
+}
+
+/// Hands out IDs that are unique among the live ones, in order from `min` to `max` and then around again.
+/// It's shared between the server and Lua, which picks IDs of server owned vehicles itself
+#[derive(Clone)]
+pub struct IdAllocator {
+    state: Arc<Mutex<AllocatorState>>,
+}
+
+impl rlua::UserData for IdAllocator {}
+
+impl IdAllocator {
+    pub fn new(min: u32, max: u32, reuse_delay: Duration) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(AllocatorState {
+                min,
+                max,
+                next: min,
+                live: HashSet::new(),
+                released: HashMap::new(),
+                reuse_delay,
+            })),
+        }
+    }
+
+    pub fn install(&self, lua: &rlua::Lua) {
+        lua.context(|lua_ctx| {
+            lua_ctx
+                .set_named_registry_value(REGISTRY_KEY, self.clone())
+                .unwrap();
+        });
+    }
+
+    pub fn from_lua(lua_ctx: rlua::Context) -> rlua::Result<Self> {
+        let allocator: rlua::AnyUserData = lua_ctx.named_registry_value(REGISTRY_KEY)?;
+        let allocator = allocator.borrow::<IdAllocator>()?.clone();
+        Ok(allocator)
+    }
+
+    /// Returns None if every ID is in use or was released too recently
+    pub fn allocate(&self) -> Option<u32> {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        let count = state.max as u64 - state.min as u64 + 1;
+        for _ in 0..count {
+            let id = state.next;
+            state.next = if id == state.max { state.min } else { id + 1 };
+            if state.live.contains(&id) || state.is_cooling_down(id, now) {
+                continue;
+            }
+            state.released.remove(&id);
+            state.live.insert(id);
+            return Some(id);
+        }
+        None
+    }
+
+    /// Marks a specific ID as used, like the ID of a vehicle restored from a save.
+    /// Returns false if it's out of range or already taken
+    pub fn reserve(&self, id: u32) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if id < state.min || id > state.max || state.is_cooling_down(id, Instant::now()) {
+            return false;
+        }
+        state.released.remove(&id);
+        state.live.insert(id)
+    }
+
+    pub fn release(&self, id: u32) {
+        let mut state = self.state.lock().unwrap();
+        if state.live.remove(&id) {
+            let now = Instant::now();
+            // IDs that waited long enough don't have to be remembered, otherwise this grows with every release
+            let reuse_delay = state.reuse_delay;
+            state
+                .released
+                .retain(|_, at| now.duration_since(*at) < reuse_delay);
+            state.released.insert(id, now);
+        }
+    }
+
+    pub fn is_live(&self, id: u32) -> bool {
+        self.state.lock().unwrap().live.contains(&id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{test_server, vehicle_data};
+
+    #[test]
+    fn live_ids_are_unique() {
+        let allocator = IdAllocator::new(1, 100, ID_REUSE_DELAY);
+        let mut ids = HashSet::new();
+        for _ in 0..100 {
+            assert!(ids.insert(allocator.allocate().unwrap()));
+        }
+        assert_eq!(allocator.allocate(), None);
+        assert!(ids.iter().all(|id| (1..=100).contains(id)));
+    }
+
+    #[test]
+    fn released_ids_wait_before_reuse() {
+        let allocator = IdAllocator::new(1, 3, ID_REUSE_DELAY);
+        let first = allocator.allocate().unwrap();
+        allocator.allocate().unwrap();
+        allocator.allocate().unwrap();
+        allocator.release(first);
+        assert_eq!(allocator.allocate(), None);
+        assert!(!allocator.reserve(first));
+    }
+
+    #[test]
+    fn released_ids_are_reused_after_the_delay() {
+        let allocator = IdAllocator::new(1, 2, Duration::from_millis(20));
+        let first = allocator.allocate().unwrap();
+        allocator.allocate().unwrap();
+        allocator.release(first);
+        std::thread::sleep(Duration::from_millis(30));
+        assert_eq!(allocator.allocate(), Some(first));
+    }
+
+    #[test]
+    fn expired_releases_are_forgotten() {
+        let allocator = IdAllocator::new(1, 100, Duration::from_millis(20));
+        for _ in 0..10 {
+            let id = allocator.allocate().unwrap();
+            allocator.release(id);
+        }
+        assert_eq!(allocator.state.lock().unwrap().released.len(), 10);
+        std::thread::sleep(Duration::from_millis(30));
+        let id = allocator.allocate().unwrap();
+        allocator.release(id);
+        assert_eq!(allocator.state.lock().unwrap().released.len(), 1);
+    }
+
+    #[test]
+    fn reserved_ids_are_skipped() {
+        let allocator = IdAllocator::new(1, 10, ID_REUSE_DELAY);
+        assert!(allocator.reserve(1));
+        assert!(allocator.reserve(2));
+        assert!(!allocator.reserve(2));
+        assert!(!allocator.reserve(11));
+        assert_eq!(allocator.allocate(), Some(3));
+    }
+
+    #[tokio::test]
+    async fn live_vehicles_never_share_an_id() {
+        let mut server = test_server("live-vehicles");
+        let mut spawned = 0;
+        for round in 0..3u32 {
+            for i in 0..300 {
+                server
+                    .spawn_vehicle(Some(i % 8), vehicle_data(round * 300 + i))
+                    .await;
+                spawned += 1;
+            }
+            // Remove some vehicles, so their IDs are released while others are still live
+            let ids: Vec<u32> = server.vehicles.keys().cloned().step_by(3).collect();
+            for id in ids {
+                server.remove_vehicle(id, None).await;
+                spawned -= 1;
+            }
+        }
+        assert_eq!(server.vehicles.len(), spawned);
+        for (id, vehicle) in &server.vehicles {
+            assert_eq!(*id, vehicle.data.server_id);
+            assert!(server.vehicle_id_allocator.is_live(*id));
+        }
+    }
+
+    #[tokio::test]
+    async fn lua_vehicles_never_share_an_id_with_client_vehicles() {
+        let mut server = test_server("lua-vehicles");
+        for i in 0..100 {
+            server.spawn_vehicle(Some(1), vehicle_data(i)).await;
+        }
+        let _ = server.update_lua_vehicles();
+        server.lua.context(|lua_ctx| {
+            lua_ctx
+                .load(
+                    r#"
+                    for i = 1, 100 do
+                        spawn_vehicle(build_vehicle("{}", {0,0,0,0,0,0,0,0}, {0,0,0,0,0,0,0,0},
+                            {0,0,0,0,0,0,0,0}, "", "pickup", {0,0,0}, {0,0,0,1}))
+                    end
+                    "#,
+                )
+                .exec()
+                .unwrap();
+        });
+        server.lua_tick().await.unwrap();
+        assert_eq!(server.vehicles.len(), 200);
+    }
+}
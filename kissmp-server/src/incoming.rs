@@ -5,15 +5,39 @@ pub enum IncomingEvent {
     ClientConnected(Connection),
     ConnectionLost,
     ClientCommand(shared::ClientCommand),
+    // A vehicle spawn that was over the rate limit, by in game ID
+    VehicleSpawnDropped(u32),
 }
 
 impl Server {
     pub async fn handle_incoming_data(
         id: u32,
         data: Vec<u8>,
+        transport: metrics::Transport,
         client_events_tx: &mut mpsc::Sender<(u32, IncomingEvent)>,
+        rate_limiter: &mut crate::rate_limit::RateLimiter,
     ) -> anyhow::Result<()> {
         let client_command = bincode::deserialize::<shared::ClientCommand>(&data)?;
+        metrics::METRICS.record_message(
+            metrics::Direction::Received,
+            transport,
+            client_command.name(),
+        );
+        match rate_limiter.check(&client_command) {
+            crate::rate_limit::Verdict::Allow => {}
+            crate::rate_limit::Verdict::Drop => {
+                // The sender's game has the vehicle already, it has to be deleted there as well
+                if let shared::ClientCommand::VehicleData(data) = client_command {
+                    client_events_tx
+                        .send((id, IncomingEvent::VehicleSpawnDropped(data.in_game_id)))
+                        .await?;
+                }
+                return Ok(());
+            }
+            crate::rate_limit::Verdict::Kick(reason) => {
+                return Err(crate::rate_limit::Kicked(reason).into())
+            }
+        }
         client_events_tx
             .send((id, IncomingEvent::ClientCommand(client_command)))
             .await?;
@@ -0,0 +1,294 @@
+use crate::*;
+
+// Far vehicles are never updated less often than every MAX_UPDATE_INTERVAL ticks
+const MAX_UPDATE_INTERVAL: u64 = 10;
+const DEFAULT_CELL_SIZE: f32 = 500.0;
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct InterestSettings {
+    /// Vehicles further than this are not sent at all
+    pub view_distance: Option<f32>,
+    /// Vehicles further than this are sent at a reduced rate
+    pub full_rate_distance: Option<f32>,
+}
+
+impl InterestSettings {
+    pub fn is_enabled(&self) -> bool {
+        self.view_distance.is_some() || self.full_rate_distance.is_some()
+    }
+
+    /// How many ticks have to pass between two updates of a vehicle at this distance
+    pub fn update_interval(&self, distance: f32) -> u64 {
+        if let Some(full_rate_distance) = self.full_rate_distance {
+            if full_rate_distance > 0.0 && distance > full_rate_distance {
+                return ((distance / full_rate_distance) as u64 + 1).min(MAX_UPDATE_INTERVAL);
+            }
+        }
+        1
+    }
+}
+
+// Vehicle ID and position
+type GridCell = Vec<(u32, [f32; 3])>;
+
+/// Uniform grid over the horizontal plane, used to find vehicles near a point
+pub struct SpatialGrid {
+    cell_size: f32,
+    cells: HashMap<(i32, i32), GridCell>,
+}
+
+impl SpatialGrid {
+    pub fn new(cell_size: f32) -> Self {
+        Self {
+            cell_size: if cell_size > 0.0 {
+                cell_size
+            } else {
+                DEFAULT_CELL_SIZE
+            },
+            cells: HashMap::new(),
+        }
+    }
+
+    pub fn from_vehicles(vehicles: &HashMap<u32, Vehicle>, cell_size: f32) -> Self {
+        let mut grid = Self::new(cell_size);
+        for (id, vehicle) in vehicles {
+            if let Some(transform) = &vehicle.transform {
+                grid.insert(*id, transform.position);
+            }
+        }
+        grid
+    }
+
+    fn cell(&self, position: [f32; 3]) -> (i32, i32) {
+        (
+            (position[0] / self.cell_size).floor() as i32,
+            (position[1] / self.cell_size).floor() as i32,
+        )
+    }
+
+    pub fn insert(&mut self, id: u32, position: [f32; 3]) {
+        let cell = self.cell(position);
+        self.cells.entry(cell).or_default().push((id, position));
+    }
+
+    /// Returns every vehicle within the radius together with its distance
+    pub fn query(&self, position: [f32; 3], radius: f32) -> Vec<(u32, f32)> {
+        let (cx, cy) = self.cell(position);
+        let range = (radius / self.cell_size).ceil() as i32;
+        let mut result = vec![];
+        for x in (cx - range)..=(cx + range) {
+            for y in (cy - range)..=(cy + range) {
+                if let Some(cell) = self.cells.get(&(x, y)) {
+                    for (id, other) in cell {
+                        let d = distance(position, *other);
+                        if d <= radius {
+                            result.push((*id, d));
+                        }
+                    }
+                }
+            }
+        }
+        result
+    }
+}
+
+pub fn distance(a: [f32; 3], b: [f32; 3]) -> f32 {
+    ((a[0] - b[0]).powi(2) + (a[1] - b[1]).powi(2) + (a[2] - b[2]).powi(2)).sqrt()
+}
+
+/// Picks the vehicles a client should receive an update for on this tick
+pub fn select_vehicles(
+    settings: &InterestSettings,
+    vehicles: &HashMap<u32, Vehicle>,
+    grid: Option<&SpatialGrid>,
+    current_vehicle: Option<u32>,
+    tick: u64,
+) -> Vec<u32> {
+    let origin = current_vehicle
+        .and_then(|id| vehicles.get(&id))
+        .and_then(|vehicle| vehicle.transform.as_ref())
+        .map(|transform| transform.position);
+    // Without a known position there is nothing to cull against
+    let origin = match origin {
+        Some(origin) if settings.is_enabled() => origin,
+        _ => return vehicles.keys().cloned().collect(),
+    };
+    let candidates: Vec<(u32, f32)> = match (settings.view_distance, grid) {
+        (Some(view_distance), Some(grid)) => grid.query(origin, view_distance),
+        (view_distance, _) => vehicles
+            .iter()
+            .filter_map(|(id, vehicle)| {
+                let transform = vehicle.transform.as_ref()?;
+                Some((*id, distance(origin, transform.position)))
+            })
+            .filter(|(_, distance)| view_distance.is_none_or(|x| *distance <= x))
+            .collect(),
+    };
+    let mut result: Vec<u32> = candidates
+        .into_iter()
+        .filter(|(id, distance)| {
+            // Stagger far updates by id so they don't all land on the same tick
+            Some(*id) == current_vehicle
+                || (tick + *id as u64).is_multiple_of(settings.update_interval(*distance))
+        })
+        .map(|(id, _)| id)
+        .collect();
+    // The client's own vehicle is always sent, even if it is outside of the grid
+    if let Some(current_vehicle) = current_vehicle {
+        if !result.contains(&current_vehicle) {
+            result.push(current_vehicle);
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::vehicle_data;
+
+    const SETTINGS: InterestSettings = InterestSettings {
+        view_distance: Some(1000.0),
+        full_rate_distance: Some(100.0),
+    };
+
+    fn vehicle(position: [f32; 3]) -> Vehicle {
+        Vehicle {
+            transform: Some(Transform {
+                position,
+                rotation: [0.0, 0.0, 0.0, 1.0],
+                velocity: [0.0; 3],
+                angular_velocity: [0.0; 3],
+            }),
+            electrics: None,
+            gearbox: None,
+            data: vehicle_data(0),
+            update_sent_at: None,
+        }
+    }
+
+    fn vehicles(positions: &[(u32, [f32; 3])]) -> HashMap<u32, Vehicle> {
+        positions
+            .iter()
+            .map(|(id, position)| (*id, vehicle(*position)))
+            .collect()
+    }
+
+    // Selects with and without a grid, both have to agree
+    fn select(
+        settings: &InterestSettings,
+        vehicles: &HashMap<u32, Vehicle>,
+        current_vehicle: Option<u32>,
+        tick: u64,
+    ) -> Vec<u32> {
+        let grid = SpatialGrid::from_vehicles(vehicles, settings.view_distance.unwrap_or(0.0));
+        let mut with_grid = select_vehicles(settings, vehicles, Some(&grid), current_vehicle, tick);
+        let mut without_grid = select_vehicles(settings, vehicles, None, current_vehicle, tick);
+        with_grid.sort_unstable();
+        without_grid.sort_unstable();
+        assert_eq!(with_grid, without_grid);
+        with_grid
+    }
+
+    #[test]
+    fn current_vehicle_is_always_selected() {
+        let mut vehicles = vehicles(&[(1, [0.0; 3]), (2, [50.0, 0.0, 0.0])]);
+        for tick in 0..20 {
+            assert!(select(&SETTINGS, &vehicles, Some(1), tick).contains(&1));
+        }
+        // Even without a position
+        vehicles.get_mut(&1).unwrap().transform = None;
+        assert!(select(&SETTINGS, &vehicles, Some(1), 0).contains(&1));
+    }
+
+    #[test]
+    fn vehicles_beyond_view_distance_are_never_selected() {
+        let vehicles = vehicles(&[
+            (1, [0.0; 3]),
+            (2, [999.0, 0.0, 0.0]),
+            (3, [1001.0, 0.0, 0.0]),
+            (4, [0.0, 0.0, 1500.0]),
+            (5, [-800.0, -800.0, 0.0]),
+        ]);
+        for tick in 0..MAX_UPDATE_INTERVAL * 2 {
+            let selected = select(&SETTINGS, &vehicles, Some(1), tick);
+            assert!(!selected.contains(&3));
+            assert!(!selected.contains(&4));
+            assert!(!selected.contains(&5));
+        }
+        // Vehicle 2 is far, but still sent on some ticks
+        assert!((0..MAX_UPDATE_INTERVAL)
+            .any(|tick| select(&SETTINGS, &vehicles, Some(1), tick).contains(&2)));
+    }
+
+    #[test]
+    fn far_vehicles_are_sent_at_a_reduced_rate() {
+        let vehicles = vehicles(&[
+            (1, [0.0; 3]),
+            (2, [100.0, 0.0, 0.0]),
+            (3, [250.0, 0.0, 0.0]),
+            (4, [950.0, 0.0, 0.0]),
+        ]);
+        let ticks = |id: u32| -> Vec<u64> {
+            (0..30)
+                .filter(|tick| select(&SETTINGS, &vehicles, Some(1), *tick).contains(&id))
+                .collect()
+        };
+        // Within full_rate_distance every tick
+        assert_eq!(ticks(2).len(), 30);
+        // 2.5 times full_rate_distance, every 3rd tick
+        assert_eq!(SETTINGS.update_interval(250.0), 3);
+        assert!(ticks(3).iter().all(|tick| (tick + 3).is_multiple_of(3)));
+        assert_eq!(ticks(3).len(), 10);
+        // The interval is capped
+        assert_eq!(SETTINGS.update_interval(950.0), MAX_UPDATE_INTERVAL);
+        assert!(ticks(4)
+            .iter()
+            .all(|tick| (tick + 4).is_multiple_of(MAX_UPDATE_INTERVAL)));
+        assert_eq!(ticks(4).len(), 3);
+    }
+
+    #[test]
+    fn everything_is_selected_when_disabled() {
+        let vehicles = vehicles(&[(1, [0.0; 3]), (2, [1e6, 0.0, 0.0])]);
+        let disabled = InterestSettings::default();
+        assert_eq!(select(&disabled, &vehicles, Some(1), 1), vec![1, 2]);
+        // Without a vehicle of their own there's nothing to measure from
+        assert_eq!(select(&SETTINGS, &vehicles, None, 1), vec![1, 2]);
+    }
+
+    #[test]
+    fn grid_cell_boundaries_keep_neighbours() {
+        let mut grid = SpatialGrid::new(100.0);
+        grid.insert(1, [99.9, 0.0, 0.0]);
+        grid.insert(2, [100.1, 0.0, 0.0]);
+        grid.insert(3, [-0.1, -0.1, 0.0]);
+        grid.insert(4, [99.9, 199.0, 0.0]);
+        grid.insert(5, [350.0, 0.0, 0.0]);
+        let mut found: Vec<u32> = grid
+            .query([99.9, 0.0, 0.0], 200.0)
+            .into_iter()
+            .map(|x| x.0)
+            .collect();
+        found.sort_unstable();
+        assert_eq!(found, vec![1, 2, 3, 4]);
+        let mut found: Vec<u32> = grid
+            .query([100.1, 0.0, 0.0], 1.0)
+            .into_iter()
+            .map(|x| x.0)
+            .collect();
+        found.sort_unstable();
+        assert_eq!(found, vec![1, 2]);
+        // A radius larger than a cell reaches more than the neighbouring cells
+        let found = grid.query([-0.1, -0.1, 0.0], 351.0);
+        assert_eq!(found.len(), 5);
+    }
+
+    #[test]
+    fn invalid_cell_sizes_use_the_default() {
+        let mut grid = SpatialGrid::new(0.0);
+        grid.insert(1, [10.0, 10.0, 0.0]);
+        assert_eq!(grid.query([0.0; 3], 20.0).len(), 1);
+        assert_eq!(SpatialGrid::new(-1.0).cell_size, DEFAULT_CELL_SIZE);
+    }
+}
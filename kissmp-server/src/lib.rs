@@ -3,13 +3,33 @@ use ipnetwork::Ipv4Network;
 use quinn::IdleTimeout;
 use shared::vehicle;
 
+pub mod access;
+pub mod addons;
+pub mod admin_api;
+pub mod chunks;
+pub mod command_hooks;
+pub mod commands;
 pub mod config;
 pub mod events;
 pub mod file_transfer;
+pub mod hook_profiler;
+pub mod http;
+pub mod ids;
 pub mod incoming;
+pub mod interest;
 pub mod lua;
+pub mod metrics;
 pub mod outgoing;
+pub mod rate_limit;
+pub mod replay;
 pub mod server_vehicle;
+pub mod storage;
+#[cfg(test)]
+mod test_utils;
+pub mod timers;
+pub mod validation;
+pub mod vehicle_policy;
+pub mod world_state;
 
 use incoming::IncomingEvent;
 use server_vehicle::*;
@@ -23,10 +43,13 @@ use log::{error, info, warn, debug};
 use std::collections::HashMap;
 use std::convert::TryFrom;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 use tokio::sync::mpsc;
-use tokio_stream::wrappers::{IntervalStream, ReceiverStream};
+use tokio_stream::wrappers::{IntervalStream, ReceiverStream, UnboundedReceiverStream};
+
+// Application close code sent to clients when the server stops
+pub const SHUTDOWN_CLOSE_CODE: u32 = 2;
 
 #[derive(Clone)]
 pub struct Connection {
@@ -35,6 +58,10 @@ pub struct Connection {
     pub unreliable: mpsc::Sender<ServerCommand>,
     pub client_info_private: ClientInfoPrivate,
     pub client_info_public: ClientInfoPublic,
+    // Set once the client acknowledges a vehicle update
+    pub compact_updates: bool,
+    pub sent_states: StateHistory,
+    pub acked_generations: HashMap<u32, u64>,
 }
 
 impl std::fmt::Debug for Connection {
@@ -65,10 +92,13 @@ impl Connection {
 
 pub struct Server {
     connections: HashMap<u32, Connection>,
+    access_list: Arc<Mutex<access::AccessList>>,
     vehicles: HashMap<u32, Vehicle>,
     // Client ID, game_id, server_id
     vehicle_ids: HashMap<u32, HashMap<u32, u32>>,
-    chunk_buffers: HashMap<u32, HashMap<u32, Vec<String>>>,  // client_id -> (total_chunks -> chunks)
+    client_id_allocator: ids::IdAllocator,
+    vehicle_id_allocator: ids::IdAllocator,
+    chunk_buffers: HashMap<u32, chunks::ChunkBuffer>,
     reqwest_client: reqwest::Client,
     name: String,
     description: String,
@@ -76,31 +106,106 @@ pub struct Server {
     tickrate: u8,
     max_players: u8,
     max_vehicles_per_client: u8,
+    vehicle_policy: vehicle_policy::VehiclePolicy,
+    vehicle_spawn_times: HashMap<u32, std::collections::VecDeque<std::time::Instant>>,
+    max_vehicle_speed: f32,
+    suspicious_update_action: validation::SuspiciousUpdateAction,
+    rate_limits: Arc<Mutex<rate_limit::RateLimits>>,
+    max_chunked_message_size: usize,
+    interest: interest::InterestSettings,
     port: u16,
     show_in_list: bool,
     lua: rlua::Lua,
     lua_watcher: notify::RecommendedWatcher,
     lua_watcher_rx: std::sync::mpsc::Receiver<notify::DebouncedEvent>,
     lua_commands: std::sync::mpsc::Receiver<lua::LuaCommand>,
+    hook_profiler: hook_profiler::HookProfiler,
+    disabled_addons: std::collections::HashSet<String>,
+    timers_rx: Option<mpsc::UnboundedReceiver<u64>>,
+    http: http::Http,
+    http_rx: Option<mpsc::UnboundedReceiver<http::HttpResult>>,
+    storage: storage::Storage,
+    last_slow_tick_warning: Option<std::time::Instant>,
     server_identifier: String,
     upnp_enabled: bool,
     upnp_port: Option<u16>,
     public_address: Option<String>,
     mods: Option<Vec<String>>,
+    record_replays: bool,
+    replay: Option<replay::ReplayRecorder>,
+    persist_world: bool,
+    // Server ID -> hash of the owner's secret, for restored vehicles whose owner hasn't returned yet
+    restored_owners: HashMap<u32, String>,
+    // Client ID -> saved vehicles the client was asked to spawn again
+    returning_vehicles: HashMap<u32, Vec<world_state::SavedVehicle>>,
+    admin_api: Option<admin_api::AdminApiSettings>,
+    metrics_port: Option<u16>,
+    start_time: std::time::Instant,
+    // Names of built-in and Lua commands, shared with the console thread for tab completion
+    command_names: Arc<Mutex<Vec<String>>>,
+    stop_requested: bool,
+    // Last applied config and the file it's reloaded from
+    config: config::Config,
+    config_path: Option<std::path::PathBuf>,
+    config_overrides: config::ConfigOverrides,
+    paths: ServerPaths,
+    console_enabled: bool,
     tick: u64,
 }
 
+/// Where the server keeps its files
+#[derive(Clone, Debug)]
+pub struct ServerPaths {
+    pub mods: std::path::PathBuf,
+    pub addons: std::path::PathBuf,
+    /// access.json, world.json and replays
+    pub data: std::path::PathBuf,
+}
+
+impl Default for ServerPaths {
+    fn default() -> Self {
+        Self {
+            mods: std::path::PathBuf::from("./mods/"),
+            addons: std::path::PathBuf::from("./addons/"),
+            data: std::path::PathBuf::from("./"),
+        }
+    }
+}
+
 impl Server {
-    pub fn from_config(config: config::Config) -> Self {
+    pub fn from_config(config: config::Config) -> anyhow::Result<Self> {
+        Self::with_paths(config, ServerPaths::default())
+    }
+    pub fn with_paths(config: config::Config, paths: ServerPaths) -> anyhow::Result<Self> {
+        let snapshot = config.clone();
         let (lua, receiver) = lua::setup_lua();
+        let hook_profiler =
+            hook_profiler::HookProfiler::new(hook_profiler::HookLimits::from_config(&config));
+        hook_profiler.install(&lua);
+        let (timers, timers_rx) = timers::Timers::new();
+        timers.install(&lua);
+        let vehicle_policy = vehicle_policy::VehiclePolicy::from_config(&config);
+        let vehicle_id_allocator =
+            ids::IdAllocator::new(1, ids::MAX_VEHICLE_ID, ids::ID_REUSE_DELAY);
+        vehicle_id_allocator.install(&lua);
+        let reqwest_client = reqwest::Client::new();
+        let (http, http_rx) =
+            http::Http::new(reqwest_client.clone(), http::HttpLimits::from_config(&config));
+        http.install(&lua);
         let (watcher_tx, watcher_rx) = std::sync::mpsc::channel();
         let lua_watcher =
             notify::Watcher::new(watcher_tx, std::time::Duration::from_secs(2)).unwrap();
-        Self {
+        let access_list_path = paths.data.join(access::ACCESS_LIST_FILE);
+        let access_list = access::AccessList::load(&access_list_path)
+            .with_context(|| format!("Failed to load {}", access_list_path.display()))?;
+        Ok(Self {
             connections: HashMap::with_capacity(8),
-            reqwest_client: reqwest::Client::new(),
+            access_list: Arc::new(Mutex::new(access_list)),
+            reqwest_client,
             vehicles: HashMap::with_capacity(64),
             vehicle_ids: HashMap::with_capacity(64),
+            client_id_allocator: ids::IdAllocator::new(1, ids::MAX_CLIENT_ID, ids::ID_REUSE_DELAY),
+            vehicle_id_allocator,
             chunk_buffers: HashMap::new(),
             name: config.server_name,
             description: config.description,
@@ -110,17 +215,58 @@ impl Server {
             upnp_port: None,
             max_players: config.max_players,
             max_vehicles_per_client: config.max_vehicles_per_client,
+            vehicle_policy,
+            vehicle_spawn_times: HashMap::new(),
+            max_vehicle_speed: config.max_vehicle_speed,
+            suspicious_update_action: config.suspicious_update_action,
+            rate_limits: Arc::new(Mutex::new(config.rate_limits.clone())),
+            max_chunked_message_size: config.max_chunked_message_size,
+            interest: interest::InterestSettings {
+                view_distance: config.view_distance,
+                full_rate_distance: config.full_rate_distance,
+            },
             show_in_list: config.show_in_server_list,
             lua: lua,
             lua_watcher,
             lua_watcher_rx: watcher_rx,
             lua_commands: receiver,
+            hook_profiler,
+            disabled_addons: config.disabled_addons.iter().cloned().collect(),
+            timers_rx: Some(timers_rx),
+            http,
+            http_rx: Some(http_rx),
+            storage: storage::Storage::new(paths.data.join(storage::STORAGE_DIR)),
+            last_slow_tick_warning: None,
             server_identifier: config.server_identifier,
             upnp_enabled: config.upnp_enabled,
             public_address: None,
             mods: config.mods,
+            record_replays: config.record_replays,
+            replay: None,
+            persist_world: config.persist_world,
+            restored_owners: HashMap::new(),
+            returning_vehicles: HashMap::new(),
+            admin_api: match (config.admin_api_port, config.admin_api_token) {
+                (Some(port), Some(token)) if !token.is_empty() => {
+                    Some(admin_api::AdminApiSettings { port, token })
+                }
+                (Some(_), _) => {
+                    warn!("admin_api_port is set, but admin_api_token is empty. Admin API is disabled");
+                    None
+                }
+                _ => None,
+            },
+            metrics_port: config.metrics_port,
+            start_time: std::time::Instant::now(),
+            command_names: Arc::new(Mutex::new(vec![])),
+            stop_requested: false,
+            config: snapshot,
+            paths,
+            console_enabled: false,
+            config_path: None,
+            config_overrides: config::ConfigOverrides::default(),
             tick: 0,
-        }
+        })
     }
     pub async fn run(
         mut self,
@@ -156,48 +302,61 @@ impl Server {
                 warn!("uPnP mapping failed.");
             }
         }
+        let mut tickrate = self.tickrate;
         let mut ticks = IntervalStream::new(tokio::time::interval(
             std::time::Duration::from_secs(1) / self.tickrate as u32,
         ))
         .fuse();
         let mut send_info_ticks =
             IntervalStream::new(tokio::time::interval(std::time::Duration::from_secs(5))).fuse();
+        let mut world_save_ticks =
+            IntervalStream::new(tokio::time::interval(world_state::SAVE_INTERVAL)).fuse();
 
-        let (cert, key) = generate_certificate();
-
-        let mut server_crypto = rustls::ServerConfig::builder()
-            .with_safe_defaults()
-            .with_no_client_auth()
-            .with_single_cert(vec![cert], key)
-            .unwrap();
-        server_crypto.alpn_protocols.push(b"kissmp".to_vec());
-
-        let mut server_config = quinn::ServerConfig::with_crypto(Arc::new(server_crypto));
+        let (endpoint, incoming) = quinn::Endpoint::server(server_config(), addr).unwrap();
+        info!("Server is listening on {}", addr);
+        if self.persist_world {
+            self.restore_world_state();
+        }
+        if self.record_replays {
+            self.start_replay_recording();
+        }
 
-        let mut transport = quinn::TransportConfig::default();
-        transport.max_idle_timeout(Some(
-            IdleTimeout::try_from(std::time::Duration::from_secs(60)).unwrap(),
-        ));
-        transport.keep_alive_interval(Some(std::time::Duration::from_secs(2)));
-        server_config.transport = std::sync::Arc::new(transport);
+        let (admin_requests_tx, admin_requests_rx) = mpsc::channel(16);
+        let mut admin_requests_rx = ReceiverStream::new(admin_requests_rx).fuse();
+        if let Some(settings) = self.admin_api.clone() {
+            let port = settings.port;
+            match admin_api::start(settings, admin_requests_tx) {
+                Ok(()) => info!("Admin API is listening on 127.0.0.1:{}", port),
+                Err(e) => error!("Failed to start admin API: {}", e),
+            }
+        }
 
-        let (_endpoint, incoming) = quinn::Endpoint::server(server_config, addr).unwrap();
-        info!("Server is listening on {}", addr);
+        if let Some(port) = self.metrics_port {
+            match metrics::start(port) {
+                Ok(()) => info!("Metrics are served on 127.0.0.1:{}/metrics", port),
+                Err(e) => error!("Failed to start metrics server: {}", e),
+            }
+        }
 
+        let mut timers_rx = UnboundedReceiverStream::new(self.timers_rx.take().unwrap()).fuse();
+        let mut http_rx = UnboundedReceiverStream::new(self.http_rx.take().unwrap()).fuse();
         let (client_events_tx, client_events_rx) = mpsc::channel(128);
         let mut client_events_rx = ReceiverStream::new(client_events_rx).fuse();
         let mut incoming = incoming
             .inspect(|_conn| info!("Client is trying to connect to the server"))
             .buffer_unordered(16);
 
-        let stdin = tokio::io::stdin();
-        let reader =
-            tokio_util::codec::FramedRead::new(stdin, tokio_util::codec::LinesCodec::new());
+        let (console_tx, console_rx) = mpsc::channel(16);
+        let mut reader = ReceiverStream::new(console_rx).fuse();
         let mut destroyer = destroyer.fuse();
-        let mut reader = reader.fuse();
+        self.update_command_names();
         if enable_lua {
-            self.load_lua_addons();
+            let _ = self.update_lua_vehicles();
             let _ = self.update_lua_connections();
+            self.load_lua_addons();
+        }
+        if self.console_enabled {
+            commands::spawn_console(self.command_names.clone(), console_tx);
         }
         info!("Server is running!");
         if let Some(setup_result) = setup_result {
@@ -215,6 +374,12 @@ impl Server {
                 _ = send_info_ticks.next() => {
                     let _ = self.send_server_info().await;
                     self.send_players_info().await;
+                    self.evict_stale_chunks();
+                }
+                _ = world_save_ticks.next() => {
+                    if self.persist_world {
+                        self.save_world_state();
+                    }
                 }
                 conn = incoming.select_next_some() => {
                     if let Ok(conn) = conn {
@@ -228,17 +393,41 @@ impl Server {
                 },
                 stdin_input = reader.next() => {
                     if let Some(stdin_input) = stdin_input {
-                        self.on_console_input(stdin_input.unwrap_or(String::from(""))).await;
+                        self.on_console_input(stdin_input).await;
                     }
                 },
                 e = client_events_rx.select_next_some() => {
                     self.on_client_event(e.0, e.1).await;
                 },
+                timer_id = timers_rx.select_next_some() => {
+                    self.run_timer(timer_id);
+                },
+                response = http_rx.select_next_some() => {
+                    self.run_http_callback(response);
+                },
+                request = admin_requests_rx.select_next_some() => {
+                    let (request, response) = request;
+                    let _ = response.send(self.on_admin_request(request).await);
+                },
                 _ = destroyer => {
-                    info!("Server shutdown requested. Shutting down");
+                    info!("Server shutdown requested");
                     break 'main;
                 },
             }
+            if self.stop_requested {
+                break 'main;
+            }
+            if tickrate != self.tickrate {
+                tickrate = self.tickrate;
+                ticks = IntervalStream::new(tokio::time::interval(
+                    std::time::Duration::from_secs(1) / tickrate as u32,
+                ))
+                .fuse();
+            }
+        }
+        self.shutdown(&endpoint).await;
+        if self.console_enabled {
+            commands::restore_terminal();
         }
     }
     async fn send_players_info(&mut self) {
@@ -255,6 +444,22 @@ impl Server {
             }
         }
     }
+    /// Reads commands from stdin. Not enabled by default, because stdin belongs to the bridge when it hosts the server
+    pub fn enable_console(&mut self) {
+        self.console_enabled = true;
+    }
+    fn server_info(&self, client_id: u32) -> shared::ServerInfo {
+        shared::ServerInfo {
+            name: self.name.clone(),
+            player_count: self.connections.len() as u8,
+            client_id,
+            map: self.map.clone(),
+            tickrate: self.tickrate,
+            max_vehicles_per_client: self.vehicle_limit(client_id),
+            mods: list_mods(self.mods.clone(), &self.paths.mods).unwrap().0,
+            server_identifier: self.server_identifier.clone(),
+        }
+    }
     async fn send_server_info(&self) -> anyhow::Result<()> {
         if (!self.show_in_list) {
             return Ok(());
@@ -317,8 +522,13 @@ impl Server {
             connection.close(0u32.into(), b"Server is full");
             return Err(anyhow::Error::msg("Server is full"));
         }
-        // Should be strong enough for our targets. TODO: Check for collisions anyway
-        let id = rand::random::<u32>();
+        let id = match self.client_id_allocator.allocate() {
+            Some(id) => id,
+            None => {
+                connection.close(0u32.into(), b"Server is full");
+                return Err(anyhow::Error::msg("No client IDs left"));
+            }
+        };
 
         info!("Client connected with ID: {}", id);
 
@@ -353,6 +563,10 @@ impl Server {
         }
 
         let connection_clone = connection.clone();
+        let access_list = self.access_list.clone();
+        // Clients that fail the handshake never reach the server loop, so their ID is released here
+        let client_ids = self.client_id_allocator.clone();
+        let rate_limiter = rate_limit::RateLimiter::new(self.rate_limits.clone());
         // Receiver
         tokio::spawn(async move {
             info!("[CONNECT_TASK] Starting connection task for {}", id);
@@ -364,6 +578,7 @@ impl Server {
                         0u32.into(),
                         b"Failed to fetch client info. Client version mismatch?",
                     );
+                    client_ids.release(id);
                     return;
                 }
             };
@@ -377,6 +592,15 @@ impl Server {
                     )
                     .as_bytes(),
                 );
+                client_ids.release(id);
+                return;
+            }
+            let remote_ip = connection_clone.remote_address().ip();
+            let rejection = access_list.lock().unwrap().check(&client_info, remote_ip);
+            if let Some(reason) = rejection {
+                info!("Client {} ({}) was refused: {}", client_info.name, remote_ip, reason);
+                connection_clone.close(0u32.into(), reason.as_bytes());
+                client_ids.release(id);
                 return;
             }
             let client_info_public = ClientInfoPublic {
@@ -392,20 +616,28 @@ impl Server {
                 unreliable: unreliable_tx,
                 client_info_private: client_info,
                 client_info_public: client_info_public,
+                compact_updates: false,
+                sent_states: StateHistory::default(),
+                acked_generations: HashMap::new(),
             };
             client_events_tx
                 .send((id, IncomingEvent::ClientConnected(client_connection)))
                 .await
                 .unwrap();
             info!("[CONNECT_TASK] Starting drive_receive for {}", id);
-            if let Err(_e) = Self::drive_receive(
+            if let Err(e) = Self::drive_receive(
                 id,
                 new_connection.uni_streams,
                 new_connection.datagrams,
                 client_events_tx.clone(),
+                rate_limiter,
             )
             .await
             {
+                if let Some(rate_limit::Kicked(reason)) = e.downcast_ref() {
+                    warn!("Client {} was kicked: {}", id, reason);
+                    connection_clone.close(1u32.into(), reason.as_bytes());
+                }
                 let _ = client_events_tx
                     .send((id, IncomingEvent::ConnectionLost))
                     .await;
@@ -413,17 +645,7 @@ impl Server {
         });
 
         let server_info =
-            bincode::serialize(&shared::ServerCommand::ServerInfo(shared::ServerInfo {
-                name: self.name.clone(),
-                player_count: self.connections.len() as u8,
-                client_id: id,
-                map: self.map.clone(),
-                tickrate: self.tickrate,
-                max_vehicles_per_client: self.max_vehicles_per_client,
-                mods: list_mods(self.mods.clone()).unwrap().0,
-                server_identifier: self.server_identifier.clone(),
-            }))
-            .unwrap();
+            bincode::serialize(&shared::ServerCommand::ServerInfo(self.server_info(id))).unwrap();
         // Sender
         tokio::spawn(async move {
             let mut stream = match connection.open_uni().await {
@@ -468,6 +690,7 @@ impl Server {
                                 let _ = file_transfer::transfer_file(connection.clone(), std::path::Path::new(&file)).await;
                             }
                             _ => {
+                                metrics::METRICS.record_message(metrics::Direction::Sent, metrics::Transport::Stream, command.name());
                                 let mut stream = connection.open_uni().await;
                                 if let Ok(stream) = &mut stream {
                                     let _ = send(stream, &Self::handle_outgoing_data(command)).await;
@@ -477,6 +700,7 @@ impl Server {
                     });
                 }
                 command = unreliable.select_next_some() => {
+                    metrics::METRICS.record_message(metrics::Direction::Sent, metrics::Transport::Datagram, command.name());
                     let data = Self::handle_outgoing_data(command);
                     connection.send_datagram(data.into())?;
                 }
@@ -493,6 +717,7 @@ impl Server {
         streams: quinn::IncomingUniStreams,
         datagrams: quinn::Datagrams,
         mut client_events_tx: mpsc::Sender<(u32, IncomingEvent)>,
+        mut rate_limiter: rate_limit::RateLimiter,
     ) -> anyhow::Result<()> {
         info!("[DEBUG] Starting drive_receive for {}", id);
         let mut cmds = streams
@@ -505,7 +730,7 @@ impl Server {
                 stream.read_exact(&mut buf).await?;
                 Ok::<_, Error>(buf)
             })
-            .buffered(256)
+            .buffered(rate_limit::MAX_CONCURRENT_STREAMS as usize)
             .fuse();
 
         let mut datagrams = datagrams
@@ -517,10 +742,10 @@ impl Server {
             .fuse();
 
         loop {
-            let data = select! {
+            let (data, transport) = select! {
                 data = cmds.try_next() => {
                     if let Some(data) = data? {
-                        data
+                        (data, metrics::Transport::Stream)
                     }
                     else{
                        return Err(anyhow::Error::msg("Disconnected"))
@@ -528,7 +753,7 @@ impl Server {
                 }
                 data = datagrams.try_next() => {
                     if let Some(data) = data? {
-                        data
+                        (data, metrics::Transport::Datagram)
                     }
                     else{
                         return Err(anyhow::Error::msg("Disconnected"))
@@ -536,18 +761,69 @@ impl Server {
                 }
                 complete => break
             };
-            let _ = Self::handle_incoming_data(id, data, &mut client_events_tx).await;
+            let result = Self::handle_incoming_data(
+                id,
+                data,
+                transport,
+                &mut client_events_tx,
+                &mut rate_limiter,
+            )
+            .await;
+            if let Err(e) = result {
+                if e.is::<rate_limit::Kicked>() {
+                    return Err(e);
+                }
+            }
         }
         Err(anyhow::Error::msg("Disconnected"))
     }
 
     async fn tick(&mut self) {
+        let tick_start = std::time::Instant::now();
         self.tick += 1;
-        for (_, client) in &mut self.connections {
-            for (vehicle_id, vehicle) in &self.vehicles {
+        if let Some(replay) = &mut self.replay {
+            replay.record_vehicles(self.tick, &self.vehicles);
+        }
+        let grid = self
+            .interest
+            .view_distance
+            .map(|distance| interest::SpatialGrid::from_vehicles(&self.vehicles, distance));
+        for client in self.connections.values_mut() {
+            let vehicle_ids = interest::select_vehicles(
+                &self.interest,
+                &self.vehicles,
+                grid.as_ref(),
+                client.client_info_public.current_vehicle,
+                self.tick,
+            );
+            for vehicle_id in vehicle_ids {
+                let vehicle = match self.vehicles.get(&vehicle_id) {
+                    Some(vehicle) => vehicle,
+                    None => continue,
+                };
                 if let (Some(transform), Some(electrics), Some(gearbox)) =
                     (&vehicle.transform, &vehicle.electrics, &vehicle.gearbox)
                 {
+                    if client.compact_updates {
+                        let state = VehicleState::from_parts(transform, electrics, gearbox);
+                        let baseline = client
+                            .acked_generations
+                            .get(&vehicle_id)
+                            .and_then(|generation| {
+                                client
+                                    .sent_states
+                                    .get(vehicle_id, *generation)
+                                    .map(|state| (*generation, state))
+                            });
+                        let update =
+                            CompactVehicleUpdate::encode(vehicle_id, self.tick, &state, baseline);
+                        client.sent_states.push(vehicle_id, self.tick, state);
+                        let _ = client
+                            .unreliable
+                            .send(ServerCommand::VehicleUpdateCompact(update.to_bytes()))
+                            .await;
+                        continue;
+                    }
                     let _ = client
                         .unreliable
                         .send(ServerCommand::VehicleUpdate(VehicleUpdate {
@@ -562,20 +838,68 @@ impl Server {
                 }
             }
         }
+        let lua_start = std::time::Instant::now();
         self.lua_tick().await.unwrap();
+        let lua_duration = lua_start.elapsed();
+        let tick_duration = tick_start.elapsed();
+        let tick_interval = std::time::Duration::from_secs(1) / self.tickrate as u32;
+        metrics::METRICS.set_counts(self.connections.len(), self.vehicles.len());
+        metrics::METRICS.record_tick(tick_duration, tick_interval);
+        if tick_duration > tick_interval
+            && self
+                .last_slow_tick_warning
+                .is_none_or(|x| x.elapsed() > hook_profiler::SLOW_TICK_WARNING_INTERVAL)
+        {
+            self.last_slow_tick_warning = Some(std::time::Instant::now());
+            warn!(
+                "Tick took {:.2}ms, longer than the tick interval of {:.2}ms ({:.2}ms in Lua). Run `hooks` to see the slowest hooks",
+                tick_duration.as_secs_f64() * 1000.0,
+                tick_interval.as_secs_f64() * 1000.0,
+                lua_duration.as_secs_f64() * 1000.0
+            );
+        }
     }
 
-    async fn on_console_input(&self, input: String) {
+    async fn on_console_input(&mut self, input: String) {
+        let name = commands::split_arguments(&input).into_iter().next();
+        if let Some(name) = name {
+            if self.is_command(&name) {
+                self.run_command(commands::CommandSource::Console, &input).await;
+                return;
+            }
+        }
         self.lua.context(|lua_ctx| {
             let _ = lua::run_hook::<String, ()>(lua_ctx, String::from("OnStdIn"), input);
         });
     }
 
-    fn cleanup(&mut self) {
+    async fn shutdown(&mut self, endpoint: &quinn::Endpoint) {
         info!("Server is shutting down");
-        let gateway = igd::search_gateway(Default::default());
-        if let Ok(gateway) = gateway {
-            if let Some(port) = self.upnp_port {
+        self.lua.context(|lua_ctx| {
+            let _ = lua::run_hook::<(), ()>(lua_ctx, String::from("OnShutdown"), ());
+        });
+        if self.persist_world {
+            self.save_world_state();
+        }
+        if let Some(replay) = &mut self.replay {
+            replay.flush();
+        }
+        self.storage.flush();
+        for connection in self.connections.values() {
+            connection
+                .conn
+                .close(SHUTDOWN_CLOSE_CODE.into(), b"Server is shutting down");
+        }
+        // Give the close frames a moment to reach the clients
+        let _ = tokio::time::timeout(std::time::Duration::from_secs(2), endpoint.wait_idle()).await;
+        self.connections.clear();
+        self.cleanup();
+    }
+
+    fn cleanup(&mut self) {
+        if let Some(port) = self.upnp_port.take() {
+            let gateway = igd::search_gateway(Default::default());
+            if let Ok(gateway) = gateway {
                 let _ = gateway.remove_port(igd::PortMappingProtocol::UDP, port);
             }
         }
@@ -596,7 +920,29 @@ fn generate_certificate() -> (rustls::Certificate, rustls::PrivateKey) {
     (rustls::Certificate(cert), rustls::PrivateKey(key))
 }
 
-async fn send(stream: &mut quinn::SendStream, message: &[u8]) -> anyhow::Result<()> {
+pub fn server_config() -> quinn::ServerConfig {
+    let (cert, key) = generate_certificate();
+
+    let mut server_crypto = rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(vec![cert], key)
+        .unwrap();
+    server_crypto.alpn_protocols.push(b"kissmp".to_vec());
+
+    let mut server_config = quinn::ServerConfig::with_crypto(Arc::new(server_crypto));
+
+    let mut transport = quinn::TransportConfig::default();
+    transport.max_idle_timeout(Some(
+        IdleTimeout::try_from(std::time::Duration::from_secs(60)).unwrap(),
+    ));
+    transport.keep_alive_interval(Some(std::time::Duration::from_secs(2)));
+    transport.max_concurrent_uni_streams(rate_limit::MAX_CONCURRENT_STREAMS.into());
+    server_config.transport = std::sync::Arc::new(transport);
+    server_config
+}
+
+pub async fn send(stream: &mut quinn::SendStream, message: &[u8]) -> anyhow::Result<()> {
     stream.write_all(&(message.len() as u32).to_le_bytes()).await?;
     stream.write_all(message).await?;
     stream.finish().await?;
@@ -605,6 +951,7 @@ async fn send(stream: &mut quinn::SendStream, message: &[u8]) -> anyhow::Result<
 
 pub fn list_mods(
     mods: Option<Vec<String>>,
+    mods_path: &std::path::Path,
 ) -> anyhow::Result<(Vec<(String, u32)>, Vec<std::path::PathBuf>)> {
     let mut paths = vec![];
 
@@ -613,13 +960,12 @@ pub fn list_mods(
             paths.push(std::path::PathBuf::from(&path));
         }
     } else {
-        let mods_path = std::path::Path::new("./mods/");
         if !mods_path.exists() {
-            std::fs::create_dir(mods_path).unwrap();
+            std::fs::create_dir_all(mods_path).unwrap();
         }
 
         for entry in std::fs::read_dir(mods_path)? {
-            let path = entry.unwrap().path();
+            let path = entry?.path();
             if let Some(extension) = path.extension() {
                 if extension.to_string_lossy().to_lowercase() == "zip" {
                     paths.push(path);
@@ -15,11 +15,28 @@ pub enum LuaCommand {
     SendLua(u32, String),
     SendVehicleLua(u32, String),
     Kick(u32, String),
-    SpawnVehicle(VehicleData, Option<u32>),
+    SpawnVehicle(u32, VehicleData),
+    MoveVehicle(VehicleReset),
+    RecolorVehicle(u32, [[f32; 8]; 3]),
+    Ban(BanTarget, String, Option<u64>),
+    Unban(String),
+}
+
+#[derive(Clone)]
+pub enum BanTarget {
+    Client(u32),
+    Identifier(String),
 }
 
 struct LuaTransform(Transform);
-struct LuaVehicleData(VehicleData);
+#[derive(Clone)]
+pub(crate) struct LuaVehicleData(pub VehicleData);
+
+pub(crate) fn to_array<const N: usize>(values: Vec<f32>, name: &str) -> rlua::Result<[f32; N]> {
+    std::convert::TryFrom::try_from(values).map_err(|_| {
+        rlua::Error::RuntimeError(format!("{} has to be a table of {} numbers", name, N))
+    })
+}
 
 impl rlua::UserData for LuaTransform {
     fn add_methods<'lua, M: rlua::UserDataMethods<'lua, Self>>(methods: &mut M) {
@@ -111,6 +128,21 @@ impl rlua::UserData for Vehicle {
             |lua_ctx, this, (x, y, z): (f32, f32, f32)| {
                 let globals = lua_ctx.globals();
                 let sender: MpscChannelSender = globals.get("MPSC_CHANNEL_SENDER")?;
+                if this.data.owner.is_none() {
+                    let rotation = match &this.transform {
+                        Some(transform) => transform.rotation,
+                        None => this.data.rotation,
+                    };
+                    sender
+                        .0
+                        .send(LuaCommand::MoveVehicle(VehicleReset {
+                            vehicle_id: this.data.server_id,
+                            position: [x, y, z],
+                            rotation,
+                        }))
+                        .unwrap();
+                    return Ok(());
+                }
                 sender
                     .0
                     .send(LuaCommand::SendLua(
@@ -129,6 +161,18 @@ impl rlua::UserData for Vehicle {
             |lua_ctx, this, (x, y, z, xr, yr, zr, w): (f32, f32, f32, f32, f32, f32, f32)| {
                 let globals = lua_ctx.globals();
                 let sender: MpscChannelSender = globals.get("MPSC_CHANNEL_SENDER")?;
+                // Nobody simulates server owned vehicles, so they're moved by the server itself
+                if this.data.owner.is_none() {
+                    sender
+                        .0
+                        .send(LuaCommand::MoveVehicle(VehicleReset {
+                            vehicle_id: this.data.server_id,
+                            position: [x, y, z],
+                            rotation: [xr, yr, zr, w],
+                        }))
+                        .unwrap();
+                    return Ok(());
+                }
                 sender
                     .0
                     .send(LuaCommand::SendLua(
@@ -142,6 +186,23 @@ impl rlua::UserData for Vehicle {
                 Ok(())
             },
         );
+        methods.add_method(
+            "setColors",
+            |lua_ctx, this, (color, p0, p1): (Vec<f32>, Vec<f32>, Vec<f32>)| {
+                let globals = lua_ctx.globals();
+                let sender: MpscChannelSender = globals.get("MPSC_CHANNEL_SENDER")?;
+                let colors_table = [
+                    to_array(color, "color")?,
+                    to_array(p0, "palete_0")?,
+                    to_array(p1, "palete_1")?,
+                ];
+                sender
+                    .0
+                    .send(LuaCommand::RecolorVehicle(this.data.server_id, colors_table))
+                    .unwrap();
+                Ok(())
+            },
+        );
         methods.add_method("sendLua", |lua_ctx, this, lua: String| {
             let globals = lua_ctx.globals();
             let sender: MpscChannelSender = globals.get("MPSC_CHANNEL_SENDER")?;
@@ -160,7 +221,8 @@ struct LuaConnection {
     current_vehicle: Option<u32>,
     ip: String,
     secret: String,
-    steamid64: Option<String>
+    steamid64: Option<String>,
+    admin: bool,
 }
 
 impl rlua::UserData for LuaConnection {
@@ -173,6 +235,7 @@ impl rlua::UserData for LuaConnection {
             Ok(this.current_vehicle)
         });
         methods.add_method("getName", |_, this, _: ()| Ok(this.name.clone()));
+        methods.add_method("isAdmin", |_, this, _: ()| Ok(this.admin));
         methods.add_method("sendChatMessage", |lua_ctx, this, message: String| {
             let globals = lua_ctx.globals();
             let sender: MpscChannelSender = globals.get("MPSC_CHANNEL_SENDER")?;
@@ -243,6 +306,7 @@ impl Server {
                     ip: connection.conn.remote_address().ip().to_string(),
                     secret: connection.client_info_private.secret.clone(),
                     steamid64: connection.client_info_private.steamid64.clone(),
+                    admin: self.is_admin(*id),
                 },
             );
         }
@@ -299,58 +363,108 @@ impl Server {
                         conn.conn.close(1u32.into(), &reason.into_bytes())
                     }
                 }
-                SpawnVehicle(data, owner) => {
-                    let _ = self.spawn_vehicle(owner, data);
+                SpawnVehicle(server_id, data) => {
+                    if self.vehicles.contains_key(&server_id) {
+                        error!("Vehicle {} from Lua wasn't spawned, its ID is taken", server_id);
+                        continue;
+                    }
+                    self.spawn_vehicle_with_id(server_id, None, data).await;
+                }
+                MoveVehicle(data) => {
+                    if self.vehicles.contains_key(&data.vehicle_id) {
+                        self.move_vehicle(data, None).await;
+                    }
+                }
+                RecolorVehicle(id, colors_table) => {
+                    self.recolor_vehicle(id, colors_table).await;
+                }
+                Ban(target, reason, duration) => {
+                    match target {
+                        BanTarget::Client(id) => self.ban_client(id, reason, duration),
+                        BanTarget::Identifier(identifier) => self.ban(
+                            crate::access::Identity::parse(&identifier),
+                            reason,
+                            duration,
+                        ),
+                    }
+                    let _ = self.update_lua_connections();
+                }
+                Unban(identifier) => {
+                    self.unban(&identifier);
                 }
             }
         }
         self.lua.context(|lua_ctx| {
             let _ = run_hook::<(), ()>(lua_ctx, String::from("Tick"), ());
         });
-        for event in self.lua_watcher_rx.try_recv() {
+        // Saving a file can produce several events, every changed addon is reloaded once
+        let mut changed_addons = vec![];
+        for event in self.lua_watcher_rx.try_iter().collect::<Vec<_>>() {
             use notify::DebouncedEvent::*;
             match event {
-                Write(path) => {
-                    info!("Lua file {} has been changed. Reloading...", path.display());
-                    self.load_lua_addon(&path);
+                Write(path) | Create(path) | Rename(_, path) if self.is_config_path(&path) => {
+                    info!("Config has been changed. Reloading...");
+                    self.reload_config().await;
+                }
+                Write(path) | Create(path) | Remove(path) | Rename(_, path)
+                    if path.extension().is_some_and(|x| x == "lua") =>
+                {
+                    if let Some(addon) = self.addon_for_path(&path) {
+                        if !changed_addons.contains(&addon) && !self.disabled_addons.contains(&addon) {
+                            info!("Lua file {} has been changed. Reloading...", path.display());
+                            changed_addons.push(addon);
+                        }
+                    }
                 }
                 _ => {}
             }
         }
+        for addon in changed_addons {
+            self.load_addon(&addon);
+        }
         Ok(())
     }
+}
 
-    pub fn load_lua_addon(&mut self, path: &std::path::Path) {
-        use notify::Watcher;
-        use std::io::Read;
-        let mut file = std::fs::File::open(path).unwrap();
-        let mut buf = String::new();
-        file.read_to_string(&mut buf).unwrap();
-        self.lua.context(|lua_ctx| {
-            if let Err(x) = lua_ctx.load(&buf).eval::<()>() {
-                error!("Lua error: {:?}", x);
-            }
-        });
-        self.lua_watcher
-            .watch(path, notify::RecursiveMode::NonRecursive)
+pub fn register_hook<'lua>(
+    lua_ctx: rlua::Context<'lua>,
+    hook: String,
+    name: String,
+    function: rlua::Function<'lua>,
+) -> rlua::Result<()> {
+    let globals = lua_ctx.globals();
+    let hooks_table: rlua::Table = globals.get("hooks").unwrap();
+    if !hooks_table.contains_key(hook.clone()).unwrap() {
+        hooks_table
+            .set(hook.clone(), Vec::new() as Vec<rlua::Function>)
             .unwrap();
     }
+    let hooks: rlua::Table = hooks_table.get(hook.clone()).unwrap();
+    hooks.set(name, function).unwrap();
+    Ok(())
+}
 
-    pub fn load_lua_addons(&mut self) {
-        let path = std::path::Path::new("./addons/");
-        if !path.exists() {
-            std::fs::create_dir(path).unwrap();
-        }
-        let paths = std::fs::read_dir(path).unwrap();
-        for path in paths {
-            let path = path.unwrap().path();
-            if !path.is_dir() {
-                continue;
-            }
-            let path = path.join("main.lua");
-            self.load_lua_addon(&path);
-        }
+/// `addon` is set for commands registered by addons, so they can be removed when the addon is unloaded
+pub fn register_command<'lua>(
+    lua_ctx: rlua::Context<'lua>,
+    name: String,
+    help: String,
+    callback: rlua::Function<'lua>,
+    addon: Option<String>,
+) -> rlua::Result<()> {
+    if name.is_empty() || name.contains(char::is_whitespace) {
+        return Err(rlua::Error::RuntimeError(String::from(
+            "Command name can't be empty or contain spaces",
+        )));
     }
+    let commands_table: rlua::Table = lua_ctx.globals().get("commands")?;
+    let list: rlua::Table = commands_table.get("list")?;
+    let command = lua_ctx.create_table()?;
+    command.set("help", help)?;
+    command.set("callback", callback)?;
+    command.set("addon", addon)?;
+    list.set(name, command)?;
+    Ok(())
 }
 
 #[derive(Clone)]
@@ -370,16 +484,7 @@ pub fn setup_lua() -> (rlua::Lua, mpsc::Receiver<LuaCommand>) {
                 lua_ctx
                     .create_function(
                         |lua_ctx, (hook, name, function): (String, String, rlua::Function)| {
-                            let globals = lua_ctx.globals();
-                            let hooks_table: rlua::Table = globals.get("hooks").unwrap();
-                            if !hooks_table.contains_key(hook.clone()).unwrap() {
-                                hooks_table
-                                    .set(hook.clone(), Vec::new() as Vec<rlua::Function>)
-                                    .unwrap();
-                            }
-                            let hooks: rlua::Table = hooks_table.get(hook.clone()).unwrap();
-                            hooks.set(name, function).unwrap();
-                            Ok(())
+                            register_hook(lua_ctx, hook, name, function)
                         },
                     )
                     .unwrap(),
@@ -387,6 +492,24 @@ pub fn setup_lua() -> (rlua::Lua, mpsc::Receiver<LuaCommand>) {
             .unwrap();
         globals.set("hooks", hooks_table).unwrap();
 
+        let commands_table = lua_ctx.create_table().unwrap();
+        commands_table
+            .set("list", lua_ctx.create_table().unwrap())
+            .unwrap();
+        commands_table
+            .set(
+                "register",
+                lua_ctx
+                    .create_function(
+                        |lua_ctx, (name, help, callback): (String, String, rlua::Function)| {
+                            register_command(lua_ctx, name, help, callback, None)
+                        },
+                    )
+                    .unwrap(),
+            )
+            .unwrap();
+        globals.set("commands", commands_table).unwrap();
+
         let tx_clone = tx.clone();
         globals
             .set("MPSC_CHANNEL_SENDER", MpscChannelSender(tx_clone))
@@ -404,20 +527,118 @@ pub fn setup_lua() -> (rlua::Lua, mpsc::Receiver<LuaCommand>) {
         globals
             .set("send_message_broadcast", send_message_broadcast)
             .unwrap();
-        // FIXME: Bring it back
-        /*let tx_clone = tx.clone();
-        let spawn_vehicle = lua_ctx
+
+        let tx_clone = tx.clone();
+        let ban = lua_ctx
             .create_function(
-                move |_, (vehicle_data, owner): (LuaVehicleData, Option<u32>)| {
+                move |_, (target, reason, duration): (rlua::Value, Option<String>, Option<u64>)| {
+                    let target = match target {
+                        rlua::Value::Integer(id) => BanTarget::Client(id as u32),
+                        rlua::Value::Number(id) => BanTarget::Client(id as u32),
+                        rlua::Value::String(identifier) => {
+                            BanTarget::Identifier(identifier.to_str()?.to_string())
+                        }
+                        _ => {
+                            return Err(rlua::Error::RuntimeError(String::from(
+                                "ban expects a client id or an identifier string",
+                            )))
+                        }
+                    };
+                    let reason = reason.unwrap_or(String::from("No reason given"));
                     tx_clone
-                        .send(LuaCommand::SpawnVehicle(vehicle_data.0, owner))
+                        .send(LuaCommand::Ban(target, reason, duration))
                         .unwrap();
                     Ok(())
                 },
             )
             .unwrap();
+        globals.set("ban", ban).unwrap();
+
+        let tx_clone = tx.clone();
+        let unban = lua_ctx
+            .create_function(move |_, identifier: String| {
+                tx_clone.send(LuaCommand::Unban(identifier)).unwrap();
+                Ok(())
+            })
+            .unwrap();
+        globals.set("unban", unban).unwrap();
+
+        let is_admin = lua_ctx
+            .create_function(|lua_ctx, client_id: u32| {
+                let connections: rlua::Table = lua_ctx.globals().get("connections")?;
+                let connection: Option<rlua::AnyUserData> = connections.get(client_id)?;
+                if let Some(connection) = connection {
+                    Ok(connection.borrow::<LuaConnection>()?.admin)
+                } else {
+                    Ok(false)
+                }
+            })
+            .unwrap();
+        globals.set("is_admin", is_admin).unwrap();
+        // Server owned vehicles aren't simulated by anyone, addons move them around themselves.
+        // The ID is allocated here, so it can be returned right away
+        let tx_clone = tx.clone();
+        let spawn_vehicle = lua_ctx
+            .create_function(move |lua_ctx, vehicle_data: LuaVehicleData| {
+                let server_id = crate::ids::IdAllocator::from_lua(lua_ctx)?
+                    .allocate()
+                    .ok_or_else(|| {
+                        rlua::Error::RuntimeError(String::from("There are no free vehicle IDs"))
+                    })?;
+                let mut data = vehicle_data.0;
+                data.server_id = server_id;
+                data.owner = None;
+                tx_clone
+                    .send(LuaCommand::SpawnVehicle(server_id, data))
+                    .unwrap();
+                Ok(server_id)
+            })
+            .unwrap();
         globals.set("spawn_vehicle", spawn_vehicle).unwrap();
-        */
+
+        let tx_clone = tx.clone();
+        let move_vehicle = lua_ctx
+            .create_function(
+                move |_, (vehicle_id, position, rotation): (u32, Vec<f32>, Vec<f32>)| {
+                    tx_clone
+                        .send(LuaCommand::MoveVehicle(VehicleReset {
+                            vehicle_id,
+                            position: to_array(position, "position")?,
+                            rotation: to_array(rotation, "rotation")?,
+                        }))
+                        .unwrap();
+                    Ok(())
+                },
+            )
+            .unwrap();
+        globals.set("move_vehicle", move_vehicle).unwrap();
+
+        let tx_clone = tx.clone();
+        let recolor_vehicle = lua_ctx
+            .create_function(
+                move |_, (vehicle_id, color, p0, p1): (u32, Vec<f32>, Vec<f32>, Vec<f32>)| {
+                    let colors_table = [
+                        to_array(color, "color")?,
+                        to_array(p0, "palete_0")?,
+                        to_array(p1, "palete_1")?,
+                    ];
+                    tx_clone
+                        .send(LuaCommand::RecolorVehicle(vehicle_id, colors_table))
+                        .unwrap();
+                    Ok(())
+                },
+            )
+            .unwrap();
+        globals.set("recolor_vehicle", recolor_vehicle).unwrap();
+
+        let tx_clone = tx.clone();
+        let despawn_vehicle = lua_ctx
+            .create_function(move |_, vehicle_id: u32| {
+                tx_clone.send(LuaCommand::RemoveVehicle(vehicle_id)).unwrap();
+                Ok(())
+            })
+            .unwrap();
+        globals.set("despawn_vehicle", despawn_vehicle).unwrap();
         let build_vehicle = lua_ctx
             .create_function(
                 move |_,
@@ -434,15 +655,15 @@ pub fn setup_lua() -> (rlua::Lua, mpsc::Receiver<LuaCommand>) {
                     Ok(LuaVehicleData(VehicleData {
                         parts_config,
                         in_game_id: 0,
-                        color: [color[0], color[1], color[2], color[3], color[4], color[5], color[6], color[7]],
-                        palete_0: [p0[0], p0[1], p0[2], p0[3], p0[4], p0[5], p0[6], p0[7]],
-                        palete_1: [p1[0], p1[1], p1[2], p1[3], p1[4], p1[5], p1[6], p1[7]],
+                        color: to_array(color, "color")?,
+                        palete_0: to_array(p0, "palete_0")?,
+                        palete_1: to_array(p1, "palete_1")?,
                         plate: Some(plate),
                         name,
                         server_id: 0,
                         owner: None,
-                        position: [position[0], position[1], position[2]],
-                        rotation: [rotation[0], rotation[1], rotation[2], rotation[3]],
+                        position: to_array(position, "position")?,
+                        rotation: to_array(rotation, "rotation")?,
                     }))
                 },
             )
@@ -463,13 +684,13 @@ pub fn setup_lua() -> (rlua::Lua, mpsc::Receiver<LuaCommand>) {
         globals.set("decode_json", decode_json).unwrap();
 
         let encode_json = lua_ctx
-            .create_function(move |_lua_ctx, table: rlua::Value| Ok(lua_to_json(table).to_string()))
+            .create_function(move |_lua_ctx, table: rlua::Value| Ok(lua_to_json(table)?.to_string()))
             .unwrap();
         globals.set("encode_json", encode_json).unwrap();
 
         let encode_json_pretty = lua_ctx
             .create_function(move |_lua_ctx, table: rlua::Value| {
-                Ok(serde_json::to_string_pretty(&lua_to_json(table)).unwrap())
+                Ok(serde_json::to_string_pretty(&lua_to_json(table)?).unwrap())
             })
             .unwrap();
         globals
@@ -509,19 +730,44 @@ pub fn json_to_lua<'lua>(
     }
 }
 
-pub fn lua_to_json<'lua>(value: rlua::Value) -> serde_json::Value {
+// Tables nested deeper than this are most likely tables that contain themselves
+const MAX_JSON_DEPTH: usize = 64;
+
+/// Fails on values JSON can't represent, instead of taking the server down with a script error
+pub fn lua_to_json(value: rlua::Value) -> rlua::Result<serde_json::Value> {
+    lua_to_json_inner(value, 0)
+}
+
+fn json_error(message: &str) -> rlua::Error {
+    rlua::Error::RuntimeError(format!("Can't convert to JSON: {}", message))
+}
+
+fn json_number(x: f64) -> rlua::Result<serde_json::Value> {
+    serde_json::Number::from_f64(x)
+        .map(serde_json::Value::Number)
+        .ok_or_else(|| json_error("NaN and infinite numbers aren't allowed"))
+}
+
+fn lua_to_json_inner(value: rlua::Value, depth: usize) -> rlua::Result<serde_json::Value> {
     use rlua::Value::*;
-    match value {
+    if depth > MAX_JSON_DEPTH {
+        return Err(json_error("tables are nested too deep"));
+    }
+    Ok(match value {
         Nil => serde_json::Value::Null,
         Boolean(x) => serde_json::Value::Bool(x),
-        Integer(x) => serde_json::Value::Number(serde_json::Number::from_f64(x as f64).unwrap()),
-        Number(x) => serde_json::Value::Number(serde_json::Number::from_f64(x).unwrap()),
-        String(x) => serde_json::Value::String(x.to_str().unwrap().to_string()),
+        Integer(x) => json_number(x as f64)?,
+        Number(x) => json_number(x)?,
+        String(x) => serde_json::Value::String(
+            x.to_str()
+                .map_err(|_| json_error("strings have to be valid UTF-8"))?
+                .to_string(),
+        ),
         Table(x) => {
             let mut is_object = false;
             let mut prev = 0;
             for x in x.clone().pairs() {
-                let (k, _): (rlua::Value, rlua::Value) = x.unwrap();
+                let (k, _): (rlua::Value, rlua::Value) = x?;
                 match k {
                     Number(x) => {
                         if x as i64 > (prev + 1) {
@@ -543,21 +789,30 @@ pub fn lua_to_json<'lua>(value: rlua::Value) -> serde_json::Value {
             if is_object {
                 let mut map = serde_json::map::Map::new();
                 for x in x.pairs() {
-                    let (k, v) = x.unwrap();
-                    map.insert(k, lua_to_json(v));
+                    let (k, v): (rlua::Value, rlua::Value) = x?;
+                    let k = match k {
+                        String(k) => k
+                            .to_str()
+                            .map_err(|_| json_error("keys have to be valid UTF-8"))?
+                            .to_string(),
+                        Integer(k) => k.to_string(),
+                        Number(k) => k.to_string(),
+                        _ => return Err(json_error("keys have to be strings or numbers")),
+                    };
+                    map.insert(k, lua_to_json_inner(v, depth + 1)?);
                 }
                 serde_json::Value::Object(map)
             } else {
                 let mut array = vec![];
                 for x in x.pairs() {
-                    let (_k, v): (rlua::Value, rlua::Value) = x.unwrap();
-                    array.push(lua_to_json(v));
+                    let (_k, v): (rlua::Value, rlua::Value) = x?;
+                    array.push(lua_to_json_inner(v, depth + 1)?);
                 }
                 serde_json::Value::Array(array)
             }
         }
         _ => serde_json::Value::Null,
-    }
+    })
 }
 
 pub fn run_hook<
@@ -571,16 +826,68 @@ pub fn run_hook<
 ) -> Vec<R> {
     let globals = lua_ctx.globals();
     let hooks_table: rlua::Table = globals.get("hooks").unwrap();
-    let hooks = hooks_table.get(name);
+    let hooks = hooks_table.get(name.as_str());
     let mut result = vec![];
     if let Ok::<rlua::Table, _>(hooks) = hooks {
-        for pair in hooks.pairs() {
-            let (_, function): (String, rlua::Function) = pair.unwrap();
+        let profiler = crate::hook_profiler::HookProfiler::from_lua(lua_ctx);
+        let mut disabled = vec![];
+        let start = std::time::Instant::now();
+        for pair in hooks.clone().pairs() {
+            let (handler, function): (String, rlua::Function) = pair.unwrap();
+            if let Some(profiler) = &profiler {
+                profiler.reset_instructions();
+            }
+            let handler_start = std::time::Instant::now();
             match function.call::<A, R>(args.clone()) {
                 Ok(r) => result.push(r),
                 Err(r) => error!("{}", r),
             }
+            if let Some(profiler) = &profiler {
+                if profiler.record(&name, &handler, handler_start.elapsed()) {
+                    disabled.push(handler);
+                }
+            }
+        }
+        for handler in disabled {
+            let _ = hooks.set(handler, rlua::Value::Nil);
         }
+        crate::metrics::METRICS.record_hook(&name, start.elapsed());
     }
     result
 }
+
+/// What a handler of a cancellable hook has decided.
+/// Handlers return `false` to cancel the event or a table to change it, anything else lets it through
+pub enum HookDecision<'lua> {
+    Allow,
+    Cancel,
+    Modify(rlua::Table<'lua>),
+}
+
+impl<'lua> rlua::FromLua<'lua> for HookDecision<'lua> {
+    fn from_lua(value: rlua::Value<'lua>, _: rlua::Context<'lua>) -> rlua::Result<Self> {
+        Ok(match value {
+            rlua::Value::Boolean(false) => HookDecision::Cancel,
+            rlua::Value::Table(table) => HookDecision::Modify(table),
+            _ => HookDecision::Allow,
+        })
+    }
+}
+
+/// Runs a cancellable hook. Returns None if any handler has cancelled the event,
+/// otherwise the tables returned by the handlers
+pub fn run_cancellable_hook<'lua, A: std::clone::Clone + rlua::ToLuaMulti<'lua>>(
+    lua_ctx: rlua::Context<'lua>,
+    name: &str,
+    args: A,
+) -> Option<Vec<rlua::Table<'lua>>> {
+    let mut changes = vec![];
+    for decision in run_hook::<A, HookDecision>(lua_ctx, name.to_string(), args) {
+        match decision {
+            HookDecision::Allow => {}
+            HookDecision::Cancel => return None,
+            HookDecision::Modify(table) => changes.push(table),
+        }
+    }
+    Some(changes)
+}
@@ -1,17 +1,107 @@
+use clap::Parser;
 use kissmp_server::*;
-use log::{info};
+use log::{error, info, warn};
+use std::path::PathBuf;
+
+#[derive(Parser)]
+#[clap(version, about = "KissMP server")]
+struct Args {
+    /// Config file. JSON, TOML or YAML, picked by the file extension.
+    /// access.json, world.json and replays are kept next to it
+    #[clap(short, long, default_value = "./config.json")]
+    config: PathBuf,
+    /// Folder with mods that are sent to players
+    #[clap(long, default_value = "./mods/")]
+    mods_dir: PathBuf,
+    /// Folder with Lua addons
+    #[clap(long, default_value = "./addons/")]
+    addons_dir: PathBuf,
+    /// Overrides the port from the config
+    #[clap(short, long)]
+    port: Option<u16>,
+    /// Don't load Lua addons
+    #[clap(long)]
+    no_lua: bool,
+    /// Prints a default config in the format of --config and exits
+    #[clap(long)]
+    print_default_config: bool,
+    /// Checks the config for errors and exits
+    #[clap(long)]
+    check_config: bool,
+    /// Logs one JSON object per line instead of colored text
+    #[clap(long)]
+    headless_json_logs: bool,
+}
 
 #[tokio::main]
 async fn main() {
-    shared::init_logging();
+    let args = Args::parse();
+    if args.print_default_config {
+        let format = config::ConfigFormat::from_path(&args.config);
+        print!("{}", config::Config::default().to_string(format).unwrap());
+        return;
+    }
+    if args.headless_json_logs {
+        shared::init_json_logging();
+    } else {
+        shared::init_logging();
+    }
+    let overrides = config::ConfigOverrides { port: args.port };
+    if args.check_config {
+        match config::Config::from_file(&args.config, &overrides) {
+            Ok(_) => info!("{} is valid", args.config.display()),
+            Err(e) => {
+                error!("{} is invalid: {}", args.config.display(), e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
 
     info!("Gas, Gas, Gas!");
-    let path = std::path::Path::new("./mods/");
-    if !path.exists() {
-        std::fs::create_dir(path).unwrap();
+    if !args.mods_dir.exists() {
+        std::fs::create_dir_all(&args.mods_dir).unwrap();
     }
-    let config = config::Config::load(std::path::Path::new("./config.json"));
-    let server = Server::from_config(config);
-    server.run(true, tokio::sync::oneshot::channel().1, None).await;
+    let config = match config::Config::load(&args.config, &overrides) {
+        Ok(config) => config,
+        Err(e) => {
+            error!("Failed to load config {}: {}", args.config.display(), e);
+            std::process::exit(1);
+        }
+    };
+    let data_dir = match args.config.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+        _ => PathBuf::from("./"),
+    };
+    let paths = ServerPaths {
+        mods: args.mods_dir,
+        addons: args.addons_dir,
+        data: data_dir,
+    };
+    let mut server = match Server::with_paths(config, paths) {
+        Ok(server) => server,
+        Err(e) => {
+            error!("{:#}", e);
+            std::process::exit(1);
+        }
+    };
+    server.watch_config(&args.config, overrides);
+    server.enable_console();
+    let (destroyer_tx, destroyer_rx) = tokio::sync::oneshot::channel();
+    tokio::spawn(async move {
+        // Handles both SIGINT and SIGTERM
+        match async_ctrlc::CtrlC::new() {
+            Ok(ctrlc) => {
+                ctrlc.await;
+                let _ = destroyer_tx.send(());
+            }
+            Err(e) => {
+                warn!("Failed to set up the shutdown signal handler: {}", e);
+                // Dropping the sender would stop the server right away
+                futures::future::pending::<()>().await;
+            }
+        }
+    });
+    server.run(!args.no_lua, destroyer_rx, None).await;
     std::process::exit(0);
 }
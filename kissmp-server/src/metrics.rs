@@ -0,0 +1,241 @@
+use crate::*;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+pub static METRICS: Metrics = Metrics::new();
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Transport {
+    Stream,
+    Datagram,
+}
+
+#[derive(Default)]
+struct HookDuration {
+    sum_nanos: u64,
+    count: u64,
+}
+
+/// Counters and gauges exposed in the Prometheus text format
+pub struct Metrics {
+    players: AtomicU64,
+    vehicles: AtomicU64,
+    tick_nanos: AtomicU64,
+    ticks: AtomicU64,
+    tick_overruns: AtomicU64,
+    file_transfer_bytes: AtomicU64,
+    messages: Mutex<BTreeMap<(Direction, Transport, &'static str), u64>>,
+    hooks: Mutex<BTreeMap<String, HookDuration>>,
+    // Client ID -> (name, ping)
+    pings: Mutex<BTreeMap<u32, (String, u32)>>,
+}
+
+impl Metrics {
+    pub const fn new() -> Self {
+        Self {
+            players: AtomicU64::new(0),
+            vehicles: AtomicU64::new(0),
+            tick_nanos: AtomicU64::new(0),
+            ticks: AtomicU64::new(0),
+            tick_overruns: AtomicU64::new(0),
+            file_transfer_bytes: AtomicU64::new(0),
+            messages: Mutex::new(BTreeMap::new()),
+            hooks: Mutex::new(BTreeMap::new()),
+            pings: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn set_counts(&self, players: usize, vehicles: usize) {
+        self.players.store(players as u64, Ordering::Relaxed);
+        self.vehicles.store(vehicles as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_tick(&self, duration: std::time::Duration, budget: std::time::Duration) {
+        self.tick_nanos
+            .fetch_add(duration.as_nanos() as u64, Ordering::Relaxed);
+        self.ticks.fetch_add(1, Ordering::Relaxed);
+        if duration > budget {
+            self.tick_overruns.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn record_message(&self, direction: Direction, transport: Transport, command: &'static str) {
+        *self
+            .messages
+            .lock()
+            .unwrap()
+            .entry((direction, transport, command))
+            .or_default() += 1;
+    }
+
+    pub fn record_file_transfer(&self, bytes: usize) {
+        self.file_transfer_bytes
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_hook(&self, hook: &str, duration: std::time::Duration) {
+        let mut hooks = self.hooks.lock().unwrap();
+        if !hooks.contains_key(hook) {
+            hooks.insert(hook.to_string(), HookDuration::default());
+        }
+        let entry = hooks.get_mut(hook).unwrap();
+        entry.sum_nanos += duration.as_nanos() as u64;
+        entry.count += 1;
+    }
+
+    pub fn set_ping(&self, client_id: u32, name: &str, ping: u32) {
+        self.pings
+            .lock()
+            .unwrap()
+            .insert(client_id, (name.to_string(), ping));
+    }
+
+    pub fn remove_client(&self, client_id: u32) {
+        self.pings.lock().unwrap().remove(&client_id);
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let gauge = |out: &mut String, name: &str, help: &str, value: u64| {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} gauge", name);
+            let _ = writeln!(out, "{} {}", name, value);
+        };
+        gauge(
+            &mut out,
+            "kissmp_players",
+            "Connected players",
+            self.players.load(Ordering::Relaxed),
+        );
+        gauge(
+            &mut out,
+            "kissmp_vehicles",
+            "Spawned vehicles",
+            self.vehicles.load(Ordering::Relaxed),
+        );
+
+        let _ = writeln!(out, "# HELP kissmp_tick_duration_seconds Time spent in server ticks");
+        let _ = writeln!(out, "# TYPE kissmp_tick_duration_seconds summary");
+        let _ = writeln!(
+            out,
+            "kissmp_tick_duration_seconds_sum {}",
+            seconds(self.tick_nanos.load(Ordering::Relaxed))
+        );
+        let _ = writeln!(
+            out,
+            "kissmp_tick_duration_seconds_count {}",
+            self.ticks.load(Ordering::Relaxed)
+        );
+        let _ = writeln!(out, "# HELP kissmp_tick_overruns_total Ticks that took longer than the tick interval");
+        let _ = writeln!(out, "# TYPE kissmp_tick_overruns_total counter");
+        let _ = writeln!(
+            out,
+            "kissmp_tick_overruns_total {}",
+            self.tick_overruns.load(Ordering::Relaxed)
+        );
+
+        let _ = writeln!(out, "# HELP kissmp_messages_total Commands sent to and received from clients");
+        let _ = writeln!(out, "# TYPE kissmp_messages_total counter");
+        for ((direction, transport, command), count) in self.messages.lock().unwrap().iter() {
+            let direction = match direction {
+                Direction::Sent => "sent",
+                Direction::Received => "received",
+            };
+            let transport = match transport {
+                Transport::Stream => "stream",
+                Transport::Datagram => "datagram",
+            };
+            let _ = writeln!(
+                out,
+                "kissmp_messages_total{{direction=\"{}\",transport=\"{}\",command=\"{}\"}} {}",
+                direction, transport, command, count
+            );
+        }
+
+        let _ = writeln!(out, "# HELP kissmp_file_transfer_bytes_total Bytes of mods sent to clients");
+        let _ = writeln!(out, "# TYPE kissmp_file_transfer_bytes_total counter");
+        let _ = writeln!(
+            out,
+            "kissmp_file_transfer_bytes_total {}",
+            self.file_transfer_bytes.load(Ordering::Relaxed)
+        );
+
+        let _ = writeln!(out, "# HELP kissmp_lua_hook_duration_seconds Time spent in Lua hooks");
+        let _ = writeln!(out, "# TYPE kissmp_lua_hook_duration_seconds summary");
+        for (hook, duration) in self.hooks.lock().unwrap().iter() {
+            let hook = escape_label(hook);
+            let _ = writeln!(
+                out,
+                "kissmp_lua_hook_duration_seconds_sum{{hook=\"{}\"}} {}",
+                hook,
+                seconds(duration.sum_nanos)
+            );
+            let _ = writeln!(
+                out,
+                "kissmp_lua_hook_duration_seconds_count{{hook=\"{}\"}} {}",
+                hook, duration.count
+            );
+        }
+
+        let _ = writeln!(out, "# HELP kissmp_client_ping_milliseconds Ping reported by each client");
+        let _ = writeln!(out, "# TYPE kissmp_client_ping_milliseconds gauge");
+        for (client_id, (name, ping)) in self.pings.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "kissmp_client_ping_milliseconds{{client_id=\"{}\",name=\"{}\"}} {}",
+                client_id,
+                escape_label(name),
+                ping
+            );
+        }
+        out
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn seconds(nanos: u64) -> f64 {
+    nanos as f64 / 1_000_000_000.0
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Serves the metrics on a separate thread. It only listens on localhost
+pub fn start(port: u16) -> anyhow::Result<()> {
+    let server = tiny_http::Server::http(("127.0.0.1", port))
+        .map_err(|e| anyhow::Error::msg(e.to_string()))?;
+    std::thread::spawn(move || {
+        for request in server.incoming_requests() {
+            // Scrapers can add query parameters, only the path matters
+            let path = request.url().split('?').next().unwrap_or("");
+            let response = if path == "/metrics" {
+                let content_type = tiny_http::Header::from_bytes(
+                    &b"Content-Type"[..],
+                    &b"text/plain; version=0.0.4"[..],
+                )
+                .unwrap();
+                tiny_http::Response::from_string(METRICS.render()).with_header(content_type)
+            } else {
+                tiny_http::Response::from_string("Not found").with_status_code(404)
+            };
+            let _ = request.respond(response);
+        }
+    });
+    Ok(())
+}
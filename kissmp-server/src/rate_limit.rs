@@ -0,0 +1,336 @@
+use crate::*;
+use serde::{Deserialize, Serialize};
+use shared::ClientCommand;
+use std::time::Instant;
+
+// Dropped messages are counted over this window before deciding to kick
+const VIOLATION_WINDOW: Duration = Duration::from_secs(10);
+// Quinn lets clients open this many streams at once. Every message is sent on its own stream
+pub const MAX_CONCURRENT_STREAMS: u32 = 64;
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+pub struct RateLimit {
+    /// 0 disables the limit
+    pub per_second: f32,
+    /// Messages that can be sent at once before the limit kicks in
+    pub burst: u32,
+}
+
+impl RateLimit {
+    const fn new(per_second: f32, burst: u32) -> Self {
+        Self { per_second, burst }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct RateLimits {
+    /// Clients are kicked after this many dropped messages within 10 seconds. 0 never kicks them
+    pub kick_after: u32,
+    pub chat: RateLimit,
+    pub vehicle_spawn: RateLimit,
+    pub vehicle_update: RateLimit,
+    pub vehicle_event: RateLimit,
+    pub data_chunk: RateLimit,
+    pub voice: RateLimit,
+    pub other: RateLimit,
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        Self {
+            kick_after: 50,
+            chat: RateLimit::new(2.0, 10),
+            vehicle_spawn: RateLimit::new(2.0, 10),
+            vehicle_update: RateLimit::new(500.0, 1000),
+            vehicle_event: RateLimit::new(30.0, 100),
+            data_chunk: RateLimit::new(20.0, 64),
+            voice: RateLimit::new(100.0, 200),
+            other: RateLimit::new(20.0, 50),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Category {
+    Chat,
+    VehicleSpawn,
+    VehicleUpdate,
+    VehicleEvent,
+    DataChunk,
+    Voice,
+    Other,
+}
+
+impl Category {
+    pub fn of(command: &ClientCommand) -> Self {
+        use ClientCommand::*;
+        match command {
+            Chat(_) => Category::Chat,
+            VehicleData(_) => Category::VehicleSpawn,
+            VehicleUpdate(_) | GearboxUpdate(_) | VehicleUpdateAck(_) => Category::VehicleUpdate,
+            ResetVehicle(_)
+            | VehicleMetaUpdate(_)
+            | VehicleChanged(_)
+            | CouplerAttached(_)
+            | CouplerDetached(_)
+            | ElectricsUndefinedUpdate(_, _) => Category::VehicleEvent,
+            DataChunk { .. } => Category::DataChunk,
+            VoiceChatPacket(_) => Category::Voice,
+            _ => Category::Other,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Category::Chat => "chat",
+            Category::VehicleSpawn => "vehicle_spawn",
+            Category::VehicleUpdate => "vehicle_update",
+            Category::VehicleEvent => "vehicle_event",
+            Category::DataChunk => "data_chunk",
+            Category::Voice => "voice",
+            Category::Other => "other",
+        }
+    }
+
+    fn limit(&self, limits: &RateLimits) -> RateLimit {
+        match self {
+            Category::Chat => limits.chat,
+            Category::VehicleSpawn => limits.vehicle_spawn,
+            Category::VehicleUpdate => limits.vehicle_update,
+            Category::VehicleEvent => limits.vehicle_event,
+            Category::DataChunk => limits.data_chunk,
+            Category::Voice => limits.voice,
+            Category::Other => limits.other,
+        }
+    }
+}
+
+struct TokenBucket {
+    tokens: f32,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn take(&mut self, limit: RateLimit, now: Instant) -> bool {
+        let elapsed = now.duration_since(self.last_refill).as_secs_f32();
+        self.last_refill = now;
+        self.tokens = (self.tokens + elapsed * limit.per_second).min(limit.burst as f32);
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Verdict {
+    Allow,
+    Drop,
+    Kick(String),
+}
+
+/// Limits how many messages of each category a single client can send.
+/// The limits are shared with the server, so config changes apply to connected clients as well
+pub struct RateLimiter {
+    limits: Arc<Mutex<RateLimits>>,
+    buckets: HashMap<Category, TokenBucket>,
+    violations: u32,
+    window_start: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(limits: Arc<Mutex<RateLimits>>) -> Self {
+        Self {
+            limits,
+            buckets: HashMap::new(),
+            violations: 0,
+            window_start: Instant::now(),
+        }
+    }
+
+    pub fn check(&mut self, command: &ClientCommand) -> Verdict {
+        self.check_at(command, Instant::now())
+    }
+
+    fn check_at(&mut self, command: &ClientCommand, now: Instant) -> Verdict {
+        // Dropping it would leave a vehicle behind that the sender's game doesn't have anymore
+        if let ClientCommand::RemoveVehicle(_) = command {
+            return Verdict::Allow;
+        }
+        let category = Category::of(command);
+        let (limit, kick_after) = {
+            let limits = self.limits.lock().unwrap();
+            (category.limit(&limits), limits.kick_after)
+        };
+        if limit.per_second <= 0.0 {
+            return Verdict::Allow;
+        }
+        let bucket = self.buckets.entry(category).or_insert(TokenBucket {
+            tokens: limit.burst as f32,
+            last_refill: now,
+        });
+        if bucket.take(limit, now) {
+            return Verdict::Allow;
+        }
+        if now.duration_since(self.window_start) > VIOLATION_WINDOW {
+            self.window_start = now;
+            self.violations = 0;
+        }
+        self.violations += 1;
+        if kick_after != 0 && self.violations >= kick_after {
+            return Verdict::Kick(format!(
+                "Too many messages ({} limit exceeded)",
+                category.name()
+            ));
+        }
+        Verdict::Drop
+    }
+}
+
+/// Returned by `handle_incoming_data` when the client has to be kicked
+#[derive(Debug)]
+pub struct Kicked(pub String);
+
+impl std::fmt::Display for Kicked {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for Kicked {}
+
+impl Server {
+    pub fn kick_client(&mut self, client_id: u32, reason: String) {
+        if let Some(connection) = self.connections.get(&client_id) {
+            warn!("Client {} was kicked: {}", client_id, reason);
+            connection.conn.close(1u32.into(), reason.as_bytes());
+        }
+        self.chunk_buffers.remove(&client_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(chat: RateLimit, kick_after: u32) -> RateLimiter {
+        RateLimiter::new(Arc::new(Mutex::new(RateLimits {
+            kick_after,
+            chat,
+            ..Default::default()
+        })))
+    }
+
+    fn chat() -> ClientCommand {
+        ClientCommand::Chat(String::from("hi"))
+    }
+
+    fn is_kick(verdict: Verdict) -> bool {
+        matches!(verdict, Verdict::Kick(_))
+    }
+
+    #[test]
+    fn burst_then_refill() {
+        let mut limiter = limiter(RateLimit::new(2.0, 3), 0);
+        let start = Instant::now();
+        for _ in 0..3 {
+            assert_eq!(limiter.check_at(&chat(), start), Verdict::Allow);
+        }
+        assert_eq!(limiter.check_at(&chat(), start), Verdict::Drop);
+        // 2 per second, so one message is allowed again after half a second
+        let later = start + Duration::from_millis(500);
+        assert_eq!(limiter.check_at(&chat(), later), Verdict::Allow);
+        assert_eq!(limiter.check_at(&chat(), later), Verdict::Drop);
+        // Tokens never go above the burst
+        let much_later = start + Duration::from_secs(60);
+        for _ in 0..3 {
+            assert_eq!(limiter.check_at(&chat(), much_later), Verdict::Allow);
+        }
+        assert_eq!(limiter.check_at(&chat(), much_later), Verdict::Drop);
+    }
+
+    #[test]
+    fn categories_are_limited_separately() {
+        let mut limiter = limiter(RateLimit::new(1.0, 1), 0);
+        let now = Instant::now();
+        assert_eq!(limiter.check_at(&chat(), now), Verdict::Allow);
+        assert_eq!(limiter.check_at(&chat(), now), Verdict::Drop);
+        assert_eq!(limiter.check_at(&ClientCommand::Ping(0), now), Verdict::Allow);
+    }
+
+    #[test]
+    fn zero_disables_the_limit() {
+        let mut limiter = limiter(RateLimit::new(0.0, 0), 1);
+        let now = Instant::now();
+        for _ in 0..1000 {
+            assert_eq!(limiter.check_at(&chat(), now), Verdict::Allow);
+        }
+    }
+
+    #[test]
+    fn vehicle_removals_are_never_dropped() {
+        let mut limiter = RateLimiter::new(Arc::new(Mutex::new(RateLimits {
+            kick_after: 1,
+            vehicle_spawn: RateLimit::new(1.0, 1),
+            ..Default::default()
+        })));
+        let now = Instant::now();
+        for id in 0..100 {
+            assert_eq!(
+                limiter.check_at(&ClientCommand::RemoveVehicle(id), now),
+                Verdict::Allow
+            );
+        }
+    }
+
+    #[test]
+    fn kicks_after_too_many_dropped_messages() {
+        let mut limiter = limiter(RateLimit::new(1.0, 1), 3);
+        let now = Instant::now();
+        assert_eq!(limiter.check_at(&chat(), now), Verdict::Allow);
+        assert_eq!(limiter.check_at(&chat(), now), Verdict::Drop);
+        assert_eq!(limiter.check_at(&chat(), now), Verdict::Drop);
+        assert!(is_kick(limiter.check_at(&chat(), now)));
+    }
+
+    #[test]
+    fn violations_are_counted_per_window() {
+        let mut limiter = limiter(RateLimit::new(0.001, 1), 3);
+        let start = Instant::now();
+        assert_eq!(limiter.check_at(&chat(), start), Verdict::Allow);
+        assert_eq!(limiter.check_at(&chat(), start), Verdict::Drop);
+        assert_eq!(limiter.check_at(&chat(), start), Verdict::Drop);
+        // The window is over, so the count starts again
+        let later = start + VIOLATION_WINDOW + Duration::from_secs(1);
+        assert_eq!(limiter.check_at(&chat(), later), Verdict::Drop);
+        assert_eq!(limiter.check_at(&chat(), later), Verdict::Drop);
+        assert!(is_kick(limiter.check_at(&chat(), later)));
+    }
+
+    #[test]
+    fn zero_kick_after_never_kicks() {
+        let mut limiter = limiter(RateLimit::new(1.0, 1), 0);
+        let now = Instant::now();
+        assert_eq!(limiter.check_at(&chat(), now), Verdict::Allow);
+        for _ in 0..1000 {
+            assert_eq!(limiter.check_at(&chat(), now), Verdict::Drop);
+        }
+    }
+
+    #[test]
+    fn limit_changes_apply_to_existing_limiters() {
+        let limits = Arc::new(Mutex::new(RateLimits {
+            kick_after: 0,
+            chat: RateLimit::new(1.0, 1),
+            ..Default::default()
+        }));
+        let mut limiter = RateLimiter::new(limits.clone());
+        let now = Instant::now();
+        assert_eq!(limiter.check_at(&chat(), now), Verdict::Allow);
+        assert_eq!(limiter.check_at(&chat(), now), Verdict::Drop);
+        limits.lock().unwrap().chat = RateLimit::new(0.0, 0);
+        assert_eq!(limiter.check_at(&chat(), now), Verdict::Allow);
+    }
+}
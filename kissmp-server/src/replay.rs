@@ -0,0 +1,366 @@
+use crate::*;
+use serde::{Deserialize, Serialize};
+use std::io::{BufReader, BufWriter, Read, Write};
+
+pub const REPLAYS_DIR: &str = "replays";
+const REPLAY_MAGIC: &[u8; 8] = b"KISSRPLY";
+const REPLAY_VERSION: u32 = 1;
+const FLUSH_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
+// Far above any real frame, a larger length prefix means the file is corrupt
+const MAX_FRAME_SIZE: usize = 64 * 1024 * 1024;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ReplayHeader {
+    pub version: u32,
+    pub server_name: String,
+    pub map: String,
+    pub tickrate: u8,
+    pub mods: Vec<(String, u32)>,
+    /// Unix timestamp in seconds
+    pub started_at: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ReplayFrame {
+    pub tick: u64,
+    pub command: ServerCommand,
+}
+
+/// Writes length prefixed frames to a replay file.
+/// Vehicle updates are stored as compact updates relative to the previous frame of the same vehicle.
+pub struct ReplayRecorder {
+    writer: BufWriter<std::fs::File>,
+    states: StateHistory,
+    last_generations: HashMap<u32, u64>,
+    last_flush: std::time::Instant,
+}
+
+impl ReplayRecorder {
+    pub fn create(path: &std::path::Path, header: &ReplayHeader) -> anyhow::Result<Self> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let mut writer = BufWriter::new(std::fs::File::create(path)?);
+        writer.write_all(REPLAY_MAGIC)?;
+        write_frame(&mut writer, &bincode::serialize(header)?)?;
+        Ok(Self {
+            writer,
+            states: StateHistory::default(),
+            last_generations: HashMap::new(),
+            last_flush: std::time::Instant::now(),
+        })
+    }
+
+    pub fn record(&mut self, tick: u64, command: ServerCommand) {
+        if let ServerCommand::RemoveVehicle(id) = &command {
+            self.states.remove(*id);
+            self.last_generations.remove(id);
+        }
+        let frame = ReplayFrame { tick, command };
+        let result = bincode::serialize(&frame)
+            .map_err(anyhow::Error::from)
+            .and_then(|data| write_frame(&mut self.writer, &data));
+        if let Err(e) = result {
+            error!("Failed to write replay frame: {}", e);
+        }
+        if self.last_flush.elapsed() > FLUSH_INTERVAL {
+            self.flush();
+        }
+    }
+
+    pub fn record_vehicles(&mut self, tick: u64, vehicles: &HashMap<u32, Vehicle>) {
+        for (vehicle_id, vehicle) in vehicles {
+            if let (Some(transform), Some(electrics), Some(gearbox)) =
+                (&vehicle.transform, &vehicle.electrics, &vehicle.gearbox)
+            {
+                let state = VehicleState::from_parts(transform, electrics, gearbox);
+                let baseline = self
+                    .last_generations
+                    .get(vehicle_id)
+                    .and_then(|generation| {
+                        self.states
+                            .get(*vehicle_id, *generation)
+                            .map(|state| (*generation, state))
+                    });
+                // Parked vehicles don't need a frame every tick
+                if let Some((_, baseline)) = baseline {
+                    if *baseline == state {
+                        continue;
+                    }
+                }
+                let update = CompactVehicleUpdate::encode(*vehicle_id, tick, &state, baseline);
+                self.states.push(*vehicle_id, tick, state);
+                self.last_generations.insert(*vehicle_id, tick);
+                self.record(tick, ServerCommand::VehicleUpdateCompact(update.to_bytes()));
+            }
+        }
+    }
+
+    pub fn flush(&mut self) {
+        let _ = self.writer.flush();
+        self.last_flush = std::time::Instant::now();
+    }
+}
+
+impl Drop for ReplayRecorder {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+/// Reads a replay file back. Compact vehicle updates are returned as regular `VehicleUpdate`s
+pub struct ReplayReader {
+    reader: BufReader<std::fs::File>,
+    states: StateHistory,
+    pub header: ReplayHeader,
+}
+
+impl ReplayReader {
+    pub fn open(path: &std::path::Path) -> anyhow::Result<Self> {
+        let mut reader = BufReader::new(std::fs::File::open(path)?);
+        let mut magic = [0; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != REPLAY_MAGIC {
+            return Err(anyhow::Error::msg("Not a KissMP replay file"));
+        }
+        let header: ReplayHeader = match read_frame(&mut reader)? {
+            Some(data) => bincode::deserialize(&data)?,
+            None => return Err(anyhow::Error::msg("Replay file is empty")),
+        };
+        if header.version != REPLAY_VERSION {
+            return Err(anyhow::Error::msg(format!(
+                "Unsupported replay version {}",
+                header.version
+            )));
+        }
+        Ok(Self {
+            reader,
+            states: StateHistory::default(),
+            header,
+        })
+    }
+
+    pub fn next_frame(&mut self) -> anyhow::Result<Option<ReplayFrame>> {
+        let data = match read_frame(&mut self.reader)? {
+            Some(data) => data,
+            None => return Ok(None),
+        };
+        let mut frame: ReplayFrame = bincode::deserialize(&data)?;
+        if let ServerCommand::VehicleUpdateCompact(data) = &frame.command {
+            let update = CompactVehicleUpdate::from_bytes(data)?;
+            let baseline = update
+                .baseline
+                .and_then(|generation| self.states.get(update.vehicle_id, generation));
+            let state = update
+                .decode(baseline)
+                .context("Vehicle update references a missing frame")?;
+            let decoded = state.to_update(update.vehicle_id, update.generation);
+            self.states.push(update.vehicle_id, update.generation, state);
+            frame.command = ServerCommand::VehicleUpdate(decoded);
+        }
+        Ok(Some(frame))
+    }
+}
+
+fn write_frame<W: Write>(writer: &mut W, data: &[u8]) -> anyhow::Result<()> {
+    if data.len() > MAX_FRAME_SIZE {
+        return Err(anyhow::Error::msg(format!(
+            "Replay frame of {} bytes is too large",
+            data.len()
+        )));
+    }
+    writer.write_all(&(data.len() as u32).to_le_bytes())?;
+    writer.write_all(data)?;
+    Ok(())
+}
+
+fn read_frame<R: Read>(reader: &mut R) -> anyhow::Result<Option<Vec<u8>>> {
+    let mut len = [0; 4];
+    match reader.read_exact(&mut len) {
+        Ok(()) => {}
+        // A replay that wasn't closed properly can end in the middle of a frame
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let len = u32::from_le_bytes(len) as usize;
+    if len > MAX_FRAME_SIZE {
+        return Err(anyhow::Error::msg(format!(
+            "Replay frame of {} bytes is too large, the file is corrupt",
+            len
+        )));
+    }
+    let mut data = vec![0; len];
+    match reader.read_exact(&mut data) {
+        Ok(()) => Ok(Some(data)),
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+impl Server {
+    pub fn start_replay_recording(&mut self) {
+        let started_at = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let header = ReplayHeader {
+            version: REPLAY_VERSION,
+            server_name: self.name.clone(),
+            map: self.map.clone(),
+            tickrate: self.tickrate,
+            mods: list_mods(self.mods.clone(), &self.paths.mods).map(|x| x.0).unwrap_or_default(),
+            started_at,
+        };
+        let path = self
+            .paths
+            .data
+            .join(REPLAYS_DIR)
+            .join(format!("{}.kissreplay", started_at));
+        match ReplayRecorder::create(&path, &header) {
+            Ok(recorder) => {
+                info!("Recording replay to {}", path.display());
+                self.replay = Some(recorder);
+            }
+            Err(e) => error!("Failed to start replay recording: {}", e),
+        }
+    }
+
+    pub fn record_replay(&mut self, command: ServerCommand) {
+        if let Some(replay) = &mut self.replay {
+            replay.record(self.tick, command);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::vehicle_data;
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("kissmp-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir.join("test.kissreplay")
+    }
+
+    fn header() -> ReplayHeader {
+        ReplayHeader {
+            version: REPLAY_VERSION,
+            server_name: String::from("Test"),
+            map: String::from("/levels/smallgrid/info.json"),
+            tickrate: 60,
+            mods: vec![(String::from("mod.zip"), 1234)],
+            started_at: 1,
+        }
+    }
+
+    fn vehicle(position: [f32; 3]) -> Vehicle {
+        Vehicle {
+            transform: Some(Transform {
+                position,
+                rotation: [0.0, 0.0, 0.0, 1.0],
+                velocity: [1.0, 2.0, 3.0],
+                angular_velocity: [0.0; 3],
+            }),
+            electrics: Some(Electrics {
+                throttle_input: 0.5,
+                brake_input: 0.0,
+                clutch: 0.0,
+                parkingbrake: 0.0,
+                steering_input: 0.0,
+            }),
+            gearbox: Some(Gearbox {
+                arcade: true,
+                lock_coef: 0.0,
+                mode: None,
+                gear_indices: [1, 0],
+            }),
+            data: vehicle_data(1),
+            update_sent_at: None,
+        }
+    }
+
+    fn next_position(reader: &mut ReplayReader, tick: u64) -> [f32; 3] {
+        let frame = reader.next_frame().unwrap().unwrap();
+        assert_eq!(frame.tick, tick);
+        match frame.command {
+            ServerCommand::VehicleUpdate(update) => {
+                assert_eq!(update.vehicle_id, 7);
+                assert_eq!(update.gearbox.gear_indices, [1, 0]);
+                update.transform.position
+            }
+            command => panic!("unexpected {:?}", command),
+        }
+    }
+
+    fn assert_close(a: [f32; 3], b: [f32; 3]) {
+        assert!(
+            a.iter().zip(b.iter()).all(|(a, b)| (a - b).abs() < 0.01),
+            "{:?} != {:?}",
+            a,
+            b
+        );
+    }
+
+    #[test]
+    fn recorded_frames_are_read_back() {
+        let path = temp_path("replay-round-trip");
+        let mut recorder = ReplayRecorder::create(&path, &header()).unwrap();
+        recorder.record(0, ServerCommand::Chat(String::from("Hello"), Some(3)));
+        let mut vehicles = HashMap::new();
+        vehicles.insert(7, vehicle([10.0, 20.0, 30.0]));
+        recorder.record_vehicles(1, &vehicles);
+        vehicles.insert(7, vehicle([11.0, 20.5, 30.0]));
+        recorder.record_vehicles(2, &vehicles);
+        // Unchanged vehicles aren't recorded again
+        recorder.record_vehicles(3, &vehicles);
+        recorder.record(4, ServerCommand::RemoveVehicle(7));
+        drop(recorder);
+
+        let mut reader = ReplayReader::open(&path).unwrap();
+        assert_eq!(reader.header.server_name, "Test");
+        assert_eq!(reader.header.mods, header().mods);
+        let frame = reader.next_frame().unwrap().unwrap();
+        assert_eq!(frame.tick, 0);
+        assert!(matches!(
+            frame.command,
+            ServerCommand::Chat(message, Some(3)) if message == "Hello"
+        ));
+        assert_close(next_position(&mut reader, 1), [10.0, 20.0, 30.0]);
+        assert_close(next_position(&mut reader, 2), [11.0, 20.5, 30.0]);
+        let frame = reader.next_frame().unwrap().unwrap();
+        assert_eq!(frame.tick, 4);
+        assert!(matches!(frame.command, ServerCommand::RemoveVehicle(7)));
+        assert!(reader.next_frame().unwrap().is_none());
+    }
+
+    #[test]
+    fn truncated_replays_end_at_the_last_whole_frame() {
+        let path = temp_path("replay-truncated");
+        let mut recorder = ReplayRecorder::create(&path, &header()).unwrap();
+        recorder.record(0, ServerCommand::Chat(String::from("Hello"), None));
+        recorder.record(1, ServerCommand::Chat(String::from("Cut off"), None));
+        drop(recorder);
+        let len = std::fs::metadata(&path).unwrap().len();
+        let file = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(len - 3).unwrap();
+
+        let mut reader = ReplayReader::open(&path).unwrap();
+        assert_eq!(reader.next_frame().unwrap().unwrap().tick, 0);
+        assert!(reader.next_frame().unwrap().is_none());
+    }
+
+    #[test]
+    fn oversized_frames_are_rejected() {
+        let mut data = REPLAY_MAGIC.to_vec();
+        data.extend_from_slice(&u32::MAX.to_le_bytes());
+        let path = temp_path("replay-oversized");
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, data).unwrap();
+        assert!(ReplayReader::open(&path).is_err());
+
+        let mut buffer = vec![];
+        assert!(write_frame(&mut buffer, &vec![0; MAX_FRAME_SIZE + 1]).is_err());
+        assert!(buffer.is_empty());
+    }
+}
@@ -6,6 +6,8 @@ pub struct Vehicle {
     pub electrics: Option<Electrics>,
     pub gearbox: Option<Gearbox>,
     pub data: VehicleData,
+    // `sent_at` of the last accepted update
+    pub update_sent_at: Option<f64>,
 }
 
 impl crate::Server {
@@ -21,8 +23,14 @@ impl crate::Server {
             }
         }
 
-        self.vehicles.remove(&id);
+        if self.vehicles.remove(&id).is_some() {
+            self.vehicle_id_allocator.release(id);
+        }
+        self.restored_owners.remove(&id);
+        self.record_replay(ServerCommand::RemoveVehicle(id));
         for (cid, client) in &mut self.connections {
+            client.sent_states.remove(id);
+            client.acked_generations.remove(&id);
             if Some(*cid) == client_id {
                 continue;
             }
@@ -38,6 +46,20 @@ impl crate::Server {
         });
     }    
     pub async fn reset_vehicle(&mut self, data: VehicleReset, client_id: Option<u32>) {
+        let vehicle_id = data.vehicle_id;
+        self.move_vehicle(data, client_id).await;
+        self.lua.context(|lua_ctx| {
+            let _ = crate::lua::run_hook::<(u32, Option<u32>), ()>(
+                lua_ctx,
+                String::from("OnVehicleResetted"),
+                (vehicle_id, client_id),
+            );
+        });
+    }
+
+    /// Resets the vehicle at a new place for everyone except `client_id`
+    pub async fn move_vehicle(&mut self, data: VehicleReset, client_id: Option<u32>) {
+        self.record_replay(ServerCommand::ResetVehicle(data.clone()));
         for (cid, client) in &mut self.connections {
             if client_id.is_some() && *cid == client_id.unwrap() {
                 continue;
@@ -60,13 +82,28 @@ impl crate::Server {
         }
 
         let _ = self.update_lua_vehicles();
-        self.lua.context(|lua_ctx| {
-            let _ = crate::lua::run_hook::<(u32, Option<u32>), ()>(
-                lua_ctx,
-                String::from("OnVehicleResetted"),
-                (data.vehicle_id, client_id),
-            );
-        });
+    }
+
+    pub async fn recolor_vehicle(&mut self, vehicle_id: u32, colors_table: [[f32; 8]; 3]) {
+        let vehicle = match self.vehicles.get_mut(&vehicle_id) {
+            Some(vehicle) => vehicle,
+            None => return,
+        };
+        vehicle.data.color = colors_table[0];
+        vehicle.data.palete_0 = colors_table[1];
+        vehicle.data.palete_1 = colors_table[2];
+        let meta = VehicleMeta {
+            vehicle_id,
+            plate: vehicle.data.plate.clone(),
+            colors_table,
+        };
+        for client in self.connections.values_mut() {
+            let _ = client
+                .ordered
+                .send(ServerCommand::VehicleMetaUpdate(meta.clone()))
+                .await;
+        }
+        let _ = self.update_lua_vehicles();
     }
 
     pub async fn set_current_vehicle(&mut self, client_id: u32, vehicle_id: Option<u32>) {
@@ -89,10 +126,21 @@ impl crate::Server {
     }
 
     pub async fn spawn_vehicle(&mut self, owner: Option<u32>, data: VehicleData) {
-        let server_id = rand::random::<u16>() as u32;
+        let server_id = match self.vehicle_id_allocator.allocate() {
+            Some(id) => id,
+            None => {
+                error!("Vehicle {} wasn't spawned, there are no free vehicle IDs", data.name);
+                return;
+            }
+        };
+        self.spawn_vehicle_with_id(server_id, owner, data).await;
+    }
+
+    pub async fn spawn_vehicle_with_id(&mut self, server_id: u32, owner: Option<u32>, data: VehicleData) {
         let mut data = data.clone();
         data.server_id = server_id;
         data.owner = owner;
+        self.record_replay(ServerCommand::VehicleSpawn(data.clone()));
         for (_, client) in &mut self.connections {
             let _ = client
                 .ordered
@@ -116,6 +164,7 @@ impl crate::Server {
                 gearbox: None,
                 electrics: None,
                 transform: None,
+                update_sent_at: None,
             },
         );
 
@@ -0,0 +1,209 @@
+use crate::*;
+use std::collections::HashSet;
+use std::sync::mpsc as std_mpsc;
+
+pub const STORAGE_DIR: &str = "storage";
+// Changes are written to disk at most this long after the first one of them
+const WRITE_DELAY: std::time::Duration = std::time::Duration::from_secs(1);
+
+type Namespace = serde_json::Map<String, serde_json::Value>;
+
+enum StorageMessage {
+    Changed(String),
+    Flush(std_mpsc::Sender<()>),
+}
+
+/// Key-value storage for addons. Every addon has its own namespace, saved to `storage/<addon>.json`.
+/// Values are kept in memory and written by a separate thread, so the tick loop never waits for the disk.
+#[derive(Clone)]
+pub struct Storage {
+    path: std::path::PathBuf,
+    namespaces: Arc<Mutex<HashMap<String, Namespace>>>,
+    writer: std_mpsc::Sender<StorageMessage>,
+}
+
+impl Storage {
+    pub fn new(path: std::path::PathBuf) -> Self {
+        let (writer, rx) = std_mpsc::channel();
+        let storage = Self {
+            path,
+            namespaces: Arc::new(Mutex::new(HashMap::new())),
+            writer,
+        };
+        let path = storage.path.clone();
+        let namespaces = storage.namespaces.clone();
+        std::thread::spawn(move || run_writer(path, namespaces, rx));
+        storage
+    }
+
+    fn with_namespace<R>(&self, namespace: &str, f: impl FnOnce(&mut Namespace) -> R) -> R {
+        let mut namespaces = self.namespaces.lock().unwrap();
+        if !namespaces.contains_key(namespace) {
+            let path = file_path(&self.path, namespace);
+            let values = match std::fs::read(&path) {
+                Ok(data) => serde_json::from_slice(&data).unwrap_or_else(|e| {
+                    error!("Failed to read {}: {}", path.display(), e);
+                    Namespace::new()
+                }),
+                Err(_) => Namespace::new(),
+            };
+            namespaces.insert(namespace.to_string(), values);
+        }
+        f(namespaces.get_mut(namespace).unwrap())
+    }
+
+    pub fn get(&self, namespace: &str, key: &str) -> Option<serde_json::Value> {
+        self.with_namespace(namespace, |values| values.get(key).cloned())
+    }
+
+    pub fn set(&self, namespace: &str, key: String, value: serde_json::Value) {
+        self.with_namespace(namespace, |values| values.insert(key, value));
+        let _ = self.writer.send(StorageMessage::Changed(namespace.to_string()));
+    }
+
+    pub fn delete(&self, namespace: &str, key: &str) {
+        if self.with_namespace(namespace, |values| values.remove(key)).is_some() {
+            let _ = self.writer.send(StorageMessage::Changed(namespace.to_string()));
+        }
+    }
+
+    pub fn keys(&self, namespace: &str) -> Vec<String> {
+        self.with_namespace(namespace, |values| values.keys().cloned().collect())
+    }
+
+    /// Blocks until every change is on disk
+    pub fn flush(&self) {
+        let (tx, rx) = std_mpsc::channel();
+        if self.writer.send(StorageMessage::Flush(tx)).is_ok() {
+            let _ = rx.recv();
+        }
+    }
+}
+
+fn file_path(path: &std::path::Path, namespace: &str) -> std::path::PathBuf {
+    path.join(format!("{}.json", namespace))
+}
+
+fn run_writer(
+    path: std::path::PathBuf,
+    namespaces: Arc<Mutex<HashMap<String, Namespace>>>,
+    rx: std_mpsc::Receiver<StorageMessage>,
+) {
+    let mut changed = HashSet::new();
+    // When the pending changes have to be written. Later changes don't move it,
+    // so addons that write all the time still get saved regularly
+    let mut deadline: Option<std::time::Instant> = None;
+    loop {
+        let message = match deadline {
+            None => match rx.recv() {
+                Ok(message) => message,
+                Err(_) => break,
+            },
+            Some(deadline_at) => {
+                let timeout = deadline_at.saturating_duration_since(std::time::Instant::now());
+                match rx.recv_timeout(timeout) {
+                    Ok(message) => message,
+                    Err(std_mpsc::RecvTimeoutError::Timeout) => {
+                        write_namespaces(&path, &namespaces, changed.drain());
+                        deadline = None;
+                        continue;
+                    }
+                    Err(std_mpsc::RecvTimeoutError::Disconnected) => break,
+                }
+            }
+        };
+        match message {
+            StorageMessage::Changed(namespace) => {
+                changed.insert(namespace);
+                if deadline.is_none() {
+                    deadline = Some(std::time::Instant::now() + WRITE_DELAY);
+                }
+            }
+            StorageMessage::Flush(done) => {
+                write_namespaces(&path, &namespaces, changed.drain());
+                deadline = None;
+                let _ = done.send(());
+            }
+        }
+    }
+    write_namespaces(&path, &namespaces, changed.drain());
+}
+
+fn write_namespaces(
+    path: &std::path::Path,
+    namespaces: &Mutex<HashMap<String, Namespace>>,
+    changed: impl Iterator<Item = String>,
+) {
+    for namespace in changed {
+        let data = match namespaces.lock().unwrap().get(&namespace) {
+            Some(values) => serde_json::to_vec_pretty(values).unwrap(),
+            None => continue,
+        };
+        if let Err(e) = write_file(&file_path(path, &namespace), &data) {
+            error!("Failed to save storage of {}: {}", namespace, e);
+        }
+    }
+}
+
+/// Writes to a temporary file first, so a crash while saving doesn't lose the previous data
+fn write_file(path: &std::path::Path, data: &[u8]) -> anyhow::Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let temp_path = path.with_extension("json.tmp");
+    std::fs::write(&temp_path, data)?;
+    std::fs::rename(temp_path, path)?;
+    Ok(())
+}
+
+/// Creates the `storage` table of an addon
+pub fn create_storage_table<'lua>(
+    lua_ctx: rlua::Context<'lua>,
+    storage: &Storage,
+    namespace: &str,
+) -> rlua::Result<rlua::Table<'lua>> {
+    let table = lua_ctx.create_table()?;
+    let (s, ns) = (storage.clone(), namespace.to_string());
+    table.set(
+        "get",
+        lua_ctx.create_function(move |lua_ctx, key: String| {
+            Ok(match s.get(&ns, &key) {
+                Some(value) => lua::json_to_lua(lua_ctx, value),
+                None => rlua::Value::Nil,
+            })
+        })?,
+    )?;
+    let (s, ns) = (storage.clone(), namespace.to_string());
+    table.set(
+        "set",
+        lua_ctx.create_function(move |_, (key, value): (String, rlua::Value)| {
+            match value {
+                rlua::Value::Nil => s.delete(&ns, &key),
+                rlua::Value::Function(_)
+                | rlua::Value::Thread(_)
+                | rlua::Value::UserData(_)
+                | rlua::Value::LightUserData(_) => {
+                    return Err(rlua::Error::RuntimeError(String::from(
+                        "Only strings, numbers, booleans and tables can be stored",
+                    )))
+                }
+                value => s.set(&ns, key, lua::lua_to_json(value)?),
+            }
+            Ok(())
+        })?,
+    )?;
+    let (s, ns) = (storage.clone(), namespace.to_string());
+    table.set(
+        "delete",
+        lua_ctx.create_function(move |_, key: String| {
+            s.delete(&ns, &key);
+            Ok(())
+        })?,
+    )?;
+    let (s, ns) = (storage.clone(), namespace.to_string());
+    table.set(
+        "keys",
+        lua_ctx.create_function(move |_, ()| Ok(s.keys(&ns)))?,
+    )?;
+    Ok(table)
+}
@@ -0,0 +1,29 @@
+use crate::*;
+
+// Keeps the files the server creates out of the working directory
+pub fn test_server(name: &str) -> Server {
+    let dir = std::env::temp_dir().join(format!("kissmp-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let paths = ServerPaths {
+        mods: dir.join("mods"),
+        addons: dir.join("addons"),
+        data: dir,
+    };
+    Server::with_paths(config::Config::default(), paths).unwrap()
+}
+
+pub fn vehicle_data(in_game_id: u32) -> VehicleData {
+    VehicleData {
+        parts_config: String::from("{}"),
+        in_game_id,
+        color: [0.0; 8],
+        palete_0: [0.0; 8],
+        palete_1: [0.0; 8],
+        plate: None,
+        name: String::from("pickup"),
+        server_id: 0,
+        owner: None,
+        position: [0.0; 3],
+        rotation: [0.0, 0.0, 0.0, 1.0],
+    }
+}
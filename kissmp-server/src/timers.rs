@@ -0,0 +1,222 @@
+use crate::*;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+const TIMERS_REGISTRY_KEY: &str = "kissmp_timers";
+// Lua table: timer ID -> { callback, interval, addon }
+const CALLBACKS_REGISTRY_KEY: &str = "kissmp_timer_callbacks";
+
+// `sleep` and `async` are easier to write in Lua, since they have to yield the running coroutine
+const COROUTINE_HELPERS: &str = r#"
+local set_timeout = ...
+local function sleep(ms)
+    local co, is_main = coroutine.running()
+    if not co or is_main then
+        error("sleep can only be used inside a coroutine. Wrap your function with async", 2)
+    end
+    set_timeout(ms, function()
+        local ok, err = coroutine.resume(co)
+        if not ok then
+            error(err, 0)
+        end
+    end)
+    coroutine.yield()
+end
+local function async(f)
+    return function(...)
+        local ok, err = coroutine.resume(coroutine.create(f), ...)
+        if not ok then
+            error(err, 0)
+        end
+    end
+end
+return sleep, async
+"#;
+
+/// Timers wait on the tokio runtime and send their ID to the server loop once they're due.
+/// Callbacks are kept in the Lua registry.
+#[derive(Clone)]
+pub struct Timers {
+    tx: mpsc::UnboundedSender<u64>,
+    handles: Arc<Mutex<HashMap<u64, tokio::task::JoinHandle<()>>>>,
+    next_id: Arc<AtomicU64>,
+}
+
+impl rlua::UserData for Timers {}
+
+impl Timers {
+    pub fn new() -> (Self, mpsc::UnboundedReceiver<u64>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        (
+            Self {
+                tx,
+                handles: Arc::new(Mutex::new(HashMap::new())),
+                next_id: Arc::new(AtomicU64::new(1)),
+            },
+            rx,
+        )
+    }
+
+    pub fn install(&self, lua: &rlua::Lua) {
+        lua.context(|lua_ctx| {
+            lua_ctx
+                .set_named_registry_value(TIMERS_REGISTRY_KEY, self.clone())
+                .unwrap();
+            lua_ctx
+                .set_named_registry_value(CALLBACKS_REGISTRY_KEY, lua_ctx.create_table().unwrap())
+                .unwrap();
+            add_timer_functions(lua_ctx, lua_ctx.globals(), None).unwrap();
+        });
+    }
+
+    fn from_lua(lua_ctx: rlua::Context) -> rlua::Result<Self> {
+        let timers: rlua::AnyUserData = lua_ctx.named_registry_value(TIMERS_REGISTRY_KEY)?;
+        let timers = timers.borrow::<Timers>()?.clone();
+        Ok(timers)
+    }
+
+    fn start(&self, delay: std::time::Duration, interval: bool) -> rlua::Result<u64> {
+        let runtime = tokio::runtime::Handle::try_current().map_err(|_| {
+            rlua::Error::RuntimeError(String::from("Timers can't be used outside of the server"))
+        })?;
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let tx = self.tx.clone();
+        let handle = runtime.spawn(async move {
+            if interval {
+                let start = tokio::time::Instant::now() + delay;
+                let mut ticks = tokio::time::interval_at(start, delay);
+                loop {
+                    ticks.tick().await;
+                    if tx.send(id).is_err() {
+                        break;
+                    }
+                }
+            } else {
+                tokio::time::sleep(delay).await;
+                let _ = tx.send(id);
+            }
+        });
+        self.handles.lock().unwrap().insert(id, handle);
+        Ok(id)
+    }
+
+    fn stop(&self, id: u64) {
+        if let Some(handle) = self.handles.lock().unwrap().remove(&id) {
+            handle.abort();
+        }
+    }
+}
+
+fn add_timer<'lua>(
+    lua_ctx: rlua::Context<'lua>,
+    delay: u64,
+    callback: rlua::Function<'lua>,
+    interval: bool,
+    addon: Option<String>,
+) -> rlua::Result<u64> {
+    if interval && delay == 0 {
+        return Err(rlua::Error::RuntimeError(String::from(
+            "Interval has to be longer than 0ms",
+        )));
+    }
+    let id = Timers::from_lua(lua_ctx)?.start(std::time::Duration::from_millis(delay), interval)?;
+    let timer = lua_ctx.create_table()?;
+    timer.set("callback", callback)?;
+    timer.set("interval", interval)?;
+    timer.set("addon", addon)?;
+    let callbacks: rlua::Table = lua_ctx.named_registry_value(CALLBACKS_REGISTRY_KEY)?;
+    callbacks.set(id, timer)?;
+    Ok(id)
+}
+
+fn cancel_timer(lua_ctx: rlua::Context, id: u64) -> rlua::Result<()> {
+    Timers::from_lua(lua_ctx)?.stop(id);
+    let callbacks: rlua::Table = lua_ctx.named_registry_value(CALLBACKS_REGISTRY_KEY)?;
+    callbacks.set(id, rlua::Value::Nil)?;
+    Ok(())
+}
+
+/// Adds `set_timeout`, `set_interval`, `cancel_timer`, `sleep` and `async` to a table.
+/// Timers created by an addon are stopped when it's unloaded
+pub fn add_timer_functions<'lua>(
+    lua_ctx: rlua::Context<'lua>,
+    table: rlua::Table<'lua>,
+    addon: Option<String>,
+) -> rlua::Result<()> {
+    let addon_clone = addon.clone();
+    let set_timeout = lua_ctx.create_function(
+        move |lua_ctx, (delay, callback): (u64, rlua::Function)| {
+            add_timer(lua_ctx, delay, callback, false, addon_clone.clone())
+        },
+    )?;
+    let addon_clone = addon.clone();
+    let set_interval = lua_ctx.create_function(
+        move |lua_ctx, (interval, callback): (u64, rlua::Function)| {
+            add_timer(lua_ctx, interval, callback, true, addon_clone.clone())
+        },
+    )?;
+    let cancel = lua_ctx.create_function(|lua_ctx, id: u64| cancel_timer(lua_ctx, id))?;
+    let (sleep, async_fn): (rlua::Function, rlua::Function) = lua_ctx
+        .load(COROUTINE_HELPERS)
+        .set_name("timers")?
+        .into_function()?
+        .call(set_timeout.clone())?;
+    table.set("set_timeout", set_timeout)?;
+    table.set("set_interval", set_interval)?;
+    table.set("cancel_timer", cancel)?;
+    table.set("sleep", sleep)?;
+    table.set("async", async_fn)?;
+    Ok(())
+}
+
+impl Server {
+    pub fn run_timer(&mut self, id: u64) {
+        self.hook_profiler.reset_instructions();
+        let start = std::time::Instant::now();
+        let found = self.lua.context(|lua_ctx| {
+            let callbacks: rlua::Table = match lua_ctx.named_registry_value(CALLBACKS_REGISTRY_KEY) {
+                Ok(callbacks) => callbacks,
+                Err(_) => return false,
+            };
+            // The timer could have been cancelled after it fired
+            let timer: rlua::Table = match callbacks.get::<_, Option<rlua::Table>>(id) {
+                Ok(Some(timer)) => timer,
+                _ => return false,
+            };
+            let callback: rlua::Function = timer.get("callback").unwrap();
+            let addon: Option<String> = timer.get("addon").unwrap();
+            if !timer.get::<_, bool>("interval").unwrap() {
+                let _ = cancel_timer(lua_ctx, id);
+            }
+            if let Err(e) = callback.call::<_, ()>(()) {
+                error!("Timer {} has failed: {}", id, e);
+            }
+            let addon = addon.unwrap_or(String::from("server"));
+            if self.hook_profiler.record("Timer", &addon, start.elapsed()) {
+                let _ = cancel_timer(lua_ctx, id);
+            }
+            true
+        });
+        if found {
+            crate::metrics::METRICS.record_hook("Timer", start.elapsed());
+        }
+    }
+
+    /// Stops every timer the addon has started, including pending `sleep` calls
+    pub fn cancel_addon_timers(&mut self, addon: &str) {
+        self.lua.context(|lua_ctx| {
+            let callbacks: rlua::Table = match lua_ctx.named_registry_value(CALLBACKS_REGISTRY_KEY) {
+                Ok(callbacks) => callbacks,
+                Err(_) => return,
+            };
+            let mut owned = vec![];
+            for (id, timer) in callbacks.clone().pairs::<u64, rlua::Table>().flatten() {
+                if timer.get::<_, Option<String>>("addon").ok().flatten().as_deref() == Some(addon) {
+                    owned.push(id);
+                }
+            }
+            for id in owned {
+                let _ = cancel_timer(lua_ctx, id);
+            }
+        });
+    }
+}
@@ -0,0 +1,402 @@
+use crate::*;
+use serde::{Deserialize, Serialize};
+
+// Extra distance allowed on top of max speed to tolerate jitter and physics corrections
+const POSITION_TOLERANCE: f32 = 5.0;
+// Longer gaps between updates are treated as packet loss and are not extrapolated further
+const MAX_UPDATE_GAP: f64 = 2.0;
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SuspiciousUpdateAction {
+    Ignore,
+    Clamp,
+    Drop,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Violation {
+    InvalidValue,
+    Speed(f32),
+    Teleport(f32),
+}
+
+impl Violation {
+    pub fn reason(&self) -> &'static str {
+        match self {
+            Violation::InvalidValue => "invalid_value",
+            Violation::Speed(_) => "speed",
+            Violation::Teleport(_) => "teleport",
+        }
+    }
+    pub fn value(&self) -> f32 {
+        match self {
+            Violation::InvalidValue => 0.0,
+            Violation::Speed(x) => *x,
+            Violation::Teleport(x) => *x,
+        }
+    }
+}
+
+fn length(v: [f32; 3]) -> f32 {
+    (v[0] * v[0] + v[1] * v[1] + v[2] * v[2]).sqrt()
+}
+
+fn difference(a: [f32; 3], b: [f32; 3]) -> [f32; 3] {
+    [a[0] - b[0], a[1] - b[1], a[2] - b[2]]
+}
+
+fn is_finite(transform: &Transform) -> bool {
+    transform
+        .position
+        .iter()
+        .chain(transform.rotation.iter())
+        .chain(transform.velocity.iter())
+        .chain(transform.angular_velocity.iter())
+        .all(|x| x.is_finite())
+}
+
+fn allowed_distance(
+    max_speed: f32,
+    tickrate: u8,
+    previous_sent_at: Option<f64>,
+    sent_at: f64,
+) -> f32 {
+    let min_dt = 1.0 / tickrate.max(1) as f64;
+    let dt = previous_sent_at
+        .map(|previous| sent_at - previous)
+        .filter(|dt| dt.is_finite())
+        .unwrap_or(min_dt)
+        .max(min_dt)
+        .min(MAX_UPDATE_GAP);
+    max_speed * dt as f32 + POSITION_TOLERANCE
+}
+
+/// Compares the update against the last known state of the vehicle.
+/// `previous_sent_at` is the `sent_at` of the update the previous transform came from.
+pub fn check_update(
+    previous: Option<&Transform>,
+    previous_sent_at: Option<f64>,
+    update: &VehicleUpdate,
+    max_speed: f32,
+    tickrate: u8,
+) -> Option<Violation> {
+    if !is_finite(&update.transform) || !update.sent_at.is_finite() {
+        return Some(Violation::InvalidValue);
+    }
+    let speed = length(update.transform.velocity);
+    if speed > max_speed {
+        return Some(Violation::Speed(speed));
+    }
+    if let Some(previous) = previous {
+        let distance = length(difference(update.transform.position, previous.position));
+        if distance > allowed_distance(max_speed, tickrate, previous_sent_at, update.sent_at) {
+            return Some(Violation::Teleport(distance));
+        }
+    }
+    None
+}
+
+/// Moves the update back into the allowed speed and distance limits
+pub fn clamp_update(
+    previous: Option<&Transform>,
+    previous_sent_at: Option<f64>,
+    update: &mut VehicleUpdate,
+    max_speed: f32,
+    tickrate: u8,
+) {
+    let transform = &mut update.transform;
+    let speed = length(transform.velocity);
+    if speed > max_speed {
+        let scale = max_speed / speed;
+        for x in &mut transform.velocity {
+            *x *= scale;
+        }
+    }
+    if let Some(previous) = previous {
+        let offset = difference(transform.position, previous.position);
+        let distance = length(offset);
+        let allowed = allowed_distance(max_speed, tickrate, previous_sent_at, update.sent_at);
+        if distance > allowed {
+            let scale = allowed / distance;
+            for ((x, previous), offset) in transform
+                .position
+                .iter_mut()
+                .zip(previous.position)
+                .zip(offset)
+            {
+                *x = previous + offset * scale;
+            }
+        }
+    }
+}
+
+impl Server {
+    /// Returns false if the update has to be dropped
+    pub fn validate_vehicle_update(
+        &mut self,
+        client_id: u32,
+        server_id: u32,
+        update: &mut VehicleUpdate,
+    ) -> bool {
+        let (previous, previous_sent_at) = match self.vehicles.get(&server_id) {
+            Some(vehicle) => (vehicle.transform.clone(), vehicle.update_sent_at),
+            None => return false,
+        };
+        let violation = check_update(
+            previous.as_ref(),
+            previous_sent_at,
+            update,
+            self.max_vehicle_speed,
+            self.tickrate,
+        );
+        let violation = match violation {
+            Some(violation) => violation,
+            None => return true,
+        };
+        // Broken values can't be clamped and shouldn't ever reach other clients
+        if violation == Violation::InvalidValue {
+            warn!(
+                "Dropped vehicle update with invalid values from client {}",
+                client_id
+            );
+            return false;
+        }
+        debug!(
+            "Suspicious update from client {} for vehicle {}: {} ({})",
+            client_id,
+            server_id,
+            violation.reason(),
+            violation.value()
+        );
+        let mut accepted = false;
+        self.lua.context(|lua_ctx| {
+            let results = crate::lua::run_hook::<(u32, u32, String, f32), Option<bool>>(
+                lua_ctx,
+                String::from("OnSuspiciousUpdate"),
+                (
+                    client_id,
+                    server_id,
+                    violation.reason().to_string(),
+                    violation.value(),
+                ),
+            );
+            accepted = results.into_iter().any(|x| x == Some(true));
+        });
+        if accepted {
+            return true;
+        }
+        match self.suspicious_update_action {
+            SuspiciousUpdateAction::Ignore => true,
+            SuspiciousUpdateAction::Clamp => {
+                clamp_update(
+                    previous.as_ref(),
+                    previous_sent_at,
+                    update,
+                    self.max_vehicle_speed,
+                    self.tickrate,
+                );
+                true
+            }
+            SuspiciousUpdateAction::Drop => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAX_SPEED: f32 = 100.0;
+    const TICKRATE: u8 = 60;
+
+    fn transform(position: [f32; 3], velocity: [f32; 3]) -> Transform {
+        Transform {
+            position,
+            rotation: [0.0, 0.0, 0.0, 1.0],
+            velocity,
+            angular_velocity: [0.0, 0.0, 0.0],
+        }
+    }
+
+    fn update(position: [f32; 3], velocity: [f32; 3], sent_at: f64) -> VehicleUpdate {
+        VehicleUpdate {
+            transform: transform(position, velocity),
+            electrics: shared::vehicle::electrics::Electrics {
+                throttle_input: 0.0,
+                brake_input: 0.0,
+                clutch: 0.0,
+                parkingbrake: 0.0,
+                steering_input: 0.0,
+            },
+            gearbox: shared::vehicle::gearbox::Gearbox {
+                arcade: false,
+                lock_coef: 0.0,
+                mode: None,
+                gear_indices: [0, 0],
+            },
+            vehicle_id: 0,
+            generation: 0,
+            sent_at,
+        }
+    }
+
+    fn check(
+        previous: &Transform,
+        previous_sent_at: f64,
+        update: &VehicleUpdate,
+    ) -> Option<Violation> {
+        check_update(
+            Some(previous),
+            Some(previous_sent_at),
+            update,
+            MAX_SPEED,
+            TICKRATE,
+        )
+    }
+
+    fn assert_close(a: f32, b: f32) {
+        assert!((a - b).abs() < 1e-3, "{} != {}", a, b);
+    }
+
+    #[test]
+    fn invalid_values_are_rejected() {
+        let previous = transform([0.0; 3], [0.0; 3]);
+        for value in &[f32::NAN, f32::INFINITY, f32::NEG_INFINITY] {
+            let mut invalid = update([0.0; 3], [0.0; 3], 1.0);
+            invalid.transform.position[1] = *value;
+            assert_eq!(
+                check(&previous, 0.0, &invalid),
+                Some(Violation::InvalidValue)
+            );
+            let mut invalid = update([0.0; 3], [0.0; 3], 1.0);
+            invalid.transform.rotation[3] = *value;
+            assert_eq!(
+                check(&previous, 0.0, &invalid),
+                Some(Violation::InvalidValue)
+            );
+            let mut invalid = update([0.0; 3], [0.0; 3], 1.0);
+            invalid.transform.angular_velocity[0] = *value;
+            assert_eq!(
+                check(&previous, 0.0, &invalid),
+                Some(Violation::InvalidValue)
+            );
+        }
+        let invalid = update([0.0; 3], [0.0; 3], f64::NAN);
+        assert_eq!(
+            check(&previous, 0.0, &invalid),
+            Some(Violation::InvalidValue)
+        );
+        // Without a previous state too
+        let mut invalid = update([0.0; 3], [0.0; 3], 1.0);
+        invalid.transform.velocity[2] = f32::NAN;
+        assert_eq!(
+            check_update(None, None, &invalid, MAX_SPEED, TICKRATE),
+            Some(Violation::InvalidValue)
+        );
+    }
+
+    #[test]
+    fn speed_over_the_limit_is_rejected() {
+        let previous = transform([0.0; 3], [0.0; 3]);
+        let at_limit = update([0.0; 3], [60.0, 80.0, 0.0], 1.0);
+        assert_eq!(check(&previous, 0.0, &at_limit), None);
+        let too_fast = update([0.0; 3], [60.0, 80.0, 1.0], 1.0);
+        match check(&previous, 0.0, &too_fast) {
+            Some(Violation::Speed(speed)) => assert!(speed > MAX_SPEED),
+            violation => panic!("unexpected {:?}", violation),
+        }
+    }
+
+    #[test]
+    fn jumps_beyond_the_allowed_distance_are_rejected() {
+        let previous = transform([0.0; 3], [0.0; 3]);
+        // One second at max speed plus the tolerance
+        let limit = MAX_SPEED + POSITION_TOLERANCE;
+        let within = update([limit - 0.1, 0.0, 0.0], [0.0; 3], 1.0);
+        assert_eq!(check(&previous, 0.0, &within), None);
+        let beyond = update([0.0, limit + 0.1, 0.0], [0.0; 3], 1.0);
+        match check(&previous, 0.0, &beyond) {
+            Some(Violation::Teleport(distance)) => assert_close(distance, limit + 0.1),
+            violation => panic!("unexpected {:?}", violation),
+        }
+        // The first update of a vehicle can be anywhere
+        assert_eq!(check_update(None, None, &beyond, MAX_SPEED, TICKRATE), None);
+    }
+
+    #[test]
+    fn time_between_updates_is_clamped() {
+        let tick = MAX_SPEED / TICKRATE as f32 + POSITION_TOLERANCE;
+        // sent_at going backwards counts as a single tick
+        assert_close(allowed_distance(MAX_SPEED, TICKRATE, Some(10.0), 5.0), tick);
+        assert_close(
+            allowed_distance(MAX_SPEED, TICKRATE, Some(10.0), 10.0),
+            tick,
+        );
+        assert_close(allowed_distance(MAX_SPEED, TICKRATE, None, 10.0), tick);
+        assert_close(
+            allowed_distance(MAX_SPEED, TICKRATE, Some(f64::NEG_INFINITY), 10.0),
+            tick,
+        );
+        // Gaps longer than MAX_UPDATE_GAP aren't extrapolated further
+        let max_gap = MAX_SPEED * MAX_UPDATE_GAP as f32 + POSITION_TOLERANCE;
+        assert_close(
+            allowed_distance(MAX_SPEED, TICKRATE, Some(0.0), 60.0),
+            max_gap,
+        );
+        assert_close(
+            allowed_distance(MAX_SPEED, TICKRATE, Some(0.0), MAX_UPDATE_GAP),
+            max_gap,
+        );
+        assert_close(
+            allowed_distance(MAX_SPEED, TICKRATE, Some(0.0), 0.5),
+            MAX_SPEED * 0.5 + POSITION_TOLERANCE,
+        );
+        // A tickrate of 0 doesn't divide by zero
+        assert_close(
+            allowed_distance(MAX_SPEED, 0, None, 0.0),
+            MAX_SPEED + POSITION_TOLERANCE,
+        );
+
+        let previous = transform([0.0; 3], [0.0; 3]);
+        let backwards = update([tick + 1.0, 0.0, 0.0], [0.0; 3], 5.0);
+        assert!(matches!(
+            check(&previous, 10.0, &backwards),
+            Some(Violation::Teleport(_))
+        ));
+    }
+
+    #[test]
+    fn clamped_updates_land_on_the_limits() {
+        let previous = transform([10.0, 20.0, 30.0], [0.0; 3]);
+        let mut clamped = update([10.0, 1020.0, 30.0], [300.0, 0.0, -400.0], 1.0);
+        clamp_update(
+            Some(&previous),
+            Some(0.0),
+            &mut clamped,
+            MAX_SPEED,
+            TICKRATE,
+        );
+
+        let velocity = clamped.transform.velocity;
+        assert_close(length(velocity), MAX_SPEED);
+        assert_close(velocity[0], 60.0);
+        assert_close(velocity[2], -80.0);
+
+        let position = clamped.transform.position;
+        let allowed = MAX_SPEED + POSITION_TOLERANCE;
+        assert_close(length(difference(position, previous.position)), allowed);
+        assert_close(position[0], 10.0);
+        assert_close(position[1], 20.0 + allowed);
+        assert_close(position[2], 30.0);
+    }
+
+    #[test]
+    fn valid_updates_are_not_clamped() {
+        let previous = transform([0.0; 3], [0.0; 3]);
+        let mut valid = update([1.0, 2.0, 3.0], [4.0, 5.0, 6.0], 1.0);
+        clamp_update(Some(&previous), Some(0.0), &mut valid, MAX_SPEED, TICKRATE);
+        assert_eq!(valid.transform.position, [1.0, 2.0, 3.0]);
+        assert_eq!(valid.transform.velocity, [4.0, 5.0, 6.0]);
+    }
+}
@@ -0,0 +1,346 @@
+use crate::*;
+
+// The walking player model, it's never limited
+const UNICYCLE: &str = "unicycle";
+const SPAWN_RATE_WINDOW: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SpawnKind {
+    New,
+    /// The player changed the parts of a vehicle they already have, the game spawns it again
+    Replacement,
+    /// A saved vehicle the player was asked to spawn again, see `take_returning_vehicle`
+    Returning,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct VehiclePolicy {
+    /// Only these models can be spawned, if not empty
+    pub allowed_vehicles: Vec<String>,
+    pub denied_vehicles: Vec<String>,
+    /// Patterns matched against the part names in the `parts` of `parts_config`. `*` matches any text
+    pub banned_parts: Vec<String>,
+    /// 0 disables the limit
+    pub max_spawns_per_minute: u32,
+    /// Role -> vehicle limit. Roles without a limit use `max_vehicles_per_client`
+    pub vehicle_limits: HashMap<String, u8>,
+}
+
+impl VehiclePolicy {
+    pub fn from_config(config: &config::Config) -> Self {
+        Self {
+            allowed_vehicles: config.allowed_vehicles.clone(),
+            denied_vehicles: config.denied_vehicles.clone(),
+            banned_parts: config.banned_parts.clone(),
+            max_spawns_per_minute: config.max_vehicle_spawns_per_minute,
+            vehicle_limits: config.vehicle_limits_per_role.clone(),
+        }
+    }
+
+    pub fn check_model(&self, name: &str) -> Result<(), String> {
+        let allowed =
+            self.allowed_vehicles.is_empty() || self.allowed_vehicles.iter().any(|x| x == name);
+        if !allowed || self.denied_vehicles.iter().any(|x| x == name) {
+            return Err(format!("{} isn't allowed on this server", name));
+        }
+        Ok(())
+    }
+
+    pub fn check_parts(&self, parts_config: &str) -> Result<(), String> {
+        if self.banned_parts.is_empty() {
+            return Ok(());
+        }
+        let parts_config: serde_json::Value = serde_json::from_str(parts_config)
+            .map_err(|_| String::from("Vehicle configuration is invalid"))?;
+        // Slot name -> part name. Empty slots have an empty part name
+        let parts = match parts_config.get("parts") {
+            Some(serde_json::Value::Object(parts)) => parts,
+            _ => return Ok(()),
+        };
+        for name in parts.values().filter_map(|x| x.as_str()) {
+            if name.is_empty() {
+                continue;
+            }
+            if self
+                .banned_parts
+                .iter()
+                .any(|pattern| wildcard_match(pattern, name))
+            {
+                return Err(format!("Part {} isn't allowed on this server", name));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn vehicle_limit(&self, role: &str, default: u8) -> u8 {
+        self.vehicle_limits.get(role).cloned().unwrap_or(default)
+    }
+}
+
+/// Case insensitive match, where `*` matches any text
+fn wildcard_match(pattern: &str, text: &str) -> bool {
+    let pattern = pattern.to_lowercase();
+    let text = text.to_lowercase();
+    let parts: Vec<&str> = pattern.split('*').collect();
+    if parts.len() == 1 {
+        return pattern == text;
+    }
+    let (first, last) = (parts[0], parts[parts.len() - 1]);
+    if !text.starts_with(first) || !text.ends_with(last) || text.len() < first.len() + last.len() {
+        return false;
+    }
+    let mut rest = &text[first.len()..text.len() - last.len()];
+    for part in &parts[1..parts.len() - 1] {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+    true
+}
+
+impl Server {
+    pub fn role(&self, client_id: u32) -> &'static str {
+        if self.is_admin(client_id) {
+            "admin"
+        } else {
+            "player"
+        }
+    }
+
+    pub fn vehicle_limit(&self, client_id: u32) -> u8 {
+        self.vehicle_policy
+            .vehicle_limit(self.role(client_id), self.max_vehicles_per_client)
+    }
+
+    /// Checks a vehicle a player wants to spawn. Returns the reason if it's denied.
+    /// Saved vehicles the player is spawning again don't count towards the limits
+    pub fn check_vehicle_policy(
+        &self,
+        client_id: u32,
+        data: &VehicleData,
+        kind: SpawnKind,
+    ) -> Result<(), String> {
+        if data.name == UNICYCLE {
+            return Ok(());
+        }
+        self.vehicle_policy.check_model(&data.name)?;
+        self.vehicle_policy.check_parts(&data.parts_config)?;
+        if kind == SpawnKind::Returning {
+            return Ok(());
+        }
+        let limit = self.vehicle_limit(client_id);
+        let count = self
+            .vehicle_ids
+            .get(&client_id)
+            .map(|vehicles| vehicles.len())
+            .unwrap_or(0);
+        if count >= limit as usize {
+            return Err(format!("You can't have more than {} vehicles", limit));
+        }
+        let max_spawns = self.vehicle_policy.max_spawns_per_minute;
+        if max_spawns != 0 && kind == SpawnKind::New {
+            let now = std::time::Instant::now();
+            let recent = self
+                .vehicle_spawn_times
+                .get(&client_id)
+                .map(|spawns| {
+                    spawns
+                        .iter()
+                        .filter(|x| now.duration_since(**x) <= SPAWN_RATE_WINDOW)
+                        .count()
+                })
+                .unwrap_or(0);
+            if recent >= max_spawns as usize {
+                return Err(format!(
+                    "You can't spawn more than {} vehicles per minute",
+                    max_spawns
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Counts a spawn towards `max_vehicle_spawns_per_minute`, once it was allowed
+    pub fn record_vehicle_spawn(&mut self, client_id: u32, data: &VehicleData, kind: SpawnKind) {
+        if data.name == UNICYCLE || kind != SpawnKind::New {
+            return;
+        }
+        let now = std::time::Instant::now();
+        let spawns = self.vehicle_spawn_times.entry(client_id).or_default();
+        while spawns
+            .front()
+            .map(|x| now.duration_since(*x) > SPAWN_RATE_WINDOW)
+            .unwrap_or(false)
+        {
+            spawns.pop_front();
+        }
+        spawns.push_back(now);
+    }
+
+    /// Tells the player why the vehicle was denied and deletes it from their game
+    pub async fn deny_vehicle(&mut self, client_id: u32, in_game_id: u32, reason: String) {
+        info!("Vehicle of client {} was denied: {}", client_id, reason);
+        if let Some(connection) = self.connections.get_mut(&client_id) {
+            connection.send_chat_message(reason).await;
+            // The vehicle doesn't have a server ID yet, so it's deleted by its in game ID
+            connection
+                .send_lua(format!(
+                    "local vehicle = be:getObjectByID({}) if vehicle then vehicle:delete() end",
+                    in_game_id
+                ))
+                .await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{test_server, vehicle_data};
+
+    fn banning(patterns: &[&str]) -> VehiclePolicy {
+        VehiclePolicy {
+            banned_parts: patterns.iter().map(|x| x.to_string()).collect(),
+            ..VehiclePolicy::default()
+        }
+    }
+
+    #[test]
+    fn wildcards() {
+        assert!(wildcard_match("*", ""));
+        assert!(wildcard_match("*", "anything"));
+        assert!(wildcard_match("a*", "a"));
+        assert!(wildcard_match("a*", "abc"));
+        assert!(!wildcard_match("a*", "ba"));
+        assert!(wildcard_match("*a", "a"));
+        assert!(wildcard_match("*a", "cba"));
+        assert!(!wildcard_match("*a", "ab"));
+        assert!(wildcard_match("a*b*c", "abc"));
+        assert!(wildcard_match("a*b*c", "axxbyyc"));
+        assert!(!wildcard_match("a*b*c", "axxcyyb"));
+        assert!(!wildcard_match("a*b*c", "ac"));
+        // The start and the end can't overlap
+        assert!(!wildcard_match("a*a", "a"));
+        assert!(wildcard_match("a*a", "aa"));
+        assert!(wildcard_match("a*b*b", "abb"));
+        assert!(!wildcard_match("a*b*b", "ab"));
+        assert!(wildcard_match("abc", "abc"));
+        assert!(!wildcard_match("abc", "abcd"));
+    }
+
+    #[test]
+    fn wildcards_ignore_case() {
+        assert!(wildcard_match("Pickup_Engine*", "pickup_engine_v8"));
+        assert!(wildcard_match("*_v8", "PICKUP_ENGINE_V8"));
+        assert!(wildcard_match("JATO", "jato"));
+    }
+
+    #[test]
+    fn banned_parts_are_matched_by_part_name() {
+        let policy = banning(&["*jato*"]);
+        assert!(policy
+            .check_parts(r#"{"parts": {"pickup_jato": "pickup_jato_rocket"}}"#)
+            .is_err());
+        // Slot names and other fields aren't parts
+        assert!(policy
+            .check_parts(r#"{"parts": {"pickup_jato": ""}, "model": "jato"}"#)
+            .is_ok());
+        assert!(policy
+            .check_parts(r#"{"parts": {"pickup_engine": "pickup_engine_v8"}}"#)
+            .is_ok());
+    }
+
+    #[test]
+    fn empty_slots_are_allowed() {
+        let policy = banning(&["*"]);
+        assert!(policy
+            .check_parts(r#"{"parts": {"a": "", "b": ""}}"#)
+            .is_ok());
+        assert!(policy
+            .check_parts(r#"{"parts": {"a": "", "b": "x"}}"#)
+            .is_err());
+    }
+
+    #[test]
+    fn configs_without_parts_are_allowed() {
+        let policy = banning(&["*"]);
+        assert!(policy.check_parts("{}").is_ok());
+        assert!(policy.check_parts(r#"{"parts": ["a", "b"]}"#).is_ok());
+        assert!(policy.check_parts(r#"{"model": "pickup"}"#).is_ok());
+        assert!(policy.check_parts("not json").is_err());
+        // Nothing is parsed without banned parts
+        assert!(banning(&[]).check_parts("not json").is_ok());
+    }
+
+    #[test]
+    fn models_are_checked_against_both_lists() {
+        let policy = VehiclePolicy {
+            allowed_vehicles: vec![String::from("pickup"), String::from("etk800")],
+            denied_vehicles: vec![String::from("etk800")],
+            ..VehiclePolicy::default()
+        };
+        assert!(policy.check_model("pickup").is_ok());
+        assert!(policy.check_model("etk800").is_err());
+        assert!(policy.check_model("covet").is_err());
+        assert!(VehiclePolicy::default().check_model("covet").is_ok());
+    }
+
+    #[test]
+    fn returning_vehicles_and_the_unicycle_skip_the_limits() {
+        let mut server = test_server("vehicle-policy");
+        server.vehicle_policy.max_spawns_per_minute = 1;
+        server.vehicle_policy.denied_vehicles = vec![String::from("pickup")];
+        let limit = server.vehicle_limit(1) as u32;
+        server
+            .vehicle_ids
+            .insert(1, (0..limit).map(|id| (id, id + 1)).collect());
+
+        let pickup = vehicle_data(100);
+        assert!(server
+            .check_vehicle_policy(1, &pickup, SpawnKind::Returning)
+            .is_err());
+        server.vehicle_policy.denied_vehicles.clear();
+        assert!(server
+            .check_vehicle_policy(1, &pickup, SpawnKind::New)
+            .is_err());
+        assert!(server
+            .check_vehicle_policy(1, &pickup, SpawnKind::Replacement)
+            .is_err());
+        assert!(server
+            .check_vehicle_policy(1, &pickup, SpawnKind::Returning)
+            .is_ok());
+
+        let unicycle = VehicleData {
+            name: String::from(UNICYCLE),
+            ..vehicle_data(101)
+        };
+        assert!(server
+            .check_vehicle_policy(1, &unicycle, SpawnKind::New)
+            .is_ok());
+        server.record_vehicle_spawn(1, &unicycle, SpawnKind::New);
+        assert!(!server.vehicle_spawn_times.contains_key(&1));
+    }
+
+    #[test]
+    fn only_new_vehicles_count_as_spawns() {
+        let mut server = test_server("vehicle-spawns");
+        server.vehicle_policy.max_spawns_per_minute = 1;
+        let pickup = vehicle_data(100);
+        server.record_vehicle_spawn(1, &pickup, SpawnKind::Replacement);
+        server.record_vehicle_spawn(1, &pickup, SpawnKind::Returning);
+        assert!(server
+            .check_vehicle_policy(1, &pickup, SpawnKind::New)
+            .is_ok());
+        server.record_vehicle_spawn(1, &pickup, SpawnKind::New);
+        assert!(server
+            .check_vehicle_policy(1, &pickup, SpawnKind::New)
+            .is_err());
+        assert!(server
+            .check_vehicle_policy(1, &pickup, SpawnKind::Replacement)
+            .is_ok());
+        assert!(server
+            .check_vehicle_policy(2, &pickup, SpawnKind::New)
+            .is_ok());
+    }
+}
@@ -0,0 +1,232 @@
+use crate::*;
+use serde::{Deserialize, Serialize};
+
+pub const WORLD_STATE_FILE: &str = "world.json";
+pub const SAVE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SavedVehicle {
+    pub data: VehicleData,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub transform: Option<Transform>,
+    /// SHA-256 of the secret of the player the vehicle belonged to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub owner_secret_hash: Option<String>,
+    /// Older saves kept the secret itself. It's hashed on load and never written again
+    #[serde(default, skip_serializing)]
+    pub owner_secret: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct WorldState {
+    pub vehicles: Vec<SavedVehicle>,
+}
+
+impl WorldState {
+    pub fn load(path: &std::path::Path) -> anyhow::Result<Self> {
+        let file = std::fs::File::open(path)?;
+        Ok(serde_json::from_reader(std::io::BufReader::new(file))?)
+    }
+
+    /// Writes to a temporary file first, so a crash while saving doesn't lose the previous state
+    pub fn save(&self, path: &std::path::Path) -> anyhow::Result<()> {
+        let temp_path = path.with_extension("json.tmp");
+        std::fs::write(&temp_path, serde_json::to_vec(self)?)?;
+        std::fs::rename(temp_path, path)?;
+        Ok(())
+    }
+}
+
+/// The secret is as good as a password, so only its hash is written to disk
+pub fn hash_secret(secret: &str) -> String {
+    ring::digest::digest(&ring::digest::SHA256, secret.as_bytes())
+        .as_ref()
+        .iter()
+        .map(|x| format!("{:02x}", x))
+        .collect()
+}
+
+/// Wraps a string into a Lua long bracket literal that can't be closed by its content
+pub(crate) fn lua_long_string(s: &str) -> String {
+    let mut level = 0;
+    while s.contains(&format!("]{}]", "=".repeat(level))) {
+        level += 1;
+    }
+    let eq = "=".repeat(level);
+    format!("[{}[{}]{}]", eq, s, eq)
+}
+
+impl Server {
+    pub fn save_world_state(&self) {
+        let mut state = WorldState::default();
+        for (server_id, vehicle) in &self.vehicles {
+            // Players on foot respawn by themselves
+            if vehicle.data.name == "unicycle" {
+                continue;
+            }
+            let owner_secret_hash = match vehicle.data.owner {
+                Some(owner) => self
+                    .connections
+                    .get(&owner)
+                    .map(|c| hash_secret(&c.client_info_private.secret)),
+                None => match self.restored_owners.get(server_id) {
+                    Some(hash) => Some(hash.clone()),
+                    // Vehicles spawned by addons are spawned again by the addon after a restart
+                    None => continue,
+                },
+            };
+            state.vehicles.push(SavedVehicle {
+                data: vehicle.data.clone(),
+                transform: vehicle.transform.clone(),
+                owner_secret_hash,
+                owner_secret: None,
+            });
+        }
+        // Vehicles that their owners were asked to spawn again, but didn't yet
+        for saved in self.returning_vehicles.values().flatten() {
+            state.vehicles.push(saved.clone());
+        }
+        let path = self.paths.data.join(WORLD_STATE_FILE);
+        match state.save(&path) {
+            Ok(()) => debug!("Saved {} vehicle(s) to {}", state.vehicles.len(), path.display()),
+            Err(e) => error!("Failed to save world state: {}", e),
+        }
+    }
+
+    /// Spawns the saved vehicles as unowned. They are handed back once their owner is in game again
+    pub fn restore_world_state(&mut self) {
+        let path = self.paths.data.join(WORLD_STATE_FILE);
+        if !path.exists() {
+            return;
+        }
+        let state = match WorldState::load(&path) {
+            Ok(state) => state,
+            Err(e) => {
+                error!("Failed to load world state: {}", e);
+                return;
+            }
+        };
+        let count = state.vehicles.len();
+        for saved in state.vehicles {
+            let mut data = saved.data;
+            // Vehicles keep their saved IDs when possible
+            if !self.vehicle_id_allocator.reserve(data.server_id) {
+                data.server_id = match self.vehicle_id_allocator.allocate() {
+                    Some(id) => id,
+                    None => {
+                        error!("Vehicle {} wasn't restored, there are no free vehicle IDs", data.name);
+                        continue;
+                    }
+                };
+            }
+            data.owner = None;
+            if let Some(transform) = &saved.transform {
+                data.position = transform.position;
+                data.rotation = transform.rotation;
+            }
+            let owner_secret = saved.owner_secret;
+            let owner_secret_hash = saved
+                .owner_secret_hash
+                .or_else(|| owner_secret.map(|secret| hash_secret(&secret)));
+            if let Some(hash) = owner_secret_hash {
+                self.restored_owners.insert(data.server_id, hash);
+            }
+            self.vehicles.insert(
+                data.server_id,
+                Vehicle {
+                    transform: saved.transform,
+                    electrics: None,
+                    gearbox: None,
+                    data,
+                    update_sent_at: None,
+                },
+            );
+        }
+        info!("Restored {} vehicle(s) from {}", count, path.display());
+        let _ = self.update_lua_vehicles();
+    }
+
+    /// Replaces the restored copies of the client's vehicles with ones spawned by the client itself.
+    /// The saved vehicles are kept until the client spawned them, see `take_returning_vehicle`
+    pub async fn return_restored_vehicles(&mut self, client_id: u32) {
+        if self.restored_owners.is_empty() {
+            return;
+        }
+        let hash = match self.connections.get(&client_id) {
+            Some(connection) => hash_secret(&connection.client_info_private.secret),
+            None => return,
+        };
+        let server_ids: Vec<u32> = self
+            .restored_owners
+            .iter()
+            .filter(|(_, owner_secret_hash)| **owner_secret_hash == hash)
+            .map(|(server_id, _)| *server_id)
+            .collect();
+        for server_id in server_ids {
+            let saved = match self.vehicles.get(&server_id) {
+                Some(vehicle) => SavedVehicle {
+                    data: vehicle.data.clone(),
+                    transform: vehicle.transform.clone(),
+                    owner_secret_hash: Some(hash.clone()),
+                    owner_secret: None,
+                },
+                None => continue,
+            };
+            self.remove_vehicle(server_id, None).await;
+            let data = serde_json::to_string(&saved.data).unwrap();
+            self.returning_vehicles
+                .entry(client_id)
+                .or_default()
+                .push(saved);
+            if let Some(connection) = self.connections.get_mut(&client_id) {
+                connection
+                    .send_lua(format!(
+                        "vehiclemanager.spawn_owned_vehicle(jsonDecode({}))",
+                        lua_long_string(&data)
+                    ))
+                    .await;
+            }
+        }
+    }
+
+    /// Returns true if the vehicle is one of the saved vehicles the client was asked to spawn again
+    pub fn take_returning_vehicle(&mut self, client_id: u32, data: &VehicleData) -> bool {
+        let vehicles = match self.returning_vehicles.get_mut(&client_id) {
+            Some(vehicles) => vehicles,
+            None => return false,
+        };
+        let index = match vehicles.iter().position(|saved| saved.data.name == data.name) {
+            Some(index) => index,
+            None => return false,
+        };
+        vehicles.remove(index);
+        if vehicles.is_empty() {
+            self.returning_vehicles.remove(&client_id);
+        }
+        true
+    }
+
+    /// Spawns the vehicles the client didn't take back before leaving as unowned again
+    pub async fn restore_returning_vehicles(&mut self, client_id: u32) {
+        let vehicles = match self.returning_vehicles.remove(&client_id) {
+            Some(vehicles) => vehicles,
+            None => return,
+        };
+        for saved in vehicles {
+            let server_id = match self.vehicle_id_allocator.allocate() {
+                Some(id) => id,
+                None => {
+                    error!("Vehicle {} wasn't restored, there are no free vehicle IDs", saved.data.name);
+                    continue;
+                }
+            };
+            if let Some(hash) = saved.owner_secret_hash {
+                self.restored_owners.insert(server_id, hash);
+            }
+            self.spawn_vehicle_with_id(server_id, None, saved.data).await;
+            if let Some(vehicle) = self.vehicles.get_mut(&server_id) {
+                vehicle.transform = saved.transform;
+            }
+        }
+    }
+}
@@ -7,8 +7,8 @@ use std::io::Write;
 use chrono::Local;
 pub use log::{info, warn, error};
 
-pub const VERSION: (u32, u32) = (0, 7);
-pub const VERSION_STR: &str = "0.7.0";
+pub const VERSION: (u32, u32) = (0, 8);
+pub const VERSION_STR: &str = "0.8.0";
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ClientInfoPrivate {
@@ -82,12 +82,16 @@ pub enum ClientCommand {
     StartTalking,
     // Only used by bridge
     EndTalking,
+    // Part of a message that is too large to be sent at once
     DataChunk {
+        message_id: u32,
         chunk_index: u32,
         total_chunks: u32,
         data: String,
     },
     Ping(u16),
+    // Latest received generation per vehicle. Also tells the server that compact updates are supported
+    VehicleUpdateAck(Vec<(u32, u64)>),
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -110,6 +114,63 @@ pub enum ServerCommand {
     FilePart(String, Vec<u8>, u32, u32, u32),
     VoiceChatPacket(u32, [f32; 3], Vec<u8>),
     Pong(f64),
+    // CompactVehicleUpdate encoded with CompactVehicleUpdate::to_bytes
+    VehicleUpdateCompact(Vec<u8>),
+}
+
+impl ClientCommand {
+    /// Name of the variant, used in metrics
+    pub fn name(&self) -> &'static str {
+        match self {
+            ClientCommand::ClientInfo(_) => "ClientInfo",
+            ClientCommand::VehicleUpdate(_) => "VehicleUpdate",
+            ClientCommand::VehicleData(_) => "VehicleData",
+            ClientCommand::GearboxUpdate(_) => "GearboxUpdate",
+            ClientCommand::RemoveVehicle(_) => "RemoveVehicle",
+            ClientCommand::ResetVehicle(_) => "ResetVehicle",
+            ClientCommand::Chat(_) => "Chat",
+            ClientCommand::RequestMods(_) => "RequestMods",
+            ClientCommand::VehicleMetaUpdate(_) => "VehicleMetaUpdate",
+            ClientCommand::VehicleChanged(_) => "VehicleChanged",
+            ClientCommand::CouplerAttached(_) => "CouplerAttached",
+            ClientCommand::CouplerDetached(_) => "CouplerDetached",
+            ClientCommand::ElectricsUndefinedUpdate(_, _) => "ElectricsUndefinedUpdate",
+            ClientCommand::VoiceChatPacket(_) => "VoiceChatPacket",
+            ClientCommand::SpatialUpdate(_, _) => "SpatialUpdate",
+            ClientCommand::StartTalking => "StartTalking",
+            ClientCommand::EndTalking => "EndTalking",
+            ClientCommand::DataChunk { .. } => "DataChunk",
+            ClientCommand::Ping(_) => "Ping",
+            ClientCommand::VehicleUpdateAck(_) => "VehicleUpdateAck",
+        }
+    }
+}
+
+impl ServerCommand {
+    /// Name of the variant, used in metrics
+    pub fn name(&self) -> &'static str {
+        match self {
+            ServerCommand::VehicleUpdate(_) => "VehicleUpdate",
+            ServerCommand::VehicleSpawn(_) => "VehicleSpawn",
+            ServerCommand::RemoveVehicle(_) => "RemoveVehicle",
+            ServerCommand::ResetVehicle(_) => "ResetVehicle",
+            ServerCommand::Chat(_, _) => "Chat",
+            ServerCommand::TransferFile(_) => "TransferFile",
+            ServerCommand::SendLua(_) => "SendLua",
+            ServerCommand::PlayerInfoUpdate(_) => "PlayerInfoUpdate",
+            ServerCommand::VehicleMetaUpdate(_) => "VehicleMetaUpdate",
+            ServerCommand::PlayerDisconnected(_) => "PlayerDisconnected",
+            ServerCommand::VehicleLuaCommand(_, _) => "VehicleLuaCommand",
+            ServerCommand::CouplerAttached(_) => "CouplerAttached",
+            ServerCommand::CouplerDetached(_) => "CouplerDetached",
+            ServerCommand::ElectricsUndefinedUpdate(_, _) => "ElectricsUndefinedUpdate",
+            ServerCommand::ServerInfo(_) => "ServerInfo",
+            ServerCommand::FilePart(_, _, _, _, _) => "FilePart",
+            ServerCommand::VoiceChatPacket(_, _, _) => "VoiceChatPacket",
+            ServerCommand::Pong(_) => "Pong",
+            ServerCommand::VehicleUpdateCompact(_) => "VehicleUpdateCompact",
+        }
+    }
 }
 
 pub fn init_logging()
@@ -143,4 +204,27 @@ pub fn init_logging()
         writeln!(buf, "[{}] [{}] [{}]: {}", Local::now().format("%H:%M:%S%.3f"), module_path, format_args!("{:>5}", level), record.args())
     })
     .try_init();
-}
\ No newline at end of file
+}
+
+/// Logs one JSON object per line, for servers running without a terminal
+pub fn init_json_logging()
+{
+    let filter = match std::env::var("RUST_LOG")
+    {
+      Ok(f) => f,
+      Err(_e) => "info".to_owned()
+    };
+
+    let _ = pretty_env_logger::env_logger::Builder::new()
+    .parse_filters(&filter)
+    .format(|buf, record| {
+        let line = serde_json::json!({
+            "time": Local::now().to_rfc3339(),
+            "level": record.level().to_string(),
+            "target": record.target(),
+            "message": record.args().to_string(),
+        });
+        writeln!(buf, "{}", line)
+    })
+    .try_init();
+}
@@ -0,0 +1,442 @@
+//! Compact encoding of vehicle updates for the unreliable path.
+//!
+//! Positions are quantized to fixed point, rotations use the "smallest three" quaternion encoding
+//! and the whole update is delta encoded against a state the receiver has acknowledged.
+//! Both sides keep the quantized states in a `StateHistory` so deltas resolve to exactly the same values.
+
+use super::*;
+use bincode::Options;
+use std::collections::{HashMap, VecDeque};
+
+const POSITION_SCALE: f32 = 512.0;
+const VELOCITY_SCALE: f32 = 100.0;
+const ANGULAR_VELOCITY_SCALE: f32 = 1000.0;
+const ROTATION_BITS: u32 = 10;
+const HISTORY_LENGTH: usize = 64;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CompactElectrics {
+    // throttle, brake, clutch, parkingbrake
+    pub inputs: [u8; 4],
+    pub steering: i8,
+}
+
+/// Quantized state of a vehicle
+#[derive(Debug, Clone, PartialEq)]
+pub struct VehicleState {
+    pub position: [i32; 3],
+    pub rotation: u32,
+    pub velocity: [i16; 3],
+    pub angular_velocity: [i16; 3],
+    pub electrics: CompactElectrics,
+    pub gearbox: Gearbox,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CompactVehicleUpdate {
+    pub vehicle_id: u32,
+    pub generation: u64,
+    /// Generation the update is relative to. Absolute values if not set
+    pub baseline: Option<u64>,
+    pub position: [i32; 3],
+    pub rotation: u32,
+    pub velocity: [i16; 3],
+    pub angular_velocity: [i16; 3],
+    /// Not set if unchanged since the baseline
+    pub electrics: Option<CompactElectrics>,
+    /// Not set if unchanged since the baseline
+    pub gearbox: Option<Gearbox>,
+}
+
+fn quantize_i16(x: f32, scale: f32) -> i16 {
+    (x * scale)
+        .round()
+        .max(i16::MIN as f32)
+        .min(i16::MAX as f32) as i16
+}
+
+fn quantize_unit(x: f32) -> u8 {
+    (x.clamp(0.0, 1.0) * 255.0).round() as u8
+}
+
+fn encode_rotation(q: [f32; 4]) -> u32 {
+    let mut largest = 0;
+    for i in 1..4 {
+        if q[i].abs() > q[largest].abs() {
+            largest = i;
+        }
+    }
+    // q and -q are the same rotation, so the largest component can always be positive
+    let sign = if q[largest] < 0.0 { -1.0 } else { 1.0 };
+    let max = (1 << ROTATION_BITS) - 1;
+    let mut result = (largest as u32) << (ROTATION_BITS * 3);
+    let mut shift = ROTATION_BITS * 2;
+    for (i, x) in q.iter().enumerate() {
+        if i == largest {
+            continue;
+        }
+        let normalized = (x * sign * std::f32::consts::SQRT_2 + 1.0) / 2.0;
+        let value = (normalized.clamp(0.0, 1.0) * max as f32).round() as u32;
+        result |= value << shift;
+        shift = shift.saturating_sub(ROTATION_BITS);
+    }
+    result
+}
+
+fn decode_rotation(x: u32) -> [f32; 4] {
+    let max = (1 << ROTATION_BITS) - 1;
+    let largest = (x >> (ROTATION_BITS * 3)) as usize & 3;
+    let mut q = [0.0; 4];
+    let mut shift = ROTATION_BITS * 2;
+    let mut sum = 0.0;
+    for (i, component) in q.iter_mut().enumerate() {
+        if i == largest {
+            continue;
+        }
+        let value = ((x >> shift) & max) as f32 / max as f32;
+        *component = (value * 2.0 - 1.0) / std::f32::consts::SQRT_2;
+        sum += *component * *component;
+        shift = shift.saturating_sub(ROTATION_BITS);
+    }
+    q[largest] = (1.0 - sum).max(0.0).sqrt();
+    q
+}
+
+impl VehicleState {
+    pub fn from_parts(transform: &Transform, electrics: &Electrics, gearbox: &Gearbox) -> Self {
+        let p = transform.position;
+        let v = transform.velocity;
+        let a = transform.angular_velocity;
+        Self {
+            position: [
+                (p[0] * POSITION_SCALE).round() as i32,
+                (p[1] * POSITION_SCALE).round() as i32,
+                (p[2] * POSITION_SCALE).round() as i32,
+            ],
+            rotation: encode_rotation(transform.rotation),
+            velocity: [
+                quantize_i16(v[0], VELOCITY_SCALE),
+                quantize_i16(v[1], VELOCITY_SCALE),
+                quantize_i16(v[2], VELOCITY_SCALE),
+            ],
+            angular_velocity: [
+                quantize_i16(a[0], ANGULAR_VELOCITY_SCALE),
+                quantize_i16(a[1], ANGULAR_VELOCITY_SCALE),
+                quantize_i16(a[2], ANGULAR_VELOCITY_SCALE),
+            ],
+            electrics: CompactElectrics {
+                inputs: [
+                    quantize_unit(electrics.throttle_input),
+                    quantize_unit(electrics.brake_input),
+                    quantize_unit(electrics.clutch),
+                    quantize_unit(electrics.parkingbrake),
+                ],
+                steering: (electrics.steering_input.clamp(-1.0, 1.0) * 127.0).round() as i8,
+            },
+            gearbox: gearbox.clone(),
+        }
+    }
+
+    pub fn to_update(&self, vehicle_id: u32, generation: u64) -> VehicleUpdate {
+        let p = self.position;
+        let v = self.velocity;
+        let a = self.angular_velocity;
+        let e = &self.electrics;
+        VehicleUpdate {
+            transform: Transform {
+                position: [
+                    p[0] as f32 / POSITION_SCALE,
+                    p[1] as f32 / POSITION_SCALE,
+                    p[2] as f32 / POSITION_SCALE,
+                ],
+                rotation: decode_rotation(self.rotation),
+                velocity: [
+                    v[0] as f32 / VELOCITY_SCALE,
+                    v[1] as f32 / VELOCITY_SCALE,
+                    v[2] as f32 / VELOCITY_SCALE,
+                ],
+                angular_velocity: [
+                    a[0] as f32 / ANGULAR_VELOCITY_SCALE,
+                    a[1] as f32 / ANGULAR_VELOCITY_SCALE,
+                    a[2] as f32 / ANGULAR_VELOCITY_SCALE,
+                ],
+            },
+            electrics: Electrics {
+                throttle_input: e.inputs[0] as f32 / 255.0,
+                brake_input: e.inputs[1] as f32 / 255.0,
+                clutch: e.inputs[2] as f32 / 255.0,
+                parkingbrake: e.inputs[3] as f32 / 255.0,
+                steering_input: e.steering as f32 / 127.0,
+            },
+            gearbox: self.gearbox.clone(),
+            vehicle_id,
+            generation,
+            sent_at: 0.0,
+        }
+    }
+}
+
+impl CompactVehicleUpdate {
+    pub fn encode(
+        vehicle_id: u32,
+        generation: u64,
+        state: &VehicleState,
+        baseline: Option<(u64, &VehicleState)>,
+    ) -> Self {
+        match baseline {
+            Some((baseline_generation, baseline)) => Self {
+                vehicle_id,
+                generation,
+                baseline: Some(baseline_generation),
+                position: [
+                    state.position[0].wrapping_sub(baseline.position[0]),
+                    state.position[1].wrapping_sub(baseline.position[1]),
+                    state.position[2].wrapping_sub(baseline.position[2]),
+                ],
+                rotation: state.rotation,
+                velocity: state.velocity,
+                angular_velocity: state.angular_velocity,
+                electrics: if state.electrics != baseline.electrics {
+                    Some(state.electrics.clone())
+                } else {
+                    None
+                },
+                gearbox: if state.gearbox != baseline.gearbox {
+                    Some(state.gearbox.clone())
+                } else {
+                    None
+                },
+            },
+            None => Self {
+                vehicle_id,
+                generation,
+                baseline: None,
+                position: state.position,
+                rotation: state.rotation,
+                velocity: state.velocity,
+                angular_velocity: state.angular_velocity,
+                electrics: Some(state.electrics.clone()),
+                gearbox: Some(state.gearbox.clone()),
+            },
+        }
+    }
+
+    /// Returns None if the update is relative to a baseline that isn't provided
+    pub fn decode(&self, baseline: Option<&VehicleState>) -> Option<VehicleState> {
+        let baseline = match (self.baseline, baseline) {
+            (Some(_), Some(baseline)) => Some(baseline),
+            (Some(_), None) => return None,
+            (None, _) => None,
+        };
+        let position = match baseline {
+            Some(baseline) => [
+                self.position[0].wrapping_add(baseline.position[0]),
+                self.position[1].wrapping_add(baseline.position[1]),
+                self.position[2].wrapping_add(baseline.position[2]),
+            ],
+            None => self.position,
+        };
+        let electrics = self
+            .electrics
+            .clone()
+            .or_else(|| baseline.map(|b| b.electrics.clone()))?;
+        let gearbox = self
+            .gearbox
+            .clone()
+            .or_else(|| baseline.map(|b| b.gearbox.clone()))?;
+        Some(VehicleState {
+            position,
+            rotation: self.rotation,
+            velocity: self.velocity,
+            angular_velocity: self.angular_velocity,
+            electrics,
+            gearbox,
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        bincode::DefaultOptions::new()
+            .with_varint_encoding()
+            .serialize(self)
+            .unwrap()
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self, bincode::Error> {
+        bincode::DefaultOptions::new()
+            .with_varint_encoding()
+            .deserialize(data)
+    }
+}
+
+/// Recent quantized states of every vehicle, by generation
+#[derive(Debug, Clone, Default)]
+pub struct StateHistory {
+    states: HashMap<u32, VecDeque<(u64, VehicleState)>>,
+}
+
+impl StateHistory {
+    pub fn push(&mut self, vehicle_id: u32, generation: u64, state: VehicleState) {
+        let states = self.states.entry(vehicle_id).or_default();
+        if states.len() >= HISTORY_LENGTH {
+            states.pop_front();
+        }
+        states.push_back((generation, state));
+    }
+
+    pub fn get(&self, vehicle_id: u32, generation: u64) -> Option<&VehicleState> {
+        self.states
+            .get(&vehicle_id)?
+            .iter()
+            .rev()
+            .find(|(g, _)| *g == generation)
+            .map(|(_, state)| state)
+    }
+
+    pub fn remove(&mut self, vehicle_id: u32) {
+        self.states.remove(&vehicle_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn normalize(q: [f32; 4]) -> [f32; 4] {
+        let length = q.iter().map(|x| x * x).sum::<f32>().sqrt();
+        [q[0] / length, q[1] / length, q[2] / length, q[3] / length]
+    }
+
+    // q and -q are the same rotation, so only the angle between them matters
+    fn assert_same_rotation(a: [f32; 4], b: [f32; 4]) {
+        let dot: f32 = a.iter().zip(b.iter()).map(|(a, b)| a * b).sum();
+        assert!(dot.abs() > 0.9999, "{:?} != {:?}", a, b);
+    }
+
+    fn state(position: [f32; 3], throttle: f32, gear: i8) -> VehicleState {
+        VehicleState::from_parts(
+            &Transform {
+                position,
+                rotation: normalize([0.1, 0.2, 0.3, 0.9]),
+                velocity: [10.0, -2.5, 0.0],
+                angular_velocity: [0.0, 0.1, -0.2],
+            },
+            &Electrics {
+                throttle_input: throttle,
+                brake_input: 0.0,
+                clutch: 0.0,
+                parkingbrake: 1.0,
+                steering_input: -0.5,
+            },
+            &Gearbox {
+                arcade: true,
+                lock_coef: 1.0,
+                mode: None,
+                gear_indices: [gear, 0],
+            },
+        )
+    }
+
+    #[test]
+    fn rotation_round_trips_for_every_dropped_component() {
+        for largest in 0..4 {
+            let mut q = [0.2, -0.3, 0.1, 0.25];
+            q[largest] = 0.9;
+            let q = normalize(q);
+            let encoded = encode_rotation(q);
+            assert_eq!((encoded >> (ROTATION_BITS * 3)) as usize, largest);
+            let decoded = decode_rotation(encoded);
+            assert_same_rotation(q, decoded);
+            assert!(decoded[largest] > 0.0);
+        }
+    }
+
+    #[test]
+    fn rotation_sign_flips_encode_the_same() {
+        for largest in 0..4 {
+            let mut q = [-0.4, 0.3, -0.2, 0.1];
+            q[largest] = -0.8;
+            let q = normalize(q);
+            let flipped = [-q[0], -q[1], -q[2], -q[3]];
+            assert_eq!(encode_rotation(q), encode_rotation(flipped));
+            assert_same_rotation(q, decode_rotation(encode_rotation(q)));
+        }
+    }
+
+    #[test]
+    fn rotation_round_trips_at_the_edges() {
+        let identity = [0.0, 0.0, 0.0, 1.0];
+        assert_same_rotation(identity, decode_rotation(encode_rotation(identity)));
+        // Two components of the same size, the smaller ones are at the end of their range
+        let half = normalize([1.0, 0.0, 1.0, 0.0]);
+        assert_same_rotation(half, decode_rotation(encode_rotation(half)));
+        let even = [0.5, -0.5, 0.5, -0.5];
+        assert_same_rotation(even, decode_rotation(encode_rotation(even)));
+    }
+
+    #[test]
+    fn absolute_updates_round_trip() {
+        let state = state([1000.25, -35.5, 120.0], 0.5, 2);
+        let update = CompactVehicleUpdate::encode(1, 10, &state, None);
+        assert_eq!(update.baseline, None);
+        let update = CompactVehicleUpdate::from_bytes(&update.to_bytes()).unwrap();
+        assert_eq!(update.decode(None), Some(state.clone()));
+
+        let decoded = state.to_update(1, 10);
+        assert!((decoded.transform.position[0] - 1000.25).abs() < 1.0 / POSITION_SCALE);
+        assert!((decoded.transform.position[1] + 35.5).abs() < 1.0 / POSITION_SCALE);
+        assert!((decoded.electrics.throttle_input - 0.5).abs() < 1.0 / 255.0);
+    }
+
+    #[test]
+    fn delta_updates_round_trip() {
+        let baseline = state([1000.0, -35.0, 120.0], 0.5, 2);
+        let current = state([1000.5, -34.0, 119.75], 0.5, 2);
+        let update = CompactVehicleUpdate::encode(1, 11, &current, Some((10, &baseline)));
+        assert_eq!(update.baseline, Some(10));
+        assert_eq!(update.position, [256, 512, -128]);
+        // Unchanged since the baseline
+        assert_eq!(update.electrics, None);
+        assert_eq!(update.gearbox, None);
+        let absolute = CompactVehicleUpdate::encode(1, 11, &current, None);
+        assert!(update.to_bytes().len() < absolute.to_bytes().len());
+
+        let update = CompactVehicleUpdate::from_bytes(&update.to_bytes()).unwrap();
+        assert_eq!(update.decode(Some(&baseline)), Some(current.clone()));
+        assert_eq!(update.decode(None), None);
+    }
+
+    #[test]
+    fn delta_updates_carry_changed_fields() {
+        let baseline = state([0.0, 0.0, 0.0], 0.0, 1);
+        let current = state([0.0, 0.0, 0.0], 1.0, 3);
+        let update = CompactVehicleUpdate::encode(1, 11, &current, Some((10, &baseline)));
+        assert_eq!(update.electrics, Some(current.electrics.clone()));
+        assert_eq!(update.gearbox, Some(current.gearbox.clone()));
+        assert_eq!(update.decode(Some(&baseline)), Some(current));
+    }
+
+    #[test]
+    fn delta_positions_wrap_around() {
+        let mut baseline = state([0.0, 0.0, 0.0], 0.0, 1);
+        let mut current = baseline.clone();
+        baseline.position = [i32::MAX, i32::MIN, 0];
+        current.position = [i32::MIN, i32::MAX, 0];
+        let update = CompactVehicleUpdate::encode(1, 11, &current, Some((10, &baseline)));
+        assert_eq!(update.decode(Some(&baseline)), Some(current));
+    }
+
+    #[test]
+    fn history_resolves_baselines() {
+        let mut history = StateHistory::default();
+        for generation in 0..HISTORY_LENGTH as u64 + 10 {
+            history.push(1, generation, state([generation as f32, 0.0, 0.0], 0.0, 1));
+        }
+        assert!(history.get(1, 0).is_none());
+        assert_eq!(
+            history.get(1, HISTORY_LENGTH as u64).map(|x| x.position[0]),
+            Some(HISTORY_LENGTH as i32 * POSITION_SCALE as i32)
+        );
+        history.remove(1);
+        assert!(history.get(1, HISTORY_LENGTH as u64).is_none());
+    }
+}
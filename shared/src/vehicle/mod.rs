@@ -1,8 +1,10 @@
+pub mod compact;
 pub mod electrics;
 pub mod gearbox;
 pub mod transform;
 pub mod vehicle_meta;
 
+pub use compact::*;
 pub use electrics::*;
 pub use gearbox::*;
 pub use transform::*;
@@ -45,18 +47,18 @@ pub struct VehicleUpdate {
 
 #[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 pub struct CouplerAttached {
-    obj_a: u32,
-    obj_b: u32,
-    node_a_id: u32,
-    node_b_id: u32,
+    pub obj_a: u32,
+    pub obj_b: u32,
+    pub node_a_id: u32,
+    pub node_b_id: u32,
 }
 
 #[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 pub struct CouplerDetached {
-    obj_a: u32,
-    obj_b: u32,
-    node_a_id: u32,
-    node_b_id: u32,
+    pub obj_a: u32,
+    pub obj_b: u32,
+    pub node_a_id: u32,
+    pub node_b_id: u32,
 }
 
 pub struct ServerSetupResult {